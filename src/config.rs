@@ -1,5 +1,5 @@
 //! This module is the config interface for the rest api server
-use std::{io, fs};
+use std::{io, fs, u8, u32};
 use std::io::Read;
 use std::path::Path;
 #[cfg(feature = "ssl")]
@@ -9,25 +9,64 @@ use std::collections::BTreeMap;
 use toml::{Parser, Value};
 use chrono::Duration;
 
+use totp::{Totp, TotpAlgorithm, MIN_DIGITS, MAX_DIGITS};
+use password::{PasswordScheme, PasswordPolicy};
+use sms::{SmsSender, SmsProvider, LogSmsSender, WebhookSmsSender};
+use storage::{BlobStore, BlobStoreKind, FileBlobStore, HttpBlobStore};
+
 const CONFIG_FILE: &'static str = "config.toml";
 
+/// Maximum number of login attempts that can be kept in the login history of each user.
+const MAX_LOGIN_HISTORY_LEN: i64 = 10000;
+/// Minimum number of digits of the phone verification codes.
+const MIN_SMS_CODE_DIGITS: u32 = 4;
+/// Maximum number of digits of the phone verification codes.
+const MAX_SMS_CODE_DIGITS: u32 = 10;
+
 /// The config struct.
-#[cfg(feature = "ssl")]
 pub struct Config {
     session_remember: Duration,
+    max_failed_logins: u32,
+    lockout_time: Duration,
+    reset_password_ttl: Duration,
+    email_change_ttl: Duration,
+    magic_link_ttl: Duration,
+    login_history_len: usize,
+    account_deletion_grace: Duration,
+    data_export_ttl: Duration,
+    totp_issuer: String,
+    totp_algorithm: TotpAlgorithm,
+    totp_digits: u32,
+    totp_period: u64,
+    totp_skew: u64,
+    scrypt_log_n: u8,
+    scrypt_r: u32,
+    scrypt_p: u32,
+    password_min_length: usize,
+    password_min_classes: u32,
+    password_min_entropy: u32,
+    password_reject_personal: bool,
+    password_reject_common: bool,
+    sms_provider: String,
+    sms_log_file: String,
+    sms_webhook_url: String,
+    sms_webhook_token: String,
+    sms_code_digits: u32,
+    sms_code_ttl: Duration,
+    sms_max_sends: u32,
+    sms_send_window: Duration,
+    blob_store: String,
+    blob_store_path: String,
+    blob_store_url: String,
+    blob_store_token: String,
+    #[cfg(feature = "ssl")]
     ssl_cert: PathBuf,
+    #[cfg(feature = "ssl")]
     ssl_key: PathBuf,
 }
 
-/// The config struct.
-#[cfg(not(feature = "ssl"))]
-pub struct Config {
-    session_remember: Duration,
-}
-
 impl Config {
     /// Gets the configuration from file.
-    #[cfg(feature = "ssl")]
     pub fn from_file() -> Result<Config, io::Error> {
         let mut config: Config = Default::default();
 
@@ -37,35 +76,148 @@ impl Config {
                     "session_remember" => {
                         config.session_remember = Duration::seconds(value.as_integer().unwrap())
                     }
-                    "ssl_cert" => config.ssl_cert = PathBuf::from(value.as_str().unwrap()),
-                    "ssl_key" => config.ssl_key = PathBuf::from(value.as_str().unwrap()),
+                    "max_failed_logins" => {
+                        config.max_failed_logins = value.as_integer().unwrap() as u32
+                    }
+                    "lockout_time" => {
+                        config.lockout_time = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "reset_password_ttl" => {
+                        config.reset_password_ttl = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "email_change_ttl" => {
+                        config.email_change_ttl = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "magic_link_ttl" => {
+                        config.magic_link_ttl = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "login_history_len" => {
+                        config.login_history_len =
+                            try!(get_integer(&key, &value, MAX_LOGIN_HISTORY_LEN)) as usize
+                    }
+                    "account_deletion_grace" => {
+                        config.account_deletion_grace =
+                            Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "data_export_ttl" => {
+                        config.data_export_ttl = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "totp_issuer" => config.totp_issuer = String::from(value.as_str().unwrap()),
+                    "totp_algorithm" => {
+                        config.totp_algorithm = match value.as_str().unwrap().parse() {
+                            Ok(algorithm) => algorithm,
+                            Err(_) => {
+                                return Err(invalid_config("totp_algorithm must be SHA1, SHA256 \
+                                                           or SHA512"))
+                            }
+                        }
+                    }
+                    "totp_digits" => config.totp_digits = value.as_integer().unwrap() as u32,
+                    "totp_period" => config.totp_period = value.as_integer().unwrap() as u64,
+                    "totp_skew" => config.totp_skew = value.as_integer().unwrap() as u64,
+                    "scrypt_log_n" => {
+                        config.scrypt_log_n = try!(get_integer(&key, &value, u8::MAX as i64)) as u8
+                    }
+                    "scrypt_r" => {
+                        config.scrypt_r = try!(get_integer(&key, &value, u32::MAX as i64)) as u32
+                    }
+                    "scrypt_p" => {
+                        config.scrypt_p = try!(get_integer(&key, &value, u32::MAX as i64)) as u32
+                    }
+                    "password_min_length" => {
+                        config.password_min_length = value.as_integer().unwrap() as usize
+                    }
+                    "password_min_classes" => {
+                        config.password_min_classes = value.as_integer().unwrap() as u32
+                    }
+                    "password_min_entropy" => {
+                        config.password_min_entropy = value.as_integer().unwrap() as u32
+                    }
+                    "password_reject_personal" => {
+                        config.password_reject_personal = value.as_bool().unwrap()
+                    }
+                    "password_reject_common" => {
+                        config.password_reject_common = value.as_bool().unwrap()
+                    }
+                    "sms_provider" => config.sms_provider = String::from(value.as_str().unwrap()),
+                    "sms_log_file" => config.sms_log_file = String::from(value.as_str().unwrap()),
+                    "sms_webhook_url" => {
+                        config.sms_webhook_url = String::from(value.as_str().unwrap())
+                    }
+                    "sms_webhook_token" => {
+                        config.sms_webhook_token = String::from(value.as_str().unwrap())
+                    }
+                    "sms_code_digits" => {
+                        config.sms_code_digits = value.as_integer().unwrap() as u32
+                    }
+                    "sms_code_ttl" => {
+                        config.sms_code_ttl = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "sms_max_sends" => config.sms_max_sends = value.as_integer().unwrap() as u32,
+                    "sms_send_window" => {
+                        config.sms_send_window = Duration::seconds(value.as_integer().unwrap())
+                    }
+                    "blob_store" => config.blob_store = String::from(value.as_str().unwrap()),
+                    "blob_store_path" => {
+                        config.blob_store_path = String::from(value.as_str().unwrap())
+                    }
+                    "blob_store_url" => {
+                        config.blob_store_url = String::from(value.as_str().unwrap())
+                    }
+                    "blob_store_token" => {
+                        config.blob_store_token = String::from(value.as_str().unwrap())
+                    }
+                    "ssl_cert" | "ssl_key" => config.set_ssl_path(key.as_str(), &value),
                     _ => unreachable!(),
                 }
             }
         }
+        try!(config.validate());
         Ok(config)
     }
 
-    /// Gets the configuration from file.
-    #[cfg(not(feature = "ssl"))]
-    pub fn from_file() -> Result<Config, io::Error> {
-        let mut config: Config = Default::default();
-
-        if let Some(parser) = try!(Config::get_config_file_parser()) {
-            for (key, value) in parser {
-                match key.as_str() {
-                    "session_remember" => {
-                        config.session_remember = Duration::seconds(value.as_integer().unwrap())
-                    }
-                    "ssl_cert" | "ssl_key" => {}
-                    _ => unreachable!(),
-                }
-            }
+    /// Checks that the values of the configuration can be used, so that the server fails to
+    /// start with a clear error instead of failing later on.
+    fn validate(&self) -> Result<(), io::Error> {
+        if self.totp_digits < MIN_DIGITS || self.totp_digits > MAX_DIGITS {
+            return Err(invalid_config("totp_digits must be between 6 and 8"));
+        }
+        if self.totp_period == 0 {
+            return Err(invalid_config("totp_period must be greater than 0"));
+        }
+        if self.login_history_len == 0 {
+            return Err(invalid_config("login_history_len must be greater than 0"));
         }
+        if let Err(e) = self.get_password_scheme().check_params() {
+            return Err(invalid_config(e));
+        }
+        if self.sms_provider.parse::<SmsProvider>().is_err() {
+            return Err(invalid_config("sms_provider must be log or webhook"));
+        }
+        if self.sms_code_digits < MIN_SMS_CODE_DIGITS ||
+           self.sms_code_digits > MAX_SMS_CODE_DIGITS {
+            return Err(invalid_config("sms_code_digits must be between 4 and 10"));
+        }
+        if self.blob_store.parse::<BlobStoreKind>().is_err() {
+            return Err(invalid_config("blob_store must be file or http"));
+        }
+        Ok(())
+    }
 
-        Ok(config)
+    /// Sets the SSL certificate or key path from the config file.
+    #[cfg(feature = "ssl")]
+    fn set_ssl_path(&mut self, key: &str, value: &Value) {
+        match key {
+            "ssl_cert" => self.ssl_cert = PathBuf::from(value.as_str().unwrap()),
+            "ssl_key" => self.ssl_key = PathBuf::from(value.as_str().unwrap()),
+            _ => unreachable!(),
+        }
     }
 
+    /// Ignores the SSL paths, since SSL support is disabled.
+    #[cfg(not(feature = "ssl"))]
+    fn set_ssl_path(&mut self, _key: &str, _value: &Value) {}
+
     /// Gets the config file parser.
     fn get_config_file_parser() -> Result<Option<BTreeMap<String, Value>>, io::Error> {
         Ok(if Path::new(CONFIG_FILE).exists() {
@@ -83,6 +235,126 @@ impl Config {
         self.session_remember
     }
 
+    /// Gets the number of consecutive failed login or TOTP attempts before the account is locked.
+    pub fn get_max_failed_logins(&self) -> u32 {
+        self.max_failed_logins
+    }
+
+    /// Gets how long an account stays locked after too many failed attempts.
+    pub fn get_lockout_time(&self) -> Duration {
+        self.lockout_time
+    }
+
+    /// Gets how long the password reset keys are valid for.
+    pub fn get_reset_password_ttl(&self) -> Duration {
+        self.reset_password_ttl
+    }
+
+    /// Gets how long an email change waits for the new address to be confirmed.
+    pub fn get_email_change_ttl(&self) -> Duration {
+        self.email_change_ttl
+    }
+
+    /// Gets how long the passwordless login links are valid.
+    pub fn get_magic_link_ttl(&self) -> Duration {
+        self.magic_link_ttl
+    }
+
+    /// Gets the number of login attempts kept in the login history of each user.
+    pub fn get_login_history_len(&self) -> usize {
+        self.login_history_len
+    }
+
+    /// Gets how long deleted accounts are kept, disabled, before they are purged.
+    pub fn get_account_deletion_grace(&self) -> Duration {
+        self.account_deletion_grace
+    }
+
+    /// Gets how long the download links of the personal data exports are valid.
+    pub fn get_data_export_ttl(&self) -> Duration {
+        self.data_export_ttl
+    }
+
+    /// Gets the default issuer shown in the authenticator apps.
+    pub fn get_totp_issuer(&self) -> &str {
+        &self.totp_issuer
+    }
+
+    /// Gets the default TOTP generator for the configured algorithm, digits, period and allowed
+    /// clock skew.
+    pub fn get_totp(&self) -> Totp {
+        Totp::new(self.totp_algorithm,
+                  self.totp_digits,
+                  self.totp_period,
+                  self.totp_skew)
+    }
+
+    /// Gets the password hashing scheme with the configured scrypt parameters.
+    pub fn get_password_scheme(&self) -> PasswordScheme {
+        PasswordScheme::new(self.scrypt_log_n, self.scrypt_r, self.scrypt_p)
+    }
+
+    /// Gets the password strength policy for new passwords.
+    pub fn get_password_policy(&self) -> PasswordPolicy {
+        PasswordPolicy::new(self.password_min_length,
+                            self.password_min_classes,
+                            self.password_min_entropy,
+                            self.password_reject_personal,
+                            self.password_reject_common)
+    }
+
+    /// Gets the configured SMS provider.
+    pub fn get_sms_sender(&self) -> Box<SmsSender> {
+        // The provider was checked when the configuration was loaded.
+        match self.sms_provider.parse().unwrap() {
+            SmsProvider::Log => Box::new(LogSmsSender::new(self.sms_log_file.as_str())),
+            SmsProvider::Webhook => {
+                Box::new(WebhookSmsSender::new(self.sms_webhook_url.as_str(),
+                                               if self.sms_webhook_token.is_empty() {
+                                                   None
+                                               } else {
+                                                   Some(self.sms_webhook_token.clone())
+                                               }))
+            }
+        }
+    }
+
+    /// Gets the number of digits of the phone verification codes.
+    pub fn get_sms_code_digits(&self) -> u32 {
+        self.sms_code_digits
+    }
+
+    /// Gets how long the phone verification codes are valid for.
+    pub fn get_sms_code_ttl(&self) -> Duration {
+        self.sms_code_ttl
+    }
+
+    /// Gets the maximum number of verification codes sent to the same number in the send window.
+    pub fn get_sms_max_sends(&self) -> u32 {
+        self.sms_max_sends
+    }
+
+    /// Gets the window in which the verification codes sent to a number are counted.
+    pub fn get_sms_send_window(&self) -> Duration {
+        self.sms_send_window
+    }
+
+    /// Gets the configured blob store for uploaded files.
+    pub fn get_blob_store(&self) -> Box<BlobStore> {
+        // The kind of store was checked when the configuration was loaded.
+        match self.blob_store.parse().unwrap() {
+            BlobStoreKind::File => Box::new(FileBlobStore::new(self.blob_store_path.as_str())),
+            BlobStoreKind::Http => {
+                Box::new(HttpBlobStore::new(self.blob_store_url.as_str(),
+                                            if self.blob_store_token.is_empty() {
+                                                None
+                                            } else {
+                                                Some(self.blob_store_token.clone())
+                                            }))
+            }
+        }
+    }
+
     /// Gets the SSL certificate path.
     #[cfg(feature = "ssl")]
     pub fn get_ssl_cert(&self) -> PathBuf {
@@ -96,11 +368,58 @@ impl Config {
     }
 }
 
+/// Creates the error for an invalid value in the configuration file.
+fn invalid_config(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData,
+                   format!("invalid configuration in {}: {}", CONFIG_FILE, message))
+}
+
+/// Gets the integer value of the given key, checking that it is between 0 and `max`.
+fn get_integer(key: &str, value: &Value, max: i64) -> Result<i64, io::Error> {
+    match value.as_integer() {
+        Some(integer) if integer >= 0 && integer <= max => Ok(integer),
+        _ => Err(invalid_config(&format!("{} must be an integer between 0 and {}", key, max))),
+    }
+}
+
 #[cfg(feature = "ssl")]
 impl Default for Config {
     fn default() -> Config {
         Config {
             session_remember: Duration::weeks(2),
+            max_failed_logins: 5,
+            lockout_time: Duration::minutes(15),
+            reset_password_ttl: Duration::hours(1),
+            email_change_ttl: Duration::days(1),
+            magic_link_ttl: Duration::minutes(10),
+            login_history_len: 50,
+            account_deletion_grace: Duration::days(30),
+            data_export_ttl: Duration::days(7),
+            totp_issuer: String::from("Auth Example"),
+            totp_algorithm: TotpAlgorithm::Sha1,
+            totp_digits: 6,
+            totp_period: 30,
+            totp_skew: 1,
+            scrypt_log_n: 15,
+            scrypt_r: 8,
+            scrypt_p: 1,
+            password_min_length: 10,
+            password_min_classes: 2,
+            password_min_entropy: 40,
+            password_reject_personal: true,
+            password_reject_common: true,
+            sms_provider: String::from("log"),
+            sms_log_file: String::from("sms.log"),
+            sms_webhook_url: String::new(),
+            sms_webhook_token: String::new(),
+            sms_code_digits: 6,
+            sms_code_ttl: Duration::minutes(10),
+            sms_max_sends: 5,
+            sms_send_window: Duration::hours(1),
+            blob_store: String::from("file"),
+            blob_store_path: String::from("blobs"),
+            blob_store_url: String::new(),
+            blob_store_token: String::new(),
             ssl_cert: PathBuf::from("my.domain.com.crt"),
             ssl_key: PathBuf::from("my.domain.com.pem"),
         }
@@ -110,6 +429,126 @@ impl Default for Config {
 #[cfg(not(feature = "ssl"))]
 impl Default for Config {
     fn default() -> Config {
-        Config { session_remember: Duration::weeks(2) }
+        Config {
+            session_remember: Duration::weeks(2),
+            max_failed_logins: 5,
+            lockout_time: Duration::minutes(15),
+            reset_password_ttl: Duration::hours(1),
+            email_change_ttl: Duration::days(1),
+            magic_link_ttl: Duration::minutes(10),
+            login_history_len: 50,
+            account_deletion_grace: Duration::days(30),
+            data_export_ttl: Duration::days(7),
+            totp_issuer: String::from("Auth Example"),
+            totp_algorithm: TotpAlgorithm::Sha1,
+            totp_digits: 6,
+            totp_period: 30,
+            totp_skew: 1,
+            scrypt_log_n: 15,
+            scrypt_r: 8,
+            scrypt_p: 1,
+            password_min_length: 10,
+            password_min_classes: 2,
+            password_min_entropy: 40,
+            password_reject_personal: true,
+            password_reject_common: true,
+            sms_provider: String::from("log"),
+            sms_log_file: String::from("sms.log"),
+            sms_webhook_url: String::new(),
+            sms_webhook_token: String::new(),
+            sms_code_digits: 6,
+            sms_code_ttl: Duration::minutes(10),
+            sms_max_sends: 5,
+            sms_send_window: Duration::hours(1),
+            blob_store: String::from("file"),
+            blob_store_path: String::from("blobs"),
+            blob_store_url: String::new(),
+            blob_store_token: String::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+
+    #[test]
+    fn default_config_is_valid() {
+        let config: Config = Default::default();
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn totp_digits() {
+        let mut config: Config = Default::default();
+        for &digits in &[6, 7, 8] {
+            config.totp_digits = digits;
+            assert!(config.validate().is_ok());
+        }
+        for &digits in &[0, 5, 9, 10] {
+            config.totp_digits = digits;
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn scrypt_params() {
+        let mut config: Config = Default::default();
+        config.scrypt_r = 0;
+        assert!(config.validate().is_err());
+        config.scrypt_r = 8;
+        config.scrypt_log_n = 0;
+        assert!(config.validate().is_err());
+        config.scrypt_log_n = 14;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn totp_period() {
+        let mut config: Config = Default::default();
+        config.totp_period = 0;
+        assert!(config.validate().is_err());
+        config.totp_period = 1;
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn sms_provider() {
+        let mut config: Config = Default::default();
+        config.sms_provider = String::from("Webhook");
+        assert!(config.validate().is_ok());
+        config.sms_provider = String::from("carrier pigeon");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn blob_store() {
+        let mut config: Config = Default::default();
+        config.blob_store = String::from("HTTP");
+        assert!(config.validate().is_ok());
+        config.blob_store = String::from("s3");
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn sms_code_digits() {
+        let mut config: Config = Default::default();
+        for &digits in &[4, 6, 10] {
+            config.sms_code_digits = digits;
+            assert!(config.validate().is_ok());
+        }
+        for &digits in &[0, 3, 11] {
+            config.sms_code_digits = digits;
+            assert!(config.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn login_history_len() {
+        let mut config: Config = Default::default();
+        config.login_history_len = 0;
+        assert!(config.validate().is_err());
+        config.login_history_len = 1;
+        assert!(config.validate().is_ok());
     }
 }
@@ -0,0 +1,391 @@
+//! This module holds the audit log of the security relevant events in the redis database
+//!
+//! The events are appended to the `audit_log` stream, and to the `users:{id}:audit_log` stream
+//! of the user they affect, so that the log of a user can be queried without scanning all of it.
+//! Streams are append only, and are capped to about the last `AUDIT_LOG_LEN` events. They
+//! require Redis 5 or newer.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, UTC, NaiveDateTime};
+use redis;
+
+use error::Result;
+use super::Database;
+
+/// Approximate maximum number of events kept in each audit log stream.
+const AUDIT_LOG_LEN: usize = 1000000;
+
+/// Number of events read from the streams at once when querying the log.
+const AUDIT_LOG_BATCH: usize = 200;
+
+/// Maximum number of stream entries scanned by a single query of the log, so that a filter
+/// matching few events cannot walk the whole stream.
+const AUDIT_LOG_SCAN_LIMIT: usize = 20000;
+
+/// An action recorded in the audit log.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuditAction {
+    /// A new user registered.
+    Register,
+    /// The user logged in.
+    Login,
+    /// A login or second factor check of the user failed.
+    LoginFailed,
+    /// The user revoked one of its sessions.
+    SessionRevoked,
+    /// The user revoked all of its sessions but the current one.
+    OtherSessionsRevoked,
+    /// The user was locked out after too many failed attempts.
+    Lockout,
+    /// The user was unlocked by an admin.
+    Unlock,
+    /// The user changed the password.
+    PasswordChange,
+    /// The user requested a passwordless login link.
+    MagicLinkRequested,
+    /// The user started a password reset.
+    PasswordResetRequested,
+    /// The user reset the password with a reset key.
+    PasswordReset,
+    /// The user changed the username.
+    UsernameChange,
+    /// The user requested an email change.
+    EmailChangeRequested,
+    /// The new email of the user was confirmed.
+    EmailChange,
+    /// A pending email change was cancelled.
+    EmailChangeCancelled,
+    /// Two factor authentication was enabled.
+    TwoFactorEnabled,
+    /// Two factor authentication was disabled.
+    TwoFactorDisabled,
+    /// A new OAuth client was created.
+    ClientCreated,
+    /// The TOTP settings of an OAuth client were changed.
+    ClientTotpSettings,
+    /// The roles of a user or an OAuth client were changed.
+    RolesChange,
+    /// The user was banned.
+    BanUser,
+    /// The ban of the user was lifted.
+    UnbanUser,
+    /// The user account was enabled.
+    EnableUser,
+    /// The user account was disabled.
+    DisableUser,
+    /// All the sessions of the user were revoked.
+    ForceLogout,
+    /// The password of the user was invalidated and a reset email sent.
+    ForcePasswordReset,
+    /// The user was deleted.
+    DeleteUser,
+    /// The user requested the deletion of its account.
+    AccountDeletionRequested,
+    /// The deletion of the account was cancelled.
+    AccountDeletionCancelled,
+    /// The user requested an export of its personal data.
+    DataExportRequested,
+    /// The user downloaded the export of its personal data.
+    DataExportDownloaded,
+}
+
+impl fmt::Display for AuditAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   AuditAction::Register => "register",
+                   AuditAction::Login => "login",
+                   AuditAction::LoginFailed => "login_failed",
+                   AuditAction::SessionRevoked => "session_revoked",
+                   AuditAction::OtherSessionsRevoked => "other_sessions_revoked",
+                   AuditAction::Lockout => "lockout",
+                   AuditAction::Unlock => "unlock",
+                   AuditAction::PasswordChange => "password_change",
+                   AuditAction::MagicLinkRequested => "magic_link_requested",
+                   AuditAction::PasswordResetRequested => "password_reset_requested",
+                   AuditAction::PasswordReset => "password_reset",
+                   AuditAction::UsernameChange => "username_change",
+                   AuditAction::EmailChangeRequested => "email_change_requested",
+                   AuditAction::EmailChange => "email_change",
+                   AuditAction::EmailChangeCancelled => "email_change_cancelled",
+                   AuditAction::TwoFactorEnabled => "two_factor_enabled",
+                   AuditAction::TwoFactorDisabled => "two_factor_disabled",
+                   AuditAction::ClientCreated => "client_created",
+                   AuditAction::ClientTotpSettings => "client_totp_settings",
+                   AuditAction::RolesChange => "roles_change",
+                   AuditAction::BanUser => "ban_user",
+                   AuditAction::UnbanUser => "unban_user",
+                   AuditAction::EnableUser => "enable_user",
+                   AuditAction::DisableUser => "disable_user",
+                   AuditAction::ForceLogout => "force_logout",
+                   AuditAction::ForcePasswordReset => "force_password_reset",
+                   AuditAction::DeleteUser => "delete_user",
+                   AuditAction::AccountDeletionRequested => "account_deletion_requested",
+                   AuditAction::AccountDeletionCancelled => "account_deletion_cancelled",
+                   AuditAction::DataExportRequested => "data_export_requested",
+                   AuditAction::DataExportDownloaded => "data_export_downloaded",
+               })
+    }
+}
+
+impl FromStr for AuditAction {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<AuditAction, ()> {
+        match s {
+            "register" => Ok(AuditAction::Register),
+            "login" => Ok(AuditAction::Login),
+            "login_failed" => Ok(AuditAction::LoginFailed),
+            "session_revoked" => Ok(AuditAction::SessionRevoked),
+            "other_sessions_revoked" => Ok(AuditAction::OtherSessionsRevoked),
+            "lockout" => Ok(AuditAction::Lockout),
+            "unlock" => Ok(AuditAction::Unlock),
+            "password_change" => Ok(AuditAction::PasswordChange),
+            "magic_link_requested" => Ok(AuditAction::MagicLinkRequested),
+            "password_reset_requested" => Ok(AuditAction::PasswordResetRequested),
+            "password_reset" => Ok(AuditAction::PasswordReset),
+            "username_change" => Ok(AuditAction::UsernameChange),
+            "email_change_requested" => Ok(AuditAction::EmailChangeRequested),
+            "email_change" => Ok(AuditAction::EmailChange),
+            "email_change_cancelled" => Ok(AuditAction::EmailChangeCancelled),
+            "two_factor_enabled" => Ok(AuditAction::TwoFactorEnabled),
+            "two_factor_disabled" => Ok(AuditAction::TwoFactorDisabled),
+            "client_created" => Ok(AuditAction::ClientCreated),
+            "client_totp_settings" => Ok(AuditAction::ClientTotpSettings),
+            "roles_change" => Ok(AuditAction::RolesChange),
+            "ban_user" => Ok(AuditAction::BanUser),
+            "unban_user" => Ok(AuditAction::UnbanUser),
+            "enable_user" => Ok(AuditAction::EnableUser),
+            "disable_user" => Ok(AuditAction::DisableUser),
+            "force_logout" => Ok(AuditAction::ForceLogout),
+            "force_password_reset" => Ok(AuditAction::ForcePasswordReset),
+            "delete_user" => Ok(AuditAction::DeleteUser),
+            "account_deletion_requested" => Ok(AuditAction::AccountDeletionRequested),
+            "account_deletion_cancelled" => Ok(AuditAction::AccountDeletionCancelled),
+            "data_export_requested" => Ok(AuditAction::DataExportRequested),
+            "data_export_downloaded" => Ok(AuditAction::DataExportDownloaded),
+            _ => Err(()),
+        }
+    }
+}
+
+/// An entry of the audit log.
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    /// The ID of the event in the stream, empty until it is stored.
+    pub id: String,
+    /// When the action happened.
+    pub time: DateTime<UTC>,
+    /// The app ID of the token that performed the action.
+    pub app_id: String,
+    /// The IP address the request came from, if known.
+    pub ip: Option<String>,
+    /// The user the action was performed on, if any.
+    pub user_id: Option<u64>,
+    /// The action.
+    pub action: AuditAction,
+    /// The value before the action, if it changed one.
+    pub before: Option<String>,
+    /// The value after the action, if it changed one.
+    pub after: Option<String>,
+    /// The reason given for the action, if any.
+    pub reason: Option<String>,
+}
+
+impl AuditEvent {
+    /// Creates a new event for an action happening now.
+    pub fn new<S: AsRef<str>>(app_id: S,
+                              ip: Option<String>,
+                              user_id: Option<u64>,
+                              action: AuditAction)
+                              -> AuditEvent {
+        AuditEvent {
+            id: String::new(),
+            time: UTC::now(),
+            app_id: String::from(app_id.as_ref()),
+            ip: ip,
+            user_id: user_id,
+            action: action,
+            before: None,
+            after: None,
+            reason: None,
+        }
+    }
+
+    /// Gets the fields stored in the stream for the event.
+    fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("time", format!("{}", self.time.timestamp())),
+                              ("app_id", self.app_id.clone()),
+                              ("action", format!("{}", self.action))];
+        if let Some(ref ip) = self.ip {
+            fields.push(("ip", ip.clone()));
+        }
+        if let Some(user_id) = self.user_id {
+            fields.push(("user_id", format!("{}", user_id)));
+        }
+        if let Some(ref before) = self.before {
+            fields.push(("before", before.clone()));
+        }
+        if let Some(ref after) = self.after {
+            fields.push(("after", after.clone()));
+        }
+        if let Some(ref reason) = self.reason {
+            fields.push(("reason", reason.clone()));
+        }
+        fields
+    }
+
+    /// Creates the event from the ID and the fields of a stream entry.
+    ///
+    /// Entries with missing or unknown fields are ignored, so that the log can still be read if
+    /// an action is removed.
+    fn from_stream_entry(id: String, mut data: HashMap<String, String>) -> Option<AuditEvent> {
+        let time = match data.get("time").and_then(|t| t.parse::<i64>().ok()) {
+            Some(time) => DateTime::from_utc(NaiveDateTime::from_timestamp(time, 0), UTC),
+            None => return None,
+        };
+        let action = match data.get("action").and_then(|a| a.parse::<AuditAction>().ok()) {
+            Some(action) => action,
+            None => return None,
+        };
+        let app_id = match data.remove("app_id") {
+            Some(app_id) => app_id,
+            None => return None,
+        };
+        Some(AuditEvent {
+            id: id,
+            time: time,
+            app_id: app_id,
+            ip: data.remove("ip"),
+            user_id: data.get("user_id").and_then(|u| u.parse::<u64>().ok()),
+            action: action,
+            before: data.remove("before"),
+            after: data.remove("after"),
+            reason: data.remove("reason"),
+        })
+    }
+}
+
+/// Filter of the audit log queries. Empty fields match all the events.
+#[derive(Debug, Clone, Default)]
+pub struct AuditFilter {
+    /// The user the actions were performed on.
+    pub user_id: Option<u64>,
+    /// The app ID of the token that performed the actions.
+    pub app_id: Option<String>,
+    /// The action.
+    pub action: Option<AuditAction>,
+    /// The earliest time of the events.
+    pub from: Option<DateTime<UTC>>,
+    /// The latest time of the events.
+    pub to: Option<DateTime<UTC>>,
+}
+
+impl AuditFilter {
+    /// Checks if the event matches the filter.
+    fn matches(&self, event: &AuditEvent) -> bool {
+        (self.user_id.is_none() || self.user_id == event.user_id) &&
+        (self.app_id.is_none() || self.app_id.as_ref() == Some(&event.app_id)) &&
+        (self.action.is_none() || self.action == Some(event.action))
+    }
+}
+
+/// Returns the stream ID right before the given one, to page through a stream backwards.
+fn previous_stream_id(id: &str) -> Option<String> {
+    let mut parts = id.splitn(2, '-');
+    let ms = match parts.next().and_then(|ms| ms.parse::<u64>().ok()) {
+        Some(ms) => ms,
+        None => return None,
+    };
+    let seq = parts.next().and_then(|seq| seq.parse::<u64>().ok()).unwrap_or(0);
+    if seq > 0 {
+        Some(format!("{}-{}", ms, seq - 1))
+    } else if ms > 0 {
+        Some(format!("{}-{}", ms - 1, u64::max_value()))
+    } else {
+        None
+    }
+}
+
+/// Methods working with the audit log
+impl Database {
+    /// Appends the event to the audit log, and to the audit log of the user it affects
+    pub fn add_audit_event(&self, event: &AuditEvent) -> Result<()> {
+        let fields = event.fields();
+        let db = self.inner.lock().unwrap();
+        let mut streams = vec![String::from("audit_log")];
+        if let Some(user_id) = event.user_id {
+            streams.push(format!("users:{}:audit_log", user_id));
+        }
+        for stream in streams {
+            let _: String = try!(redis::cmd("XADD")
+                .arg(stream)
+                .arg("MAXLEN")
+                .arg("~")
+                .arg(AUDIT_LOG_LEN)
+                .arg("*")
+                .arg(&fields[..])
+                .query(&db.connection));
+        }
+        Ok(())
+    }
+
+    /// Gets the latest events of the audit log matching the filter, newest first
+    ///
+    /// At most `AUDIT_LOG_SCAN_LIMIT` entries are scanned, so fewer than `limit` events might be
+    /// returned even if older ones match the filter. The time range of the filter can be used to
+    /// reach them.
+    pub fn get_audit_events(&self, filter: &AuditFilter, limit: usize) -> Result<Vec<AuditEvent>> {
+        let stream = match filter.user_id {
+            Some(user_id) => format!("users:{}:audit_log", user_id),
+            None => String::from("audit_log"),
+        };
+        let start = match filter.from {
+            Some(from) => format!("{}", from.timestamp() * 1000),
+            None => String::from("-"),
+        };
+        let mut end = match filter.to {
+            Some(to) => format!("{}", to.timestamp() * 1000 + 999),
+            None => String::from("+"),
+        };
+
+        let mut events = Vec::new();
+        let mut scanned = 0;
+        while events.len() < limit && scanned < AUDIT_LOG_SCAN_LIMIT {
+            // The lock is only held for each batch, so that other requests can use the
+            // connection while the log is paged through.
+            let entries: Vec<(String, HashMap<String, String>)> = {
+                let db = self.inner.lock().unwrap();
+                try!(redis::cmd("XREVRANGE")
+                    .arg(&stream)
+                    .arg(&end)
+                    .arg(&start)
+                    .arg("COUNT")
+                    .arg(AUDIT_LOG_BATCH)
+                    .query(&db.connection))
+            };
+            scanned += entries.len();
+            let last_id = match entries.last() {
+                Some(&(ref id, _)) => id.clone(),
+                None => break,
+            };
+            let batch_len = entries.len();
+            for (id, data) in entries {
+                if let Some(event) = AuditEvent::from_stream_entry(id, data) {
+                    if filter.matches(&event) && events.len() < limit {
+                        events.push(event);
+                    }
+                }
+            }
+            end = match previous_stream_id(&last_id) {
+                Some(id) if batch_len == AUDIT_LOG_BATCH => id,
+                _ => break,
+            };
+        }
+        Ok(events)
+    }
+}
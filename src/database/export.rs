@@ -0,0 +1,107 @@
+//! This module holds the personal data exports of the users in the redis database
+//!
+//! The IDs of the users waiting for their export are queued in the `pending_exports` list, and
+//! `users:{id}:export_pending` is set while the export is being prepared. Once it is in the blob
+//! store, the `data_exports:{key}` key points to the user until the download link expires, and
+//! the key of the blob is added to the `data_exports` sorted set, scored by its expiration, so
+//! that the blob can be deleted afterwards.
+
+use chrono::{DateTime, UTC};
+use redis::Commands;
+
+use error::Result;
+use super::Database;
+
+/// Returns the key of the export with the given download key in the blob store.
+pub fn export_blob_key<S: AsRef<str>>(user_id: u64, export_key: S) -> String {
+    format!("exports/{}/{}", user_id, export_key.as_ref())
+}
+
+/// Methods working with the personal data exports
+impl Database {
+    /// Queues a data export for the user, and returns whether it was queued
+    ///
+    /// Nothing is queued if an export of the user is already being prepared.
+    pub fn queue_data_export(&self, user_id: u64) -> Result<bool> {
+        let db = self.inner.lock().unwrap();
+        let queued: bool =
+            try!(db.connection.set_nx(format!("users:{}:export_pending", user_id), 1));
+        if queued {
+            try!(db.connection.rpush("pending_exports", user_id));
+        }
+        Ok(queued)
+    }
+
+    /// Takes the next user waiting for a data export from the queue
+    pub fn next_data_export(&self) -> Result<Option<u64>> {
+        Ok(try!(self.inner.lock().unwrap().connection.lpop("pending_exports")))
+    }
+
+    /// Stores the download key of the prepared export of the user, valid until the given time
+    ///
+    /// The previous download key of the user stops working.
+    pub fn finish_data_export<S: AsRef<str>>(&self,
+                                             user_id: u64,
+                                             export_key: S,
+                                             expires: DateTime<UTC>)
+                                             -> Result<()> {
+        let user_key = format!("users:{}:export", user_id);
+        let key = format!("data_exports:{}", export_key.as_ref());
+        let db = self.inner.lock().unwrap();
+        let old_key: Option<String> = try!(db.connection.get(&user_key));
+        if let Some(old_key) = old_key {
+            try!(db.connection.del(format!("data_exports:{}", old_key)));
+        }
+        try!(db.connection.set(&key, user_id));
+        try!(db.connection.expire_at(&key, expires.timestamp() as usize));
+        try!(db.connection.set(&user_key, export_key.as_ref()));
+        try!(db.connection.expire_at(&user_key, expires.timestamp() as usize));
+        try!(db.connection.zadd("data_exports",
+                                export_blob_key(user_id, export_key.as_ref()),
+                                expires.timestamp()));
+        try!(db.connection.del(format!("users:{}:export_pending", user_id)));
+        Ok(())
+    }
+
+    /// Marks the export of the user as no longer being prepared, after it failed
+    pub fn abort_data_export(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}:export_pending", user_id);
+        Ok(try!(self.inner.lock().unwrap().connection.del(key)))
+    }
+
+    /// Returns the user the given export download key belongs to, if it has not expired
+    pub fn get_data_export_user<S: AsRef<str>>(&self, export_key: S) -> Result<Option<u64>> {
+        let key = format!("data_exports:{}", export_key.as_ref());
+        Ok(try!(self.inner.lock().unwrap().connection.get(key)))
+    }
+
+    /// Returns the blob keys of the exports that expired before the given time
+    pub fn get_expired_data_exports(&self, now: DateTime<UTC>) -> Result<Vec<String>> {
+        Ok(try!(self.inner
+            .lock()
+            .unwrap()
+            .connection
+            .zrangebyscore("data_exports", "-inf", now.timestamp())))
+    }
+
+    /// Forgets the expired export, once its blob has been deleted
+    pub fn remove_data_export<S: AsRef<str>>(&self, blob_key: S) -> Result<()> {
+        Ok(try!(self.inner.lock().unwrap().connection.zrem("data_exports", blob_key.as_ref())))
+    }
+
+    /// Deletes the export keys of the user
+    ///
+    /// The exported blob is kept until the download link expires, and is then deleted along with
+    /// the other expired exports.
+    pub fn delete_user_data_export(&self, user_id: u64) -> Result<()> {
+        let user_key = format!("users:{}:export", user_id);
+        let db = self.inner.lock().unwrap();
+        let export_key: Option<String> = try!(db.connection.get(&user_key));
+        if let Some(export_key) = export_key {
+            try!(db.connection.del(format!("data_exports:{}", export_key)));
+        }
+        try!(db.connection.del(&user_key));
+        try!(db.connection.del(format!("users:{}:export_pending", user_id)));
+        Ok(())
+    }
+}
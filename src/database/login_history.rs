@@ -0,0 +1,108 @@
+//! This module holds the login history of the users in the redis database
+//!
+//! The login attempts are kept, newest first, in the bounded `users:{id}:login_history` list. The
+//! user agents and IP addresses of the successful logins are kept in the
+//! `users:{id}:known_devices` and `users:{id}:known_ips` sets, to detect logins from new devices.
+//! Those sets are capped to `MAX_KNOWN_LOGIN_VALUES` members each.
+
+use chrono::{DateTime, UTC, NaiveDateTime};
+use redis::{self, Commands};
+use rustc_serialize::json;
+
+use error::Result;
+use super::Database;
+
+/// Name stored for the logins that did not send a user agent.
+const UNKNOWN_DEVICE: &'static str = "unknown device";
+
+/// Maximum number of devices and of IP addresses remembered for each user. Once a set is full, a
+/// random member is forgotten to make room for a new one.
+const MAX_KNOWN_LOGIN_VALUES: usize = 100;
+
+/// A login attempt of a user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct LoginAttempt {
+    /// UNIX timestamp of the attempt.
+    pub time: i64,
+    /// The IP address the attempt came from.
+    pub ip: Option<String>,
+    /// The user agent of the device, if it was sent.
+    pub user_agent: Option<String>,
+    /// ID of the client application used to log in.
+    pub app_id: String,
+    /// Whether the login succeeded.
+    pub success: bool,
+    /// Why the login failed, if it did.
+    pub reason: Option<String>,
+}
+
+impl LoginAttempt {
+    /// Returns the time of the attempt.
+    pub fn get_time(&self) -> DateTime<UTC> {
+        DateTime::from_utc(NaiveDateTime::from_timestamp(self.time, 0), UTC)
+    }
+
+    /// Returns the name of the device of the attempt.
+    pub fn get_device(&self) -> &str {
+        self.user_agent.as_ref().map(|ua| ua.as_str()).unwrap_or(UNKNOWN_DEVICE)
+    }
+}
+
+/// Methods working with the login history
+impl Database {
+    /// Adds the attempt to the login history of the user, keeping the last `max_len` attempts
+    pub fn add_login_attempt(&self,
+                             user_id: u64,
+                             attempt: &LoginAttempt,
+                             max_len: usize)
+                             -> Result<()> {
+        let key = format!("users:{}:login_history", user_id);
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.lpush(&key, json::encode(attempt).unwrap()));
+        try!(db.connection.ltrim(&key, 0, max_len as isize - 1));
+        Ok(())
+    }
+
+    /// Returns the login history of the user, newest first
+    pub fn get_login_history(&self, user_id: u64) -> Result<Vec<LoginAttempt>> {
+        let key = format!("users:{}:login_history", user_id);
+        let attempts: Vec<String> = try!(self.inner.lock().unwrap().connection.lrange(key, 0, -1));
+        Ok(attempts.iter().filter_map(|a| json::decode(a).ok()).collect())
+    }
+
+    /// Remembers the device and IP address of a successful login, and returns whether any of
+    /// them had never been seen before
+    ///
+    /// The first login recorded for a user is not considered new, since there is nothing to
+    /// compare it with.
+    pub fn register_login_device(&self, user_id: u64, attempt: &LoginAttempt) -> Result<bool> {
+        let devices_key = format!("users:{}:known_devices", user_id);
+        let ips_key = format!("users:{}:known_ips", user_id);
+        let db = self.inner.lock().unwrap();
+        let known: usize = try!(db.connection.scard(&devices_key));
+        let new_device = try!(remember_login_value(&db.connection,
+                                                   &devices_key,
+                                                   attempt.get_device()));
+        let new_ip = match attempt.ip {
+            Some(ref ip) => try!(remember_login_value(&db.connection, &ips_key, ip)),
+            None => false,
+        };
+        Ok(known > 0 && (new_device || new_ip))
+    }
+}
+
+/// Adds the value to the given set of known devices or IP addresses, and returns whether it was
+/// not in the set
+///
+/// If the set is full, a random member is removed first.
+fn remember_login_value(connection: &redis::Connection, key: &str, value: &str) -> Result<bool> {
+    if try!(connection.sismember(key, value)) {
+        return Ok(false);
+    }
+    let len: usize = try!(connection.scard(key));
+    if len >= MAX_KNOWN_LOGIN_VALUES {
+        let _: Option<String> = try!(connection.spop(key));
+    }
+    let _: usize = try!(connection.sadd(key, value));
+    Ok(true)
+}
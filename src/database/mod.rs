@@ -7,12 +7,24 @@ use redis::{Connection, Commands, IntoConnectionInfo, Client};
 use error::{Error, Result};
 
 
+pub mod audit;
+pub mod export;
+pub mod login_history;
 pub mod oauth;
+pub mod role;
+pub mod session;
 pub mod user;
+pub mod verification;
 
 
+pub use self::audit::*;
+pub use self::export::*;
+pub use self::login_history::*;
 pub use self::user::*;
 pub use self::oauth::*;
+pub use self::role::*;
+pub use self::session::*;
+pub use self::verification::*;
 
 
 /// Application's secret length.
@@ -21,6 +33,12 @@ pub const SECRET_LEN: usize = 20;
 /// The TOTP secret length
 pub const TOTP_SECRET_LEN: usize = 20;
 
+/// The number of recovery codes generated for two factor authentication
+pub const RECOVERY_CODES: usize = 10;
+
+/// The number of wrong codes accepted before a phone verification is cancelled
+pub const MAX_PHONE_CODE_ATTEMPTS: u32 = 5;
+
 
 /// The object used to handle our connection to the database
 #[derive(Clone)]
@@ -54,6 +72,10 @@ impl Database {
             try!(connection.set_nx("next_transaction_id", 0));
         }
 
+        if !try!(connection.exists("next_verification_id")) {
+            try!(connection.set_nx("next_verification_id", 0));
+        }
+
         Ok(Database { inner: Arc::new(Mutex::new(InnerDatabase { connection: connection })) })
     }
 
@@ -72,6 +94,14 @@ impl Database {
         }
     }
 
+    /// Increments the verification request ID in the database
+    pub fn increment_verification_id(&self) -> Result<u64> {
+        match self.inner.lock().unwrap().connection.incr("next_verification_id", 1) {
+            Ok(r) => Ok(r),
+            Err(e) => Err(Error::from(e)),
+        }
+    }
+
     /// Increments the client ID in the database
     fn increment_client_id(&self) -> Result<u64> {
         match self.inner.lock().unwrap().connection.incr("next_client_id", 1) {
@@ -15,6 +15,7 @@ use rand::{thread_rng, Rng};
 use rustc_serialize::base64::{FromBase64, STANDARD, ToBase64};
 
 use error::{Error, Result};
+use totp::Totp;
 
 /// Methods working with oAuth
 impl Database {
@@ -98,6 +99,23 @@ impl Database {
         Ok(())
     }
 
+    /// Changes the clients TOTP issuer and settings, using the server defaults for `None`
+    pub fn change_client_totp_settings<S: AsRef<str>>(&self,
+                                                      id: S,
+                                                      issuer: Option<&str>,
+                                                      totp: Option<&Totp>)
+                                                      -> Result<()> {
+        let key = format!("clients:{}", id.as_ref());
+        let data = [("totp_issuer", String::from(issuer.unwrap_or(""))),
+                    ("totp_settings",
+                     match totp {
+                        Some(t) => t.to_settings(),
+                        None => String::new(),
+                    })];
+        try!(self.inner.lock().unwrap().connection.hset_multiple(key, &data));
+        Ok(())
+    }
+
     /// Returns a client
     pub fn get_client<S: AsRef<str>>(&self, id: S) -> Result<Option<DeveloperClient>> {
         let key = format!("clients:{}", id.as_ref());
@@ -115,6 +133,7 @@ impl Database {
 
         let db = self.inner.lock().unwrap();
         try!(db.connection.hdel("clientkeys", client.get_name().as_str()));
+        try!(db.connection.del(format!("{}:roles", key)));
         Ok(try!(db.connection.del(key)))
     }
 
@@ -161,6 +180,10 @@ pub struct DeveloperClient {
     request_count: u32,
     /// The limit of the requests allowed
     request_limit: u32,
+    /// The issuer shown in the authenticator apps of the clients users
+    totp_issuer: Option<String>,
+    /// The TOTP settings for the clients users
+    totp: Option<Totp>,
 }
 
 impl DeveloperClient {
@@ -176,6 +199,8 @@ impl DeveloperClient {
         let mut scopes_str = String::new();
         let mut request_count = 0u32;
         let mut request_limit = 032;
+        let mut totp_issuer = String::new();
+        let mut totp_settings = String::new();
 
         for (key, value) in data.iter() {
             match key.as_ref() {
@@ -184,6 +209,8 @@ impl DeveloperClient {
                 "scopes" => scopes_str = try!(String::from_redis_value(value)),
                 "request_count" => request_count = try!(u32::from_redis_value(value)),
                 "request_limit" => request_limit = try!(u32::from_redis_value(value)),
+                "totp_issuer" => totp_issuer = try!(String::from_redis_value(value)),
+                "totp_settings" => totp_settings = try!(String::from_redis_value(value)),
                 _ => unreachable!(),
             }
         }
@@ -201,6 +228,12 @@ impl DeveloperClient {
             scopes: scopes,
             request_count: request_count,
             request_limit: request_limit,
+            totp_issuer: if totp_issuer.len() > 0 {
+                Some(totp_issuer)
+            } else {
+                None
+            },
+            totp: Totp::from_settings(totp_settings, 0),
         })
     }
 
@@ -233,6 +266,34 @@ impl DeveloperClient {
         Ok(())
     }
 
+    /// Gets the issuer shown in the authenticator apps, if the client has its own
+    pub fn get_totp_issuer(&self) -> Option<&str> {
+        match self.totp_issuer {
+            Some(ref issuer) => Some(issuer),
+            None => None,
+        }
+    }
+
+    /// Gets the TOTP generator for the clients users, or `default` if it has no own settings
+    ///
+    /// The clock skew is always taken from `default`.
+    pub fn get_totp(&self, default: &Totp) -> Totp {
+        match self.totp {
+            Some(ref totp) => totp.with_skew(default.get_skew()),
+            None => *default,
+        }
+    }
+
+    /// Sets the clients TOTP issuer and settings
+    pub fn set_totp_settings(&mut self, issuer: Option<String>, totp: Option<Totp>) -> Result<()> {
+        try!(self.database.change_client_totp_settings(self.get_id(),
+                                                       issuer.as_ref().map(|i| i.as_str()),
+                                                       totp.as_ref()));
+        self.totp_issuer = issuer;
+        self.totp = totp;
+        Ok(())
+    }
+
     /// Deletes the client
     pub fn delete(self) -> Result<()> {
         self.database.delete_client(&self)
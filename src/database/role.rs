@@ -0,0 +1,176 @@
+//! This module holds the roles of the users and clients in the redis database
+//!
+//! Each role grants a fixed set of permissions. The roles of a user are kept in the
+//! `users:{id}:roles` set, and the roles of a client in the `clients:{id}:roles` set.
+
+use std::fmt;
+use std::str::FromStr;
+
+use redis::{self, Commands, PipelineCommands};
+
+use error::Result;
+use super::Database;
+
+/// A permission to perform a group of privileged actions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permission {
+    /// Reading the data of any user, such as its profile, login history or pending email changes.
+    UsersRead,
+    /// Changing any user, unlocking, enabling and disabling it, logging it out or resetting its
+    /// password.
+    UsersWrite,
+    /// Banning and unbanning users.
+    UsersBan,
+    /// Deleting users.
+    UsersDelete,
+    /// Reading the identity verification requests of any user and their documents.
+    VerificationsRead,
+    /// Approving and rejecting identity verification requests.
+    VerificationsReview,
+    /// Querying the audit log.
+    AuditRead,
+    /// Creating clients and changing their settings.
+    ClientsWrite,
+    /// Assigning roles to users and clients.
+    RolesWrite,
+}
+
+/// Permissions of the `Support` role.
+static SUPPORT_PERMISSIONS: [Permission; 3] =
+    [Permission::UsersRead, Permission::VerificationsRead, Permission::AuditRead];
+/// Permissions of the `Moderator` role.
+static MODERATOR_PERMISSIONS: [Permission; 5] = [Permission::UsersRead,
+                                                 Permission::UsersBan,
+                                                 Permission::VerificationsRead,
+                                                 Permission::VerificationsReview,
+                                                 Permission::AuditRead];
+/// Permissions of the `Billing` role.
+static BILLING_PERMISSIONS: [Permission; 1] = [Permission::UsersRead];
+/// Permissions of the `Admin` role, that are all of them.
+static ADMIN_PERMISSIONS: [Permission; 9] = [Permission::UsersRead,
+                                             Permission::UsersWrite,
+                                             Permission::UsersBan,
+                                             Permission::UsersDelete,
+                                             Permission::VerificationsRead,
+                                             Permission::VerificationsReview,
+                                             Permission::AuditRead,
+                                             Permission::ClientsWrite,
+                                             Permission::RolesWrite];
+
+impl fmt::Display for Permission {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   Permission::UsersRead => "users:read",
+                   Permission::UsersWrite => "users:write",
+                   Permission::UsersBan => "users:ban",
+                   Permission::UsersDelete => "users:delete",
+                   Permission::VerificationsRead => "verifications:read",
+                   Permission::VerificationsReview => "verifications:review",
+                   Permission::AuditRead => "audit:read",
+                   Permission::ClientsWrite => "clients:write",
+                   Permission::RolesWrite => "roles:write",
+               })
+    }
+}
+
+/// A role of a user or a client, that grants a set of permissions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Role {
+    /// Customer support, that can look up users, their verification requests and the audit log.
+    Support,
+    /// Moderators, that can also ban users and review verification requests.
+    Moderator,
+    /// Billing staff, that can look up users.
+    Billing,
+    /// Administrators, with every permission.
+    Admin,
+}
+
+impl Role {
+    /// Returns the permissions granted by the role.
+    pub fn get_permissions(&self) -> &'static [Permission] {
+        match *self {
+            Role::Support => &SUPPORT_PERMISSIONS,
+            Role::Moderator => &MODERATOR_PERMISSIONS,
+            Role::Billing => &BILLING_PERMISSIONS,
+            Role::Admin => &ADMIN_PERMISSIONS,
+        }
+    }
+
+    /// Returns whether the role grants the given permission.
+    pub fn has_permission(&self, permission: Permission) -> bool {
+        self.get_permissions().contains(&permission)
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   Role::Support => "support",
+                   Role::Moderator => "moderator",
+                   Role::Billing => "billing",
+                   Role::Admin => "admin",
+               })
+    }
+}
+
+impl FromStr for Role {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<Role, ()> {
+        match s.to_lowercase().as_str() {
+            "support" => Ok(Role::Support),
+            "moderator" => Ok(Role::Moderator),
+            "billing" => Ok(Role::Billing),
+            "admin" => Ok(Role::Admin),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Methods working with roles
+impl Database {
+    /// Returns the roles of the user
+    pub fn get_user_roles(&self, user_id: u64) -> Result<Vec<Role>> {
+        self.get_roles(format!("users:{}:roles", user_id))
+    }
+
+    /// Replaces the roles of the user
+    pub fn set_user_roles(&self, user_id: u64, roles: &[Role]) -> Result<()> {
+        self.set_roles(format!("users:{}:roles", user_id), roles)
+    }
+
+    /// Returns the roles of the client
+    pub fn get_client_roles<S: AsRef<str>>(&self, client_id: S) -> Result<Vec<Role>> {
+        self.get_roles(format!("clients:{}:roles", client_id.as_ref()))
+    }
+
+    /// Replaces the roles of the client
+    pub fn set_client_roles<S: AsRef<str>>(&self, client_id: S, roles: &[Role]) -> Result<()> {
+        self.set_roles(format!("clients:{}:roles", client_id.as_ref()), roles)
+    }
+
+    /// Returns the roles in the given set, ignoring the ones that no longer exist
+    fn get_roles(&self, key: String) -> Result<Vec<Role>> {
+        let roles: Vec<String> = try!(self.inner.lock().unwrap().connection.smembers(key));
+        Ok(roles.iter().filter_map(|role| role.parse().ok()).collect())
+    }
+
+    /// Replaces the roles in the given set
+    ///
+    /// The set is replaced in a transaction, so that the roles are never read half written.
+    fn set_roles(&self, key: String, roles: &[Role]) -> Result<()> {
+        let names: Vec<String> = roles.iter().map(|role| format!("{}", role)).collect();
+        let mut pipe = redis::pipe();
+        let _ = pipe.atomic().del(&key).ignore();
+        if !names.is_empty() {
+            let _ = pipe.sadd(&key, names).ignore();
+        }
+        let _: () = try!(pipe.query(&self.inner.lock().unwrap().connection));
+        Ok(())
+    }
+}
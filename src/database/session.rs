@@ -0,0 +1,188 @@
+//! This module holds the login sessions of the users in the redis database
+//!
+//! Each login is stored in a `sessions:{id}` hash that expires with the token issued for it, and
+//! its ID is added to the `users:{id}:sessions` set of the user.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, UTC, NaiveDateTime};
+use redis::{self, Commands};
+
+use error::Result;
+use utils::{to_millis, from_millis};
+use super::Database;
+
+/// Seconds between the updates of the last seen time of a session.
+const SESSION_TOUCH_INTERVAL: i64 = 60;
+
+/// Script that updates the last seen time of a session only if the session still exists, so
+/// that a session revoked in the meantime is not created again.
+const TOUCH_SESSION_SCRIPT: &'static str = r"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    redis.call('HSET', KEYS[1], 'last_seen', ARGV[1])
+    return 1
+end
+return 0
+";
+
+/// A login of a user, that lasts as long as the token issued for it.
+#[derive(Debug, Clone)]
+pub struct Session {
+    /// The ID of the session, stored in the token.
+    pub id: String,
+    /// ID of the user.
+    pub user_id: u64,
+    /// ID of the client application the user logged in with.
+    pub app_id: String,
+    /// The user agent of the device the user logged in from, if it was sent.
+    pub user_agent: Option<String>,
+    /// The IP address the user logged in from.
+    pub ip: Option<String>,
+    /// When the user logged in.
+    pub created: DateTime<UTC>,
+    /// When the token of the session was last used.
+    pub last_seen: DateTime<UTC>,
+    /// Whether the user asked to be remembered.
+    pub remember_me: bool,
+    /// When the token of the session expires.
+    pub expiration: DateTime<UTC>,
+}
+
+impl Session {
+    /// Creates the session from the fields of its hash.
+    ///
+    /// Unknown fields are ignored, so that sessions stored by other versions can still be read.
+    fn from_hash(id: String, data: HashMap<String, String>) -> Session {
+        let mut session = Session {
+            id: id,
+            user_id: 0,
+            app_id: String::new(),
+            user_agent: None,
+            ip: None,
+            created: UTC::now(),
+            last_seen: UTC::now(),
+            remember_me: false,
+            expiration: UTC::now(),
+        };
+        for (key, value) in data {
+            match key.as_str() {
+                "user_id" => session.user_id = value.parse().unwrap(),
+                "app_id" => session.app_id = value,
+                "user_agent" => session.user_agent = Some(value),
+                "ip" => session.ip = Some(value),
+                "created" => session.created = timestamp_to_time(&value),
+                "last_seen" => session.last_seen = timestamp_to_time(&value),
+                "remember_me" => session.remember_me = value == "1",
+                "expiration" => session.expiration = timestamp_to_time(&value),
+                _ => {}
+            }
+        }
+        session
+    }
+}
+
+/// Converts a stored UNIX timestamp to a `DateTime`.
+fn timestamp_to_time(timestamp: &str) -> DateTime<UTC> {
+    DateTime::from_utc(NaiveDateTime::from_timestamp(timestamp.parse().unwrap(), 0), UTC)
+}
+
+/// Methods working with the login sessions
+impl Database {
+    /// Stores a new session, that expires with its token
+    pub fn create_session(&self, session: &Session) -> Result<()> {
+        let key = format!("sessions:{}", session.id);
+        let mut data = vec![("user_id", format!("{}", session.user_id)),
+                            ("app_id", session.app_id.clone()),
+                            ("created", format!("{}", session.created.timestamp())),
+                            ("last_seen", format!("{}", session.last_seen.timestamp())),
+                            ("remember_me",
+                             String::from(if session.remember_me { "1" } else { "0" })),
+                            ("expiration", format!("{}", session.expiration.timestamp()))];
+        if let Some(ref user_agent) = session.user_agent {
+            data.push(("user_agent", user_agent.clone()));
+        }
+        if let Some(ref ip) = session.ip {
+            data.push(("ip", ip.clone()));
+        }
+
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.hset_multiple(&key, &data));
+        try!(db.connection.expire_at(&key, session.expiration.timestamp() as usize));
+        try!(db.connection.sadd(format!("users:{}:sessions", session.user_id), &session.id));
+        Ok(())
+    }
+
+    /// Returns the session with the given ID, if it has not expired or been revoked
+    pub fn get_session<S: AsRef<str>>(&self, session_id: S) -> Result<Option<Session>> {
+        let key = format!("sessions:{}", session_id.as_ref());
+        let data: HashMap<String, String> =
+            try!(self.inner.lock().unwrap().connection.hgetall(key));
+        if data.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(Session::from_hash(String::from(session_id.as_ref()), data)))
+        }
+    }
+
+    /// Returns the active sessions of the user, the most recently used first
+    ///
+    /// The IDs of the sessions that have expired are removed from the set of the user.
+    pub fn get_user_sessions(&self, user_id: u64) -> Result<Vec<Session>> {
+        let set_key = format!("users:{}:sessions", user_id);
+        let ids: Vec<String> = try!(self.inner.lock().unwrap().connection.smembers(&set_key));
+        let mut sessions = Vec::with_capacity(ids.len());
+        for id in ids {
+            match try!(self.get_session(&id)) {
+                Some(session) => sessions.push(session),
+                None => try!(self.inner.lock().unwrap().connection.srem(&set_key, &id)),
+            }
+        }
+        sessions.sort_by(|a, b| b.last_seen.cmp(&a.last_seen));
+        Ok(sessions)
+    }
+
+    /// Updates the last seen time of the session, if it was not updated recently
+    pub fn touch_session(&self, session: &Session) -> Result<()> {
+        let now = UTC::now();
+        if (now - session.last_seen).num_seconds() >= SESSION_TOUCH_INTERVAL {
+            let key = format!("sessions:{}", session.id);
+            let script = redis::Script::new(TOUCH_SESSION_SCRIPT);
+            let _: i32 = try!(script.key(key)
+                .arg(now.timestamp())
+                .invoke(&self.inner.lock().unwrap().connection));
+        }
+        Ok(())
+    }
+
+    /// Revokes the given session of the user
+    pub fn revoke_session<S: AsRef<str>>(&self, user_id: u64, session_id: S) -> Result<()> {
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.del(format!("sessions:{}", session_id.as_ref())));
+        try!(db.connection.srem(format!("users:{}:sessions", user_id), session_id.as_ref()));
+        Ok(())
+    }
+
+    /// Revokes all the sessions of the user, except the given one if any
+    ///
+    /// The user tokens issued without a session until now are revoked too, since they cannot be
+    /// told apart from each other.
+    pub fn revoke_all_sessions(&self, user_id: u64, except: Option<&str>) -> Result<()> {
+        let set_key = format!("users:{}:sessions", user_id);
+        let sessionless_key = format!("users:{}:sessionless_revoked", user_id);
+        try!(self.inner.lock().unwrap().connection.set(sessionless_key, to_millis(&UTC::now())));
+        let ids: Vec<String> = try!(self.inner.lock().unwrap().connection.smembers(&set_key));
+        for id in ids {
+            if Some(id.as_str()) != except {
+                try!(self.revoke_session(user_id, &id));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the time up to which the user tokens without a session have been revoked, if any
+    pub fn get_sessionless_revoked_time(&self, user_id: u64) -> Result<Option<DateTime<UTC>>> {
+        let key = format!("users:{}:sessionless_revoked", user_id);
+        let millis: Option<i64> = try!(self.inner.lock().unwrap().connection.get(key));
+        Ok(millis.map(from_millis))
+    }
+}
@@ -4,17 +4,57 @@ use std::collections::HashMap;
 use std::{fmt, u8};
 
 
-use chrono::{DateTime, UTC, NaiveDateTime, NaiveDate};
+use chrono::{DateTime, Duration, UTC, NaiveDateTime, NaiveDate};
 use rand::{thread_rng, Rng};
-use crypto::pbkdf2;
-use otpauth::TOTP;
-use redis::{FromRedisValue, Value, Commands};
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use crypto::util::fixed_time_eq;
+use redis::{self, FromRedisValue, Value, Commands};
 
 use dto::{UserDTO, ProfileDTO};
 use public_utils::Address;
 
 use error::{Error, Result};
-use super::{Database, TOTP_SECRET_LEN};
+use totp::Totp;
+use utils::{to_millis, from_millis};
+use password::PasswordScheme;
+use validation;
+use super::{Database, TOTP_SECRET_LEN, RECOVERY_CODES, MAX_PHONE_CODE_ATTEMPTS};
+
+/// Script that stores the time step of an accepted authenticator code, unless a code from that
+/// time step or a newer one was already accepted. It returns 1 if the time step was stored.
+const SET_LAST_TOTP_STEP_SCRIPT: &'static str = r"
+local last = redis.call('HGET', KEYS[1], 'last_totp_step')
+if last and last ~= '' and tonumber(last) >= tonumber(ARGV[1]) then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'last_totp_step', ARGV[1])
+return 1
+";
+
+/// Script that confirms a pending email change. It returns 0 if the confirmation key was already
+/// used, -1 if the new address is already in use, after dropping the change, or 1 once the user,
+/// the email index and the pending change are updated.
+///
+/// The keys are the confirmation key, the user hash, the cancellation key, the pending change,
+/// the email index and the set of pending changes. The arguments are the ID of the user, the new
+/// address, the old address, and every form the new address may be stored in.
+const CONFIRM_EMAIL_CHANGE_SCRIPT: &'static str = r"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('DEL', KEYS[1], KEYS[3], KEYS[4])
+redis.call('SREM', KEYS[6], ARGV[1])
+for i = 4, #ARGV do
+    if redis.call('HEXISTS', KEYS[5], ARGV[i]) == 1 then
+        return -1
+    end
+end
+redis.call('HMSET', KEYS[2], 'email', ARGV[2], 'email_confirmed', '1')
+redis.call('HDEL', KEYS[5], ARGV[3])
+redis.call('HSET', KEYS[5], ARGV[2], ARGV[1])
+return 1
+";
 
 /// Methods working with user
 impl Database {
@@ -23,7 +63,8 @@ impl Database {
                                              username: S,
                                              password: S,
                                              email: S,
-                                             email_key: S)
+                                             email_key: S,
+                                             scheme: &PasswordScheme)
                                              -> Result<u64> {
 
 
@@ -31,7 +72,7 @@ impl Database {
         let id = try!(self.increment_user_id());
         let key = format!("users:{}", id);
         let verify_email_key = format!("verify_emails:{}", email_key.as_ref());
-        let hash = pbkdf2::pbkdf2_simple(password.as_ref(), 5).unwrap();
+        let hash = try!(scheme.hash(password.as_ref()));
         let name_lowercase = username.as_ref().to_lowercase();
         let email_lowercase = email.as_ref().to_lowercase();
         let authenticator_data =
@@ -48,13 +89,17 @@ impl Database {
                          ("email_confirmed", "0"),
                          ("birthday_confirmed", "0"),
                          ("authenticator_secret", authenticator_data.as_str()),
+                         ("two_factor_enabled", "0"),
+                         ("last_totp_step", ""),
+                         ("totp_settings", ""),
                          ("address_confirmed", "0"),
                          ("phone_confirmed", "0"),
                          ("image_url", ""),
                          ("enabled", "1"),
                          ("registration_time", &format!("{}", UTC::now().timestamp())),
                          ("last_activity", &format!("{}", UTC::now().timestamp())),
-                         ("banned", "")];
+                         ("banned", ""),
+                         ("locked", "")];
 
 
         // TODO: if something fails, delete what has been created before returning Result
@@ -79,7 +124,8 @@ impl Database {
                                       phone: Option<String>,
                                       image: Option<String>,
                                       address: Option<Address>,
-                                      email_key: S)
+                                      email_key: S,
+                                      scheme: &PasswordScheme)
                                       -> Result<u64> {
 
 
@@ -89,7 +135,7 @@ impl Database {
 
         let verify_email_key = format!("verify_emails:{}", email_key.as_ref());
 
-        let hash = pbkdf2::pbkdf2_simple(password.as_ref(), 5).unwrap();
+        let hash = try!(scheme.hash(password.as_ref()));
 
         let name_lowercase = username.as_ref().to_lowercase();
 
@@ -133,13 +179,17 @@ impl Database {
                          ("birthday", dob_data.as_str()),
                          ("birthday_confirmed", "0"),
                          ("address_confirmed", "0"),
+                         ("two_factor_enabled", "0"),
+                         ("last_totp_step", ""),
+                         ("totp_settings", ""),
                          ("phone", phone_data.as_str()),
                          ("phone_confirmed", "0"),
                          ("image_url", image_data.as_str()),
                          ("enabled", "1"),
                          ("registration_time", &format!("{}", UTC::now().timestamp())),
                          ("last_activity", &format!("{}", UTC::now().timestamp())),
-                         ("banned", "")];
+                         ("banned", ""),
+                         ("locked", "")];
 
         // Link the username to the user_id in the 'userkeys' hashSet
 
@@ -171,14 +221,132 @@ impl Database {
         Ok(())
     }
 
-    /// Creates a new authenticator secret, stores it in the database, and returns it
-    fn create_new_user_authenticator_secret(&self, user_id: u64) -> Result<String> {
+    /// Creates a new authenticator secret, stores it in the database with the TOTP settings it
+    /// will be used with, and returns it
+    fn create_new_user_authenticator_secret(&self, user_id: u64, totp: &Totp) -> Result<String> {
         let key = format!("users:{}", user_id);
         let secret = thread_rng().gen_ascii_chars().take(TOTP_SECRET_LEN).collect::<String>();
-        try!(self.inner.lock().unwrap().connection.hset(key, "authenticator_secret", &secret));
+        let settings = totp.to_settings();
+        let data = [("authenticator_secret", secret.as_str()),
+                    ("last_totp_step", ""),
+                    ("totp_settings", settings.as_str())];
+        try!(self.inner.lock().unwrap().connection.hset_multiple(key, &data));
         Ok(secret)
     }
 
+    /// Sets the last time step in which the user used an authenticator code, if it is newer than
+    /// the stored one, and returns whether it was set
+    ///
+    /// The check and the update run in a single script, so that concurrent requests cannot
+    /// accept the same code twice.
+    fn set_last_totp_step(&self, user_id: u64, time_step: u64) -> Result<bool> {
+        let key = format!("users:{}", user_id);
+        let script = redis::Script::new(SET_LAST_TOTP_STEP_SCRIPT);
+        Ok(try!(script.key(key).arg(time_step).invoke(&self.inner.lock().unwrap().connection)))
+    }
+
+    /// Enables or disables two factor authentication for the user
+    fn set_two_factor_enabled(&self, user_id: u64, enabled: bool) -> Result<()> {
+        let key = format!("users:{}", user_id);
+        try!(self.inner
+            .lock()
+            .unwrap()
+            .connection
+            .hset(key, "two_factor_enabled", if enabled { "1" } else { "0" }));
+        Ok(())
+    }
+
+    /// Replaces the recovery codes of the user with the given hashed codes
+    fn set_recovery_codes(&self, user_id: u64, hashes: &[String]) -> Result<()> {
+        let key = format!("users:{}:recovery_codes", user_id);
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.del(&key));
+        try!(db.connection.sadd(&key, hashes));
+        Ok(())
+    }
+
+    /// Removes the given hashed recovery code, returning whether the user had it
+    fn use_recovery_code<S: AsRef<str>>(&self, user_id: u64, hash: S) -> Result<bool> {
+        let key = format!("users:{}:recovery_codes", user_id);
+        let removed: u8 = try!(self.inner.lock().unwrap().connection.srem(key, hash.as_ref()));
+        Ok(removed == 1)
+    }
+
+    /// Returns the number of recovery codes the user has left
+    fn get_recovery_codes_left(&self, user_id: u64) -> Result<u64> {
+        let key = format!("users:{}:recovery_codes", user_id);
+        Ok(try!(self.inner.lock().unwrap().connection.scard(key)))
+    }
+
+    /// Deletes all the recovery codes of the user
+    fn delete_recovery_codes(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}:recovery_codes", user_id);
+        Ok(try!(self.inner.lock().unwrap().connection.del(key)))
+    }
+
+    /// Stores a login that still needs the second factor, for the given amount of seconds
+    pub fn create_mfa_pending_login<S: AsRef<str>>(&self,
+                                                   mfa_key: S,
+                                                   pending: &MfaPendingLogin,
+                                                   seconds: usize)
+                                                   -> Result<()> {
+        let key = format!("mfa_pending:{}", mfa_key.as_ref());
+        let data = [("user_id", format!("{}", pending.user_id)),
+                    ("app_id", pending.app_id.clone()),
+                    ("remember_me", String::from(if pending.remember_me { "1" } else { "0" }))];
+        let user_key = format!("users:{}:mfa_pending", pending.user_id);
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.hset_multiple(&key, &data));
+        try!(db.connection.expire(&key, seconds));
+        try!(db.connection.sadd(&user_key, mfa_key.as_ref()));
+        try!(db.connection.expire(&user_key, seconds));
+        Ok(())
+    }
+
+    /// Returns the pending login for the given key, if it exists and has not expired
+    pub fn get_mfa_pending_login<S: AsRef<str>>(&self,
+                                                mfa_key: S)
+                                                -> Result<Option<MfaPendingLogin>> {
+        let key = format!("mfa_pending:{}", mfa_key.as_ref());
+        let data: HashMap<String, String> =
+            try!(self.inner.lock().unwrap().connection.hgetall(key));
+        if data.len() == 0 {
+            return Ok(None);
+        }
+        let mut pending = MfaPendingLogin {
+            user_id: 0,
+            app_id: String::new(),
+            remember_me: false,
+        };
+        for (key, value) in data {
+            match key.as_str() {
+                "user_id" => pending.user_id = value.parse().unwrap(),
+                "app_id" => pending.app_id = value,
+                "remember_me" => pending.remember_me = value == "1",
+                _ => unreachable!(),
+            }
+        }
+        Ok(Some(pending))
+    }
+
+    /// Deletes the pending login once the second factor has been verified
+    pub fn delete_mfa_pending_login<S: AsRef<str>>(&self, mfa_key: S) -> Result<()> {
+        let key = format!("mfa_pending:{}", mfa_key.as_ref());
+        Ok(try!(self.inner.lock().unwrap().connection.del(key)))
+    }
+
+    /// Deletes all the logins of the user that are waiting for the second factor
+    fn delete_mfa_pending_logins(&self, user_id: u64) -> Result<()> {
+        let user_key = format!("users:{}:mfa_pending", user_id);
+        let db = self.inner.lock().unwrap();
+        let keys: Vec<String> = try!(db.connection.smembers(&user_key));
+        for key in keys {
+            try!(db.connection.del(format!("mfa_pending:{}", key)));
+        }
+        try!(db.connection.del(&user_key));
+        Ok(())
+    }
+
     /// Sets the users last activity time
     fn set_last_activity_time(&self, user_id: u64) -> Result<()> {
         let key = format!("users:{}", user_id);
@@ -191,24 +359,145 @@ impl Database {
     }
 
     /// Bannes the user until the provided date
+    ///
+    /// The account is not disabled, so that the ban is lifted when it expires.
     fn ban_user(&self, user_id: u64, until: DateTime<UTC>) -> Result<()> {
         let key = format!("users:{}", user_id);
-        let data = [("enabled", format!("{}", 0)), ("banned", format!("{}", until.timestamp()))];
-        try!(self.inner.lock().unwrap().connection.hset_multiple(key, &data));
+        try!(self.inner.lock().unwrap().connection.hset(key, "banned", until.timestamp()));
         Ok(())
     }
 
-    /// Starts to reset the users password
-    fn start_reset_password<S: AsRef<str>>(&self, user_id: u64, password_key: S) -> Result<()> {
+    /// Lifts the ban of the user
+    fn unban_user(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}", user_id);
+        try!(self.inner.lock().unwrap().connection.hset(key, "banned", ""));
+        Ok(())
+    }
+
+    /// Registers a failed login or authenticator attempt and returns the current failure count
+    ///
+    /// The counter expires after `window`, so only consecutive failures within that time count.
+    fn increment_failed_logins(&self, user_id: u64, window: Duration) -> Result<u32> {
+        let key = format!("users:{}:failed_logins", user_id);
+        let db = self.inner.lock().unwrap();
+        let count: u32 = try!(db.connection.incr(&key, 1));
+        try!(db.connection.expire(&key, window.num_seconds() as usize));
+        Ok(count)
+    }
+
+    /// Resets the failed login counter of the user
+    fn reset_failed_logins(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}:failed_logins", user_id);
+        Ok(try!(self.inner.lock().unwrap().connection.del(key)))
+    }
+
+    /// Locks the user until the provided date
+    fn lock_user(&self, user_id: u64, until: DateTime<UTC>) -> Result<()> {
+        let key = format!("users:{}", user_id);
+        try!(self.inner.lock().unwrap().connection.hset(key, "locked", until.timestamp()));
+        Ok(())
+    }
+
+    /// Removes the lock of the user and resets its failed login counter
+    fn unlock_user(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}", user_id);
+        try!(self.inner.lock().unwrap().connection.hset(key, "locked", ""));
+        self.reset_failed_logins(user_id)
+    }
+
+    /// Starts to reset the users password, invalidating the previous reset key of the user
+    fn start_reset_password<S: AsRef<str>>(&self,
+                                           user_id: u64,
+                                           password_key: S,
+                                           ttl: Duration)
+                                           -> Result<()> {
+        let user_key = format!("users:{}:reset_password", user_id);
         let reset_password_key = format!("reset_passwords:{}", password_key.as_ref());
-        try!(self.inner
-            .lock()
-            .unwrap()
-            .connection
-            .set_ex(reset_password_key, user_id, 60 * 60 * 24));
+        let seconds = ttl.num_seconds() as usize;
+
+        let db = self.inner.lock().unwrap();
+        let old_key: Option<String> = try!(db.connection.get(&user_key));
+        if let Some(old_key) = old_key {
+            try!(db.connection.del(format!("reset_passwords:{}", old_key)));
+        }
+        try!(db.connection.set_ex(reset_password_key, user_id, seconds));
+        try!(db.connection.set_ex(user_key, password_key.as_ref(), seconds));
         Ok(())
     }
 
+    /// Stores a one time login key for the user, replacing its previous one
+    fn start_magic_login<S: AsRef<str>>(&self,
+                                        login_key: S,
+                                        login: &MagicLogin,
+                                        ttl: Duration)
+                                        -> Result<()> {
+        let user_key = format!("users:{}:magic_login", login.user_id);
+        let key = format!("magic_logins:{}", login_key.as_ref());
+        let data = [("user_id", format!("{}", login.user_id)),
+                    ("app_id", login.app_id.clone()),
+                    ("remember_me", String::from(if login.remember_me { "1" } else { "0" }))];
+        let seconds = ttl.num_seconds() as usize;
+
+        let db = self.inner.lock().unwrap();
+        let old_key: Option<String> = try!(db.connection.get(&user_key));
+        if let Some(old_key) = old_key {
+            try!(db.connection.del(format!("magic_logins:{}", old_key)));
+        }
+        try!(db.connection.hset_multiple(&key, &data));
+        try!(db.connection.expire(&key, seconds));
+        try!(db.connection.set_ex(user_key, login_key.as_ref(), seconds));
+        Ok(())
+    }
+
+    /// Redeems the one time login key, and returns the login it was issued for
+    ///
+    /// The key can only be used once, so `None` is returned if it does not exist, has expired or
+    /// has already been used.
+    pub fn use_magic_login<S: AsRef<str>>(&self, login_key: S) -> Result<Option<MagicLogin>> {
+        let key = format!("magic_logins:{}", login_key.as_ref());
+        let db = self.inner.lock().unwrap();
+        let data: HashMap<String, String> = try!(db.connection.hgetall(&key));
+        // Only the request that deletes the key can use it.
+        let deleted: u64 = try!(db.connection.del(&key));
+        if data.is_empty() || deleted == 0 {
+            return Ok(None);
+        }
+        let mut login = MagicLogin {
+            user_id: 0,
+            app_id: String::new(),
+            remember_me: false,
+        };
+        for (key, value) in data {
+            match key.as_str() {
+                "user_id" => login.user_id = value.parse().unwrap(),
+                "app_id" => login.app_id = value,
+                "remember_me" => login.remember_me = value == "1",
+                _ => unreachable!(),
+            }
+        }
+        try!(db.connection.del(format!("users:{}:magic_login", login.user_id)));
+        Ok(Some(login))
+    }
+
+    /// Revokes all the tokens issued to the user until now, their sessions and the logins still
+    /// waiting for the second factor
+    ///
+    /// The revocation time is stored in milliseconds, so that tokens issued right after it, in
+    /// the same second, stay valid.
+    fn revoke_user_sessions(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}:sessions_revoked", user_id);
+        try!(self.inner.lock().unwrap().connection.set(key, to_millis(&UTC::now())));
+        try!(self.delete_mfa_pending_logins(user_id));
+        self.revoke_all_sessions(user_id, None)
+    }
+
+    /// Returns the time up to which the tokens of the user have been revoked, if any
+    pub fn get_sessions_revoked_time(&self, user_id: u64) -> Result<Option<DateTime<UTC>>> {
+        let key = format!("users:{}:sessions_revoked", user_id);
+        let millis: Option<i64> = try!(self.inner.lock().unwrap().connection.get(key));
+        Ok(millis.map(from_millis))
+    }
+
     /// Start confirm email address
     pub fn start_confirm_email<S: AsRef<str>>(&self, user_id: u64, email_key: S) -> Result<()> {
         let verify_email_key = format!("verify_emails:{}", email_key.as_ref());
@@ -220,24 +509,283 @@ impl Database {
         Ok(())
     }
 
-    /// Confirms the password reset
-    pub fn confirm_password_reset<S: AsRef<str>>(&self,
-                                                 password_key: S,
-                                                 new_password: S)
-                                                 -> Result<()> {
-        let reset_password_key = format!("reset_passwords:{}", password_key.as_ref());
-        let id_opt: Option<u64> =
-            try!(self.inner.lock().unwrap().connection.get(&reset_password_key));
+    /// Stages a change of the users email until the new address is confirmed
+    ///
+    /// It replaces any previous pending change of the user.
+    fn start_email_change<S: AsRef<str>>(&self,
+                                         user_id: u64,
+                                         new_email: S,
+                                         confirm_key: S,
+                                         cancel_key: S,
+                                         ttl: Duration)
+                                         -> Result<()> {
+        try!(self.delete_email_change(user_id));
+
+        let key = format!("users:{}:pending_email", user_id);
+        let seconds = ttl.num_seconds() as usize;
+        let data = [("email", new_email.as_ref().to_lowercase()),
+                    ("confirm_key", String::from(confirm_key.as_ref())),
+                    ("cancel_key", String::from(cancel_key.as_ref())),
+                    ("requested", format!("{}", UTC::now().timestamp()))];
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.hset_multiple(&key, &data));
+        try!(db.connection.expire(&key, seconds));
+        try!(db.connection
+            .set_ex(format!("email_changes:{}", confirm_key.as_ref()), user_id, seconds));
+        try!(db.connection
+            .set_ex(format!("email_change_cancels:{}", cancel_key.as_ref()), user_id, seconds));
+        try!(db.connection.sadd("pending_email_changes", user_id));
+        Ok(())
+    }
+
+    /// Returns the pending email change of the user, if it exists and has not expired
+    pub fn get_pending_email_change(&self, user_id: u64) -> Result<Option<PendingEmailChange>> {
+        let key = format!("users:{}:pending_email", user_id);
+        let db = self.inner.lock().unwrap();
+        let data: HashMap<String, String> = try!(db.connection.hgetall(key));
+        if data.len() == 0 {
+            try!(db.connection.srem("pending_email_changes", user_id));
+            return Ok(None);
+        }
+        let mut pending = PendingEmailChange {
+            user_id: user_id,
+            new_email: String::new(),
+            confirm_key: String::new(),
+            cancel_key: String::new(),
+            requested: UTC::now(),
+        };
+        for (key, value) in data {
+            match key.as_str() {
+                "email" => pending.new_email = value,
+                "confirm_key" => pending.confirm_key = value,
+                "cancel_key" => pending.cancel_key = value,
+                "requested" => {
+                    pending.requested =
+                        DateTime::from_utc(NaiveDateTime::from_timestamp(value.parse().unwrap(),
+                                                                         0),
+                                           UTC)
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(Some(pending))
+    }
+
+    /// Returns the pending email changes of all the users
+    pub fn get_pending_email_changes(&self) -> Result<Vec<PendingEmailChange>> {
+        let ids: Vec<u64> =
+            try!(self.inner.lock().unwrap().connection.smembers("pending_email_changes"));
+        let mut changes = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(pending) = try!(self.get_pending_email_change(id)) {
+                changes.push(pending);
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Deletes the pending email change of the user, if any, along with its keys
+    fn delete_email_change(&self, user_id: u64) -> Result<()> {
+        if let Some(pending) = try!(self.get_pending_email_change(user_id)) {
+            let db = self.inner.lock().unwrap();
+            try!(db.connection.del(format!("email_changes:{}", pending.confirm_key)));
+            try!(db.connection.del(format!("email_change_cancels:{}", pending.cancel_key)));
+            try!(db.connection.del(format!("users:{}:pending_email", user_id)));
+            try!(db.connection.srem("pending_email_changes", user_id));
+        }
+        Ok(())
+    }
+
+    /// Confirms the pending email change with the key sent to the new address, and returns the
+    /// updated user and its previous email
+    ///
+    /// The key, the availability of the new address, the user, the email index and the pending
+    /// change are checked and updated in a single script, so that a key can only be used once and
+    /// two users cannot confirm the same address at the same time. If the address was taken since
+    /// the change was requested, the pending change is dropped.
+    pub fn confirm_email_change<S: AsRef<str>>(&self, confirm_key: S) -> Result<(User, String)> {
+        let confirm_key = format!("email_changes:{}", confirm_key.as_ref());
+        let id_opt: Option<u64> = try!(self.inner.lock().unwrap().connection.get(&confirm_key));
+        let id = match id_opt {
+            Some(id) => id,
+            None => return Err(Error::IncorrectKey),
+        };
+        let pending = match try!(self.get_pending_email_change(id)) {
+            Some(pending) => pending,
+            None => return Err(Error::IncorrectKey),
+        };
+        let user = match try!(self.get_user_by_id(id)) {
+            Some(user) => user,
+            None => return Err(Error::UserDoesNotExist),
+        };
+
+        let result: i64 = {
+            let script = redis::Script::new(CONFIRM_EMAIL_CHANGE_SCRIPT);
+            let mut invocation = script.key(&confirm_key);
+            let _ = invocation.key(format!("users:{}", id))
+                .key(format!("email_change_cancels:{}", pending.cancel_key))
+                .key(format!("users:{}:pending_email", id))
+                .key("emailkeys")
+                .key("pending_email_changes")
+                .arg(id)
+                .arg(&pending.new_email)
+                .arg(user.get_email().to_lowercase());
+            for variant in validation::email_variants(&pending.new_email) {
+                let _ = invocation.arg(variant);
+            }
+            try!(invocation.invoke(&self.inner.lock().unwrap().connection))
+        };
+        match result {
+            1 => {}
+            0 => return Err(Error::IncorrectKey),
+            _ => return Err(Error::EmailExists),
+        }
+
+        match try!(self.get_user_by_id(id)) {
+            Some(updated) => Ok((updated, String::from(user.get_email()))),
+            None => Err(Error::UserDoesNotExist),
+        }
+    }
+
+    /// Cancels the pending email change with the key sent to the old address, and returns the ID
+    /// of the user
+    pub fn cancel_email_change<S: AsRef<str>>(&self, cancel_key: S) -> Result<u64> {
+        let cancel_key = format!("email_change_cancels:{}", cancel_key.as_ref());
+        let id_opt: Option<u64> = try!(self.inner.lock().unwrap().connection.get(&cancel_key));
         match id_opt {
             Some(id) => {
-                let mut user = try!(self.get_user_by_id(id)).unwrap();
-                try!(self.inner.lock().unwrap().connection.del(reset_password_key));
-                user.set_password(new_password.as_ref())
+                try!(self.delete_email_change(id));
+                Ok(id)
             }
             None => Err(Error::IncorrectKey),
         }
     }
 
+    /// Schedules the user to be purged at the given time, unless the deletion is cancelled with
+    /// the key before, and disables the user in the meantime
+    ///
+    /// It replaces any previous scheduled deletion of the user. If the user was enabled, it is
+    /// marked as disabled by the deletion, so that cancelling the deletion enables it again.
+    fn start_account_deletion<S: AsRef<str>>(&self,
+                                             user_id: u64,
+                                             cancel_key: S,
+                                             purge_at: DateTime<UTC>,
+                                             enabled: bool)
+                                             -> Result<()> {
+        try!(self.delete_account_deletion(user_id));
+
+        let cancel = format!("account_deletion_cancels:{}", cancel_key.as_ref());
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.set(format!("users:{}:deletion", user_id), cancel_key.as_ref()));
+        try!(db.connection.set(&cancel, user_id));
+        try!(db.connection.expire_at(&cancel, purge_at.timestamp() as usize));
+        try!(db.connection.zadd("pending_deletions", user_id, purge_at.timestamp()));
+        if enabled {
+            try!(db.connection.set(format!("users:{}:disabled_by_deletion", user_id), 1));
+        }
+        try!(db.connection.hset(format!("users:{}", user_id), "enabled", "0"));
+        Ok(())
+    }
+
+    /// Returns the IDs of the users whose deletion grace period ended before the given time
+    pub fn get_due_account_deletions(&self, now: DateTime<UTC>) -> Result<Vec<u64>> {
+        Ok(try!(self.inner
+            .lock()
+            .unwrap()
+            .connection
+            .zrangebyscore("pending_deletions", "-inf", now.timestamp())))
+    }
+
+    /// Deletes the scheduled deletion of the user, if any, along with its keys
+    pub fn delete_account_deletion(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}:deletion", user_id);
+        let db = self.inner.lock().unwrap();
+        let cancel_key: Option<String> = try!(db.connection.get(&key));
+        if let Some(cancel_key) = cancel_key {
+            try!(db.connection.del(format!("account_deletion_cancels:{}", cancel_key)));
+        }
+        try!(db.connection.del(&key));
+        try!(db.connection.zrem("pending_deletions", user_id));
+        Ok(())
+    }
+
+    /// Cancels the scheduled deletion with the key sent to the user and returns its ID
+    ///
+    /// The user is only enabled again if it was disabled by the deletion, and not by an admin
+    /// since. The key can only be used once.
+    pub fn cancel_account_deletion<S: AsRef<str>>(&self, cancel_key: S) -> Result<u64> {
+        let cancel_key = format!("account_deletion_cancels:{}", cancel_key.as_ref());
+        let id = {
+            let db = self.inner.lock().unwrap();
+            let id_opt: Option<u64> = try!(db.connection.get(&cancel_key));
+            let id = match id_opt {
+                Some(id) => id,
+                None => return Err(Error::IncorrectKey),
+            };
+            // Only the request that deletes the key can use it.
+            let deleted: u64 = try!(db.connection.del(&cancel_key));
+            if deleted == 0 {
+                return Err(Error::IncorrectKey);
+            }
+            id
+        };
+        try!(self.delete_account_deletion(id));
+        let disabled_by_deletion: u64 = try!(self.inner
+            .lock()
+            .unwrap()
+            .connection
+            .del(format!("users:{}:disabled_by_deletion", id)));
+        if disabled_by_deletion > 0 {
+            try!(self.enable_user(id));
+        }
+        Ok(id)
+    }
+
+    /// Returns the user the given password reset key belongs to
+    pub fn get_password_reset_user<S: AsRef<str>>(&self, password_key: S) -> Result<Option<User>> {
+        let reset_password_key = format!("reset_passwords:{}", password_key.as_ref());
+        let id_opt: Option<u64> =
+            try!(self.inner.lock().unwrap().connection.get(&reset_password_key));
+        match id_opt {
+            Some(id) => self.get_user_by_id(id),
+            None => Ok(None),
+        }
+    }
+
+    /// Confirms the password reset, and returns the user whose password was changed
+    ///
+    /// The key can only be used once, and all the tokens of the user are revoked.
+    pub fn confirm_password_reset<S: AsRef<str>>(&self,
+                                                 password_key: S,
+                                                 new_password: S,
+                                                 scheme: &PasswordScheme)
+                                                 -> Result<User> {
+        let reset_password_key = format!("reset_passwords:{}", password_key.as_ref());
+        let id = {
+            let db = self.inner.lock().unwrap();
+            let id_opt: Option<u64> = try!(db.connection.get(&reset_password_key));
+            let id = match id_opt {
+                Some(id) => id,
+                None => return Err(Error::IncorrectKey),
+            };
+            // Only the request that deletes the key can use it.
+            let deleted: u64 = try!(db.connection.del(&reset_password_key));
+            if deleted == 0 {
+                return Err(Error::IncorrectKey);
+            }
+            try!(db.connection.del(format!("users:{}:reset_password", id)));
+            id
+        };
+
+        let mut user = match try!(self.get_user_by_id(id)) {
+            Some(user) => user,
+            None => return Err(Error::UserDoesNotExist),
+        };
+        try!(user.set_password(scheme, new_password.as_ref()));
+        try!(self.revoke_user_sessions(id));
+        Ok(user)
+    }
+
     /// trys to confirms the users email
     pub fn try_confirm_email<S: AsRef<str>>(&self, email_key: S) -> Result<()> {
         let verify_email_key = format!("verify_emails:{}", email_key.as_ref());
@@ -264,16 +812,63 @@ impl Database {
     }
 
     /// Enables the given user
+    ///
+    /// Any mark of the user being disabled by a pending deletion is removed.
     fn enable_user(&self, user_id: u64) -> Result<()> {
         let key = format!("users:{}", user_id);
-        try!(self.inner.lock().unwrap().connection.hset(key, "enabled", "1"));
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.hset(key, "enabled", "1"));
+        try!(db.connection.del(format!("users:{}:disabled_by_deletion", user_id)));
         Ok(())
     }
 
     /// Disables the given user
+    ///
+    /// Any mark of the user being disabled by a pending deletion is removed, so that cancelling
+    /// the deletion does not enable it again.
     fn disable_user(&self, user_id: u64) -> Result<()> {
         let key = format!("users:{}", user_id);
-        try!(self.inner.lock().unwrap().connection.hset(key, "enabled", "0"));
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.hset(key, "enabled", "0"));
+        try!(db.connection.del(format!("users:{}:disabled_by_deletion", user_id)));
+        Ok(())
+    }
+
+    /// Returns which fields of the profile of the user are shown publicly
+    ///
+    /// If a stored field is not known, the whole profile is hidden, since it cannot be told what
+    /// the user chose to show.
+    fn get_profile_visibility(&self, user_id: u64) -> Result<ProfileVisibility> {
+        let key = format!("users:{}:profile_visibility", user_id);
+        let data: HashMap<String, String> =
+            try!(self.inner.lock().unwrap().connection.hgetall(key));
+        let mut visibility = ProfileVisibility::default();
+        for (key, value) in data {
+            let visible = value == "1";
+            match key.as_str() {
+                "hidden" => visibility.hidden = visible,
+                "first_name" => visibility.first_name = visible,
+                "last_name" => visibility.last_name = visible,
+                "image" => visibility.image = visible,
+                "age" => visibility.age = visible,
+                "address" => visibility.address = visible,
+                _ => return Ok(ProfileVisibility::hidden()),
+            }
+        }
+        Ok(visibility)
+    }
+
+    /// Sets which fields of the profile of the user are shown publicly
+    fn set_profile_visibility(&self, user_id: u64, visibility: &ProfileVisibility) -> Result<()> {
+        let key = format!("users:{}:profile_visibility", user_id);
+        let flag = |visible: bool| if visible { "1" } else { "0" };
+        let data = [("hidden", flag(visibility.hidden)),
+                    ("first_name", flag(visibility.first_name)),
+                    ("last_name", flag(visibility.last_name)),
+                    ("image", flag(visibility.image)),
+                    ("age", flag(visibility.age)),
+                    ("address", flag(visibility.address))];
+        try!(self.inner.lock().unwrap().connection.hset_multiple(key, &data));
         Ok(())
     }
 
@@ -322,6 +917,27 @@ impl Database {
         Ok(())
     }
 
+    /// Sets the version of the uploaded avatar of the user, and returns the previous one
+    fn set_user_avatar<S: AsRef<str>>(&self,
+                                      user_id: u64,
+                                      version: Option<S>)
+                                      -> Result<Option<String>> {
+        let key = format!("users:{}:avatar", user_id);
+        let db = self.inner.lock().unwrap();
+        let old_version: Option<String> = try!(db.connection.get(&key));
+        match version {
+            Some(version) => try!(db.connection.set(&key, version.as_ref())),
+            None => try!(db.connection.del(&key)),
+        }
+        Ok(old_version)
+    }
+
+    /// Returns the version of the uploaded avatar of the user, if it has one
+    pub fn get_user_avatar(&self, user_id: u64) -> Result<Option<String>> {
+        let key = format!("users:{}:avatar", user_id);
+        Ok(try!(self.inner.lock().unwrap().connection.get(key)))
+    }
+
     /// Sets the users birthday
     fn set_user_birthday(&self, user_id: u64, date: Option<NaiveDate>) -> Result<()> {
         let key = format!("users:{}", user_id);
@@ -361,6 +977,71 @@ impl Database {
         Ok(())
     }
 
+    /// Counts a verification code sent to the phone number, and returns the number of codes sent
+    /// to it in the current window
+    pub fn increment_sms_sends<S: AsRef<str>>(&self, phone: S, window: Duration) -> Result<u32> {
+        let key = format!("sms_sends:{}", phone.as_ref());
+        let db = self.inner.lock().unwrap();
+        let count: u32 = try!(db.connection.incr(&key, 1));
+        if count == 1 {
+            try!(db.connection.expire(&key, window.num_seconds() as usize));
+        }
+        Ok(count)
+    }
+
+    /// Stores the verification code sent to the users phone, replacing any previous one
+    ///
+    /// The code is stored as is: a short numeric code would be found from its hash right away,
+    /// so it is only protected by expiring with the key and by the limited attempts.
+    fn start_phone_verification<S: AsRef<str>>(&self,
+                                               user_id: u64,
+                                               phone: S,
+                                               code: S,
+                                               ttl: Duration)
+                                               -> Result<()> {
+        let key = format!("users:{}:phone_verification", user_id);
+        let data = [("phone", String::from(phone.as_ref())),
+                    ("code", String::from(code.as_ref().trim())),
+                    ("attempts", String::from("0"))];
+        let db = self.inner.lock().unwrap();
+        try!(db.connection.del(&key));
+        try!(db.connection.hset_multiple(&key, &data));
+        try!(db.connection.expire(&key, ttl.num_seconds() as usize));
+        Ok(())
+    }
+
+    /// Checks the verification code sent to the users phone, and confirms the phone if it is
+    /// correct
+    ///
+    /// The code must have been sent to the given phone, so that changing the phone number
+    /// invalidates it. After `MAX_PHONE_CODE_ATTEMPTS` wrong codes a new one must be requested.
+    fn check_phone_verification<S: AsRef<str>>(&self,
+                                               user_id: u64,
+                                               phone: S,
+                                               code: S)
+                                               -> Result<bool> {
+        let key = format!("users:{}:phone_verification", user_id);
+        let db = self.inner.lock().unwrap();
+        let data: HashMap<String, String> = try!(db.connection.hgetall(&key));
+        match (data.get("phone"), data.get("code")) {
+            (Some(p), Some(c)) if p == phone.as_ref() => {
+                if fixed_time_eq(c.as_bytes(), code.as_ref().trim().as_bytes()) {
+                    try!(db.connection.del(&key));
+                    try!(db.connection
+                        .hset(format!("users:{}", user_id), "phone_confirmed", "1"));
+                    Ok(true)
+                } else {
+                    let attempts: u32 = try!(db.connection.hincr(&key, "attempts", 1));
+                    if attempts >= MAX_PHONE_CODE_ATTEMPTS {
+                        try!(db.connection.del(&key));
+                    }
+                    Ok(false)
+                }
+            }
+            _ => Ok(false),
+        }
+    }
+
     /// Sets the users email in the database
     fn set_user_email<S: AsRef<str>>(&self,
                                      user_id: u64,
@@ -399,18 +1080,67 @@ impl Database {
     }
 
     /// Deletes a user from the database
+    ///
+    /// Every key of the user is deleted, along with the keys pointing to it, such as its email
+    /// and the reset password, login and cancellation keys issued to it. The audit log of the user
+    /// is kept, and the documents attached to its verification requests must be deleted from the
+    /// blob store by the caller, as well as its avatar. Its data export, if any, is deleted from
+    /// the blob store once it expires.
     fn delete_user(&self, user: &User) -> Result<()> {
+        try!(self.delete_email_change(user.get_id()));
+        try!(self.delete_account_deletion(user.get_id()));
+        try!(self.delete_user_verifications(user.get_id()));
+        try!(self.delete_user_data_export(user.get_id()));
+        try!(self.delete_mfa_pending_logins(user.get_id()));
+        try!(self.revoke_all_sessions(user.get_id(), None));
         let key = format!("users:{}", user.get_id());
         let sign_key = format!("users:{}:sign_keys", user.get_id());
         let enc_key = format!("users:{}:enc_keys", user.get_id());
         let addr_key = format!("users:{}:addr", user.get_id());
+        let failed_logins_key = format!("users:{}:failed_logins", user.get_id());
+        let recovery_codes_key = format!("users:{}:recovery_codes", user.get_id());
+        let reset_password_key = format!("users:{}:reset_password", user.get_id());
+        let sessions_revoked_key = format!("users:{}:sessions_revoked", user.get_id());
+        let sessionless_revoked_key = format!("users:{}:sessionless_revoked", user.get_id());
+        let disabled_by_deletion_key = format!("users:{}:disabled_by_deletion", user.get_id());
+        let phone_verification_key = format!("users:{}:phone_verification", user.get_id());
+        let login_history_key = format!("users:{}:login_history", user.get_id());
+        let known_devices_key = format!("users:{}:known_devices", user.get_id());
+        let known_ips_key = format!("users:{}:known_ips", user.get_id());
+        let magic_login_key = format!("users:{}:magic_login", user.get_id());
+        let profile_visibility_key = format!("users:{}:profile_visibility", user.get_id());
+        let avatar_key = format!("users:{}:avatar", user.get_id());
+        let roles_key = format!("users:{}:roles", user.get_id());
         // let wallet_key = format!("users:{}:wallet_addresses", user.get_id());
         {
             let db = self.inner.lock().unwrap();
+            let reset_password: Option<String> = try!(db.connection.get(&reset_password_key));
+            if let Some(reset_password) = reset_password {
+                try!(db.connection.del(format!("reset_passwords:{}", reset_password)));
+            }
+            let magic_login: Option<String> = try!(db.connection.get(&magic_login_key));
+            if let Some(magic_login) = magic_login {
+                try!(db.connection.del(format!("magic_logins:{}", magic_login)));
+            }
             try!(db.connection.del(key));
             try!(db.connection.del(sign_key));
             try!(db.connection.del(enc_key));
             try!(db.connection.del(addr_key));
+            try!(db.connection.del(failed_logins_key));
+            try!(db.connection.del(recovery_codes_key));
+            try!(db.connection.del(reset_password_key));
+            try!(db.connection.del(sessions_revoked_key));
+            try!(db.connection.del(sessionless_revoked_key));
+            try!(db.connection.del(disabled_by_deletion_key));
+            try!(db.connection.del(phone_verification_key));
+            try!(db.connection.del(login_history_key));
+            try!(db.connection.del(known_devices_key));
+            try!(db.connection.del(known_ips_key));
+            try!(db.connection.del(magic_login_key));
+            try!(db.connection.del(profile_visibility_key));
+            try!(db.connection.del(avatar_key));
+            try!(db.connection.del(roles_key));
+            try!(db.connection.hdel("emailkeys", user.get_email().to_lowercase()));
         }
 
 
@@ -436,24 +1166,29 @@ impl Database {
             .hexists("userkeys", username.as_ref().to_lowercase())))
     }
 
-    /// Checks if a user with a given username already exists
+    /// Checks if a user with a given email already exists
+    ///
+    /// Every form the address may be stored in is checked, see `validation::email_variants()`.
     pub fn check_email_exists<S: AsRef<str>>(&self, email: S) -> Result<bool> {
-        Ok(try!(self.inner
-            .lock()
-            .unwrap()
-            .connection
-            .hexists("emailkeys", email.as_ref().to_lowercase())))
+        let db = self.inner.lock().unwrap();
+        for variant in validation::email_variants(email) {
+            if try!(db.connection.hexists("emailkeys", variant)) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
     }
 
     /// Returns the user based on email
+    ///
+    /// Every form the address may be stored in is looked up, see `validation::email_variants()`.
     pub fn get_user_by_email<S: AsRef<str>>(&self, email: S) -> Result<Option<User>> {
-        if !email.as_ref().contains('@') {
-            return Ok(None);
+        for variant in validation::email_variants(email) {
+            if let Some(id) = try!(self.get_user_id_by_email(variant)) {
+                return self.get_user_by_id(id);
+            }
         }
-        Ok(match try!(self.get_user_id_by_email(email)) {
-            Some(id) => try!(self.get_user_by_id(id)),
-            None => None,
-        })
+        Ok(None)
     }
 
     /// Returns the user id based on the email
@@ -499,15 +1234,20 @@ impl Database {
     }
 
     /// Returns a user by ID
+    ///
+    /// A `users:{id}` hash without a username, such as one left by writing a field of a user
+    /// that does not exist, is not a user, so `None` is returned for it.
     pub fn get_user_by_id(&self, id: u64) -> Result<Option<User>> {
         let key = format!("users:{}", id);
         let addr_key = format!("users:{}:addr", id);
 
-        let (data, addr_data): (_, _) = {
+        let (data, addr_data): (HashMap<String, Value>, _) = {
             let db = self.inner.lock().unwrap();
             (try!(db.connection.hgetall(key)), try!(db.connection.hgetall(addr_key)))
         };
-
+        if !data.contains_key("username") {
+            return Ok(None);
+        }
 
         Ok(Some(try!(User::from_db_data(self.clone(), id, data, addr_data))))
     }
@@ -542,6 +1282,12 @@ pub struct User {
     last_name: Option<(String, bool)>,
     /// The authenticator secret
     authenticator_secret: String,
+    /// Whether the user has enrolled in two factor authentication
+    two_factor_enabled: bool,
+    /// The time step of the last accepted authenticator code
+    last_totp_step: Option<u64>,
+    /// The TOTP settings the authenticator secret was enrolled with
+    totp: Option<Totp>,
     /// the users date of birth
     birthday: Option<(NaiveDate, bool)>,
     /// the user's phone #
@@ -558,6 +1304,8 @@ pub struct User {
     last_activity: DateTime<UTC>,
     /// Whether the user is banned
     banned: Option<DateTime<UTC>>,
+    /// Until when the user is locked out after too many failed attempts
+    locked: Option<DateTime<UTC>>,
 }
 
 impl User {
@@ -571,6 +1319,9 @@ impl User {
         let mut data_display_name = String::new();
         let mut data_password = String::new();
         let mut authenticator_secret = String::new();
+        let mut two_factor_enabled = 0u8;
+        let mut last_totp_step = None;
+        let mut totp_settings = String::new();
         let mut dob_str = String::new();
         let mut dob_confirmed = 0u8;
         let mut phone = String::new();
@@ -593,6 +1344,7 @@ impl User {
         let mut last_activity = UTC::now();
         let mut enabled = 0u8;
         let mut banned = None;
+        let mut locked = None;
 
         for (key, value) in data.iter() {
             match key.as_ref() {
@@ -604,6 +1356,16 @@ impl User {
                 "authenticator_secret" => {
                     authenticator_secret = try!(String::from_redis_value(value))
                 }
+                "two_factor_enabled" => two_factor_enabled = try!(u8::from_redis_value(value)),
+                "totp_settings" => totp_settings = try!(String::from_redis_value(value)),
+                "last_totp_step" => {
+                    let step = try!(String::from_redis_value(value));
+                    last_totp_step = if step.len() > 0 {
+                        Some(step.parse().unwrap())
+                    } else {
+                        None
+                    };
+                }
                 "first_name" => first_name = try!(String::from_redis_value(value)),
                 "first_name_confirmed" => first_name_confirmed = try!(u8::from_redis_value(value)),
                 "last_name" => last_name = try!(String::from_redis_value(value)),
@@ -645,6 +1407,19 @@ impl User {
                     };
 
                 }
+                "locked" => {
+                    locked = match String::from_redis_value(value) {
+                        Ok(l) => {
+                            if l.len() > 0 {
+                                Some(DateTime::<UTC>::from_utc(
+                                    NaiveDateTime::from_timestamp(l.parse().unwrap(), 0), UTC))
+                            } else {
+                                None
+                            }
+                        }
+                        Err(_) => unreachable!(),
+                    };
+                }
                 _ => unreachable!(),
             }
         }
@@ -713,6 +1488,9 @@ impl User {
             first_name: first_opt,
             last_name: last_opt,
             authenticator_secret: authenticator_secret,
+            two_factor_enabled: two_factor_enabled == 1,
+            last_totp_step: last_totp_step,
+            totp: Totp::from_settings(totp_settings, 0),
             birthday: dob,
             phone: phone_opt,
             image_url: img_opt,
@@ -721,6 +1499,7 @@ impl User {
             last_activity: last_activity,
             enabled: enabled == 1,
             banned: banned,
+            locked: locked,
         })
     }
 
@@ -748,6 +1527,37 @@ impl User {
         }
     }
 
+    /// Gets the profile of the user with only the fields allowed by the visibility settings.
+    pub fn get_visible_profile(&self, visibility: &ProfileVisibility) -> Profile {
+        let mut profile = self.get_profile();
+        if !visibility.first_name {
+            profile.first_name = None;
+        }
+        if !visibility.last_name {
+            profile.last_name = None;
+        }
+        if !visibility.image {
+            profile.image_url = None;
+        }
+        if !visibility.age {
+            profile.age = None;
+        }
+        if !visibility.address {
+            profile.address = None;
+        }
+        profile
+    }
+
+    /// Returns which fields of the profile of the user are shown publicly
+    pub fn get_profile_visibility(&self) -> Result<ProfileVisibility> {
+        self.database.get_profile_visibility(self.user_id)
+    }
+
+    /// Sets which fields of the profile of the user are shown publicly
+    pub fn set_profile_visibility(&self, visibility: &ProfileVisibility) -> Result<()> {
+        self.database.set_profile_visibility(self.user_id, visibility)
+    }
+
     /// Returns the user ID
     pub fn get_id(&self) -> u64 {
         self.user_id
@@ -763,17 +1573,87 @@ impl User {
         &self.display_name
     }
 
-    /// Returns the authenticator secret
-    pub fn get_authenticator_secret(&mut self) -> Result<String> {
-        let secret = try!(self.database.create_new_user_authenticator_secret(self.user_id));
+    /// Creates a new authenticator secret to be used with the given TOTP settings, and returns it
+    pub fn get_authenticator_secret(&mut self, totp: &Totp) -> Result<String> {
+        let secret = try!(self.database.create_new_user_authenticator_secret(self.user_id, totp));
         self.authenticator_secret = secret;
+        self.last_totp_step = None;
+        self.totp = Some(*totp);
         Ok(self.authenticator_secret.clone())
     }
 
     /// Checks if the provided authenticator code is valid.
-    pub fn check_authenticator_code(&self, code: u32) -> bool {
-        let auth = TOTP::new(self.authenticator_secret.as_str());
-        auth.verify(code, 30, UTC::now().timestamp() as usize)
+    ///
+    /// The code is checked with the settings the secret was enrolled with, or with `default` for
+    /// secrets enrolled before they were stored. The clock skew is always taken from `default`.
+    ///
+    /// Each code can only be used once: the time step of an accepted code is stored, and codes
+    /// from that time step or older ones will be rejected.
+    pub fn check_authenticator_code(&mut self, default: &Totp, code: u32) -> Result<bool> {
+        let totp = match self.totp {
+            Some(ref totp) => totp.with_skew(default.get_skew()),
+            None => *default,
+        };
+        match totp.verify(self.authenticator_secret.as_bytes(),
+                          code,
+                          UTC::now().timestamp() as u64,
+                          self.last_totp_step) {
+            Some(time_step) => {
+                if try!(self.database.set_last_totp_step(self.user_id, time_step)) {
+                    self.last_totp_step = Some(time_step);
+                    Ok(true)
+                } else {
+                    // Another request used a code from this time step or a newer one.
+                    Ok(false)
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Returns the TOTP settings the authenticator secret was enrolled with, if they were stored
+    pub fn get_totp(&self) -> Option<&Totp> {
+        self.totp.as_ref()
+    }
+
+    /// Returns whether the user has two factor authentication enabled
+    pub fn is_two_factor_enabled(&self) -> bool {
+        self.two_factor_enabled
+    }
+
+    /// Enables or disables two factor authentication for the user
+    pub fn set_two_factor_enabled(&mut self, enabled: bool) -> Result<()> {
+        try!(self.database.set_two_factor_enabled(self.user_id, enabled));
+        self.two_factor_enabled = enabled;
+        Ok(())
+    }
+
+    /// Generates a new set of recovery codes for the user, replacing the previous ones.
+    ///
+    /// Only the hashes of the codes are stored, so the returned codes must be shown to the user
+    /// now, since they cannot be retrieved again.
+    pub fn generate_recovery_codes(&self) -> Result<Vec<String>> {
+        let codes = (0..RECOVERY_CODES).map(|_| generate_recovery_code()).collect::<Vec<_>>();
+        let hashes = codes.iter().map(|c| hash_code(c)).collect::<Vec<_>>();
+        try!(self.database.set_recovery_codes(self.user_id, &hashes));
+        Ok(codes)
+    }
+
+    /// Uses the given recovery code in place of an authenticator code.
+    ///
+    /// Returns whether the code was valid. A valid code is removed, so it can only be used once.
+    pub fn use_recovery_code<S: AsRef<str>>(&self, code: S) -> Result<bool> {
+        self.database.use_recovery_code(self.user_id, hash_code(code.as_ref()))
+    }
+
+    /// Returns the number of unused recovery codes of the user
+    pub fn get_recovery_codes_left(&self) -> Result<u64> {
+        self.database.get_recovery_codes_left(self.user_id)
+    }
+
+    /// Deletes all the recovery codes of the user
+    pub fn delete_recovery_codes(&self) -> Result<()> {
+        self.database.delete_recovery_codes(self.user_id)
     }
 
     /// Returns the users first name and whether its been confirmed
@@ -831,25 +1711,27 @@ impl User {
         Ok(())
     }
 
-    /// checks the password against the users password
-    pub fn check_password<S: AsRef<str>>(&self, pass: S) -> Result<bool> {
-        match pbkdf2::pbkdf2_check(pass.as_ref(), &self.password.as_str()) {
-            Ok(b) => Ok(b),
-            Err(e) => return Err(Error::PasswordError(e)),
+    /// Checks the password against the users password.
+    ///
+    /// If the password is correct but its hash was created with an older algorithm or different
+    /// parameters than the ones in `scheme`, it will be rehashed with `scheme`.
+    pub fn check_password<S: AsRef<str>>(&mut self,
+                                         scheme: &PasswordScheme,
+                                         pass: S)
+                                         -> Result<bool> {
+        let correct = try!(scheme.verify(pass.as_ref(), &self.password));
+        if correct && scheme.needs_rehash(&self.password) {
+            try!(self.set_password(scheme, pass));
         }
-
+        Ok(correct)
     }
 
     /// Sets the user password
-    pub fn set_password<S: AsRef<str>>(&mut self, pass: S) -> Result<()> {
-        match pbkdf2::pbkdf2_simple(pass.as_ref(), 5) {
-            Ok(k) => {
-                try!(self.database.set_user_password(self.get_id(), &k));
-                self.password = k;
-                Ok(())
-            }
-            Err(e) => Err(Error::IO(e)),
-        }
+    pub fn set_password<S: AsRef<str>>(&mut self, scheme: &PasswordScheme, pass: S) -> Result<()> {
+        let hash = try!(scheme.hash(pass));
+        try!(self.database.set_user_password(self.get_id(), &hash));
+        self.password = hash;
+        Ok(())
     }
 
     /// Sets the users email
@@ -859,6 +1741,35 @@ impl User {
         Ok(())
     }
 
+    /// Starts a change of the users email, that will only be applied once the new address is
+    /// confirmed with the `confirm_key`, and can be cancelled with the `cancel_key`
+    pub fn start_email_change<S: AsRef<str>>(&self,
+                                             new_email: S,
+                                             confirm_key: S,
+                                             cancel_key: S,
+                                             ttl: Duration)
+                                             -> Result<()> {
+        self.database.start_email_change(self.user_id, new_email, confirm_key, cancel_key, ttl)
+    }
+
+    /// Returns the pending email change of the user, if any
+    pub fn get_pending_email_change(&self) -> Result<Option<PendingEmailChange>> {
+        self.database.get_pending_email_change(self.user_id)
+    }
+
+    /// Schedules the account to be purged after the grace period, unless the deletion is
+    /// cancelled with the given key, and disables it until then
+    pub fn start_account_deletion<S: AsRef<str>>(&mut self,
+                                                 cancel_key: S,
+                                                 grace: Duration)
+                                                 -> Result<DateTime<UTC>> {
+        let purge_at = UTC::now() + grace;
+        try!(self.database
+            .start_account_deletion(self.user_id, cancel_key, purge_at, self.enabled));
+        self.enabled = false;
+        Ok(purge_at)
+    }
+
     /// Returns the users email address
     pub fn get_email(&self) -> &str {
         &self.email.0
@@ -919,6 +1830,34 @@ impl User {
         self.phone.as_ref()
     }
 
+    /// Stores the verification code sent to the users phone
+    pub fn start_phone_verification<S: AsRef<str>>(&self, code: S, ttl: Duration) -> Result<()> {
+        match self.phone {
+            Some((ref phone, _)) => {
+                self.database
+                    .start_phone_verification(self.user_id, phone.as_str(), code.as_ref(), ttl)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Checks the code sent to the users phone, and confirms the phone if it is correct
+    pub fn verify_phone<S: AsRef<str>>(&mut self, code: S) -> Result<bool> {
+        let verified = match self.phone {
+            Some((ref phone, _)) => {
+                try!(self.database
+                    .check_phone_verification(self.user_id, phone.as_str(), code.as_ref()))
+            }
+            None => false,
+        };
+        if verified {
+            if let Some((_, ref mut confirmed)) = self.phone {
+                *confirmed = true;
+            }
+        }
+        Ok(verified)
+    }
+
     /// Sets the users phone #
     pub fn set_phone<S: AsRef<str>>(&mut self, phonenum: Option<S>) -> Result<()> {
         let phonenum = match phonenum {
@@ -951,6 +1890,25 @@ impl User {
         Ok(())
     }
 
+    /// Sets the uploaded avatar of the user as its profile image, and returns the version of the
+    /// previous one, if any
+    pub fn set_avatar<S: AsRef<str>>(&mut self,
+                                     version: S,
+                                     image_url: S)
+                                     -> Result<Option<String>> {
+        let old_version = try!(self.database.set_user_avatar(self.user_id, Some(version)));
+        try!(self.set_image(Some(image_url)));
+        Ok(old_version)
+    }
+
+    /// Removes the uploaded avatar of the user and its profile image, and returns the version of
+    /// the avatar, if it had one
+    pub fn remove_avatar(&mut self) -> Result<Option<String>> {
+        let old_version = try!(self.database.set_user_avatar(self.user_id, None::<&str>));
+        try!(self.set_image(None::<&str>));
+        Ok(old_version)
+    }
+
     /// Returns the users address
     pub fn get_address(&self) -> Option<&Address> {
         match self.address {
@@ -989,7 +1947,6 @@ impl User {
     pub fn ban(&mut self, until: DateTime<UTC>) -> Result<()> {
         match self.database.ban_user(self.user_id, until) {
             Ok(_) => {
-                self.enabled = false;
                 self.banned = Some(until);
                 Ok(())
             }
@@ -998,6 +1955,13 @@ impl User {
 
     }
 
+    /// Lifts the ban of the user
+    pub fn unban(&mut self) -> Result<()> {
+        try!(self.database.unban_user(self.user_id));
+        self.banned = None;
+        Ok(())
+    }
+
     /// Returns when the user registered
     pub fn get_registration_time(&self) -> &DateTime<UTC> {
         &self.registration_time
@@ -1028,9 +1992,74 @@ impl User {
         }
     }
 
-    /// Begins the reset password proccess
-    pub fn start_reset_password<S: AsRef<str>>(&self, key: S) -> Result<()> {
-        self.database.start_reset_password(self.user_id, key)
+    /// Returns until when the user is locked, if it is locked
+    pub fn get_locked(&self) -> Option<&DateTime<UTC>> {
+        self.locked.as_ref()
+    }
+
+    /// Checks if the user is currently locked out after too many failed attempts
+    pub fn is_locked(&self) -> bool {
+        match self.locked {
+            Some(l) => l > UTC::now(),
+            None => false,
+        }
+    }
+
+    /// Registers a failed login or authenticator attempt.
+    ///
+    /// If the user reaches `max_attempts` consecutive failures, it will be locked for `lockout`
+    /// and `true` will be returned, so that the caller can notify the user.
+    pub fn register_failed_attempt(&mut self,
+                                   max_attempts: u32,
+                                   lockout: Duration)
+                                   -> Result<bool> {
+        let count = try!(self.database.increment_failed_logins(self.user_id, lockout));
+        if count >= max_attempts {
+            let until = UTC::now() + lockout;
+            try!(self.database.lock_user(self.user_id, until));
+            try!(self.database.reset_failed_logins(self.user_id));
+            self.locked = Some(until);
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
+    /// Clears the failed attempts of the user after a successful login or authentication
+    pub fn clear_failed_attempts(&self) -> Result<()> {
+        self.database.reset_failed_logins(self.user_id)
+    }
+
+    /// Unlocks the user account
+    pub fn unlock(&mut self) -> Result<()> {
+        try!(self.database.unlock_user(self.user_id));
+        self.locked = None;
+        Ok(())
+    }
+
+    /// Begins the reset password proccess, with a key valid for the given time
+    pub fn start_reset_password<S: AsRef<str>>(&self, key: S, ttl: Duration) -> Result<()> {
+        self.database.start_reset_password(self.user_id, key, ttl)
+    }
+
+    /// Starts a passwordless login with the one time key, valid for the given time
+    pub fn start_magic_login<S: AsRef<str>>(&self,
+                                            key: S,
+                                            app_id: S,
+                                            remember_me: bool,
+                                            ttl: Duration)
+                                            -> Result<()> {
+        let login = MagicLogin {
+            user_id: self.user_id,
+            app_id: String::from(app_id.as_ref()),
+            remember_me: remember_me,
+        };
+        self.database.start_magic_login(key, &login, ttl)
+    }
+
+    /// Revokes all the tokens issued to the user until now
+    pub fn revoke_sessions(&self) -> Result<()> {
+        self.database.revoke_user_sessions(self.user_id)
     }
 
     /// Disables the users account
@@ -1133,6 +2162,110 @@ impl PartialEq for User {
     }
 }
 
+/// Generates a random recovery code, in the form `xxxxx-xxxxx`
+fn generate_recovery_code() -> String {
+    const CHARSET: &'static [u8] = b"abcdefghijkmnpqrstuvwxyz23456789";
+    let mut rng = thread_rng();
+    let mut code = String::with_capacity(11);
+    for i in 0..10 {
+        if i == 5 {
+            code.push('-');
+        }
+        code.push(*rng.choose(CHARSET).unwrap() as char);
+    }
+    code
+}
+
+/// Hashes a recovery code to store it or look it up in the database
+fn hash_code(code: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input_str(code.trim().to_lowercase().as_str());
+    hasher.result_str()
+}
+
+/// A login that passed the password check but still needs the second factor
+#[derive(Debug, Clone)]
+pub struct MfaPendingLogin {
+    /// ID of the user logging in.
+    pub user_id: u64,
+    /// ID of the client application the user is logging in with.
+    pub app_id: String,
+    /// Whether the user asked to be remembered.
+    pub remember_me: bool,
+}
+
+/// A passwordless login requested with a one time key sent by email
+#[derive(Debug, Clone)]
+pub struct MagicLogin {
+    /// ID of the user logging in.
+    pub user_id: u64,
+    /// ID of the client application the login was requested with.
+    pub app_id: String,
+    /// Whether the user asked to be remembered.
+    pub remember_me: bool,
+}
+
+/// A change of email waiting for the new address to be confirmed
+#[derive(Debug, Clone)]
+pub struct PendingEmailChange {
+    /// ID of the user changing the email.
+    pub user_id: u64,
+    /// The new email address.
+    pub new_email: String,
+    /// The key sent to the new address to confirm the change.
+    pub confirm_key: String,
+    /// The key sent to the old address to cancel the change.
+    pub cancel_key: String,
+    /// When the change was requested.
+    pub requested: DateTime<UTC>,
+}
+
+/// Which fields of the profile of a user are shown publicly
+///
+/// The display name is always shown, unless the whole profile is hidden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProfileVisibility {
+    /// Whether the whole profile is hidden.
+    pub hidden: bool,
+    /// Whether the first name is shown.
+    pub first_name: bool,
+    /// Whether the last name is shown.
+    pub last_name: bool,
+    /// Whether the profile image is shown.
+    pub image: bool,
+    /// Whether the age is shown.
+    pub age: bool,
+    /// Whether the city, state and country are shown.
+    pub address: bool,
+}
+
+impl ProfileVisibility {
+    /// Returns the most restrictive visibility, that hides the whole profile.
+    pub fn hidden() -> ProfileVisibility {
+        ProfileVisibility {
+            hidden: true,
+            first_name: false,
+            last_name: false,
+            image: false,
+            age: false,
+            address: false,
+        }
+    }
+}
+
+impl Default for ProfileVisibility {
+    fn default() -> ProfileVisibility {
+        ProfileVisibility {
+            hidden: false,
+            first_name: false,
+            last_name: false,
+            image: true,
+            age: false,
+            address: false,
+        }
+    }
+}
+
 /// Struct for profiles
 #[derive(Debug, Clone)]
 pub struct Profile {
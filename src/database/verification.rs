@@ -0,0 +1,404 @@
+//! This module holds the methods and structs related to the identity verification requests in
+//! the redis database
+//!
+//! Users submit a verification request for some of their personal fields with supporting
+//! documents, and admins approve or reject it. The values of the fields are stored with the
+//! request, so that an approval only confirms the values that were actually reviewed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, UTC, NaiveDateTime};
+use redis::Commands;
+use rustc_serialize::json;
+
+use public_utils::Address;
+
+use error::{Error, Result};
+use super::{Database, User};
+
+/// A user field that can be confirmed with an identity verification.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationField {
+    /// The first name.
+    FirstName,
+    /// The last name.
+    LastName,
+    /// The date of birth.
+    Birthday,
+    /// The postal address.
+    Address,
+}
+
+impl VerificationField {
+    /// Returns the name of the confirmed flag of the field in the user hash.
+    fn confirmed_key(&self) -> &'static str {
+        match *self {
+            VerificationField::FirstName => "first_name_confirmed",
+            VerificationField::LastName => "last_name_confirmed",
+            VerificationField::Birthday => "birthday_confirmed",
+            VerificationField::Address => "address_confirmed",
+        }
+    }
+
+    /// Returns the current value of the field for the user, if it is set.
+    fn get_value(&self, user: &User) -> Option<String> {
+        match *self {
+            VerificationField::FirstName => user.get_first_name().map(String::from),
+            VerificationField::LastName => user.get_last_name().map(String::from),
+            VerificationField::Birthday => user.get_birthday().map(|b| format!("{}", b)),
+            VerificationField::Address => user.get_address().map(address_value),
+        }
+    }
+}
+
+impl fmt::Display for VerificationField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   VerificationField::FirstName => "first_name",
+                   VerificationField::LastName => "last_name",
+                   VerificationField::Birthday => "birthday",
+                   VerificationField::Address => "address",
+               })
+    }
+}
+
+impl FromStr for VerificationField {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<VerificationField, ()> {
+        match s {
+            "first_name" => Ok(VerificationField::FirstName),
+            "last_name" => Ok(VerificationField::LastName),
+            "birthday" => Ok(VerificationField::Birthday),
+            "address" => Ok(VerificationField::Address),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The state of a verification request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VerificationStatus {
+    /// Waiting for an admin to review it.
+    Pending,
+    /// Approved, and the fields confirmed.
+    Approved,
+    /// Rejected, with a reason.
+    Rejected,
+}
+
+impl fmt::Display for VerificationStatus {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   VerificationStatus::Pending => "pending",
+                   VerificationStatus::Approved => "approved",
+                   VerificationStatus::Rejected => "rejected",
+               })
+    }
+}
+
+impl FromStr for VerificationStatus {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<VerificationStatus, ()> {
+        match s {
+            "pending" => Ok(VerificationStatus::Pending),
+            "approved" => Ok(VerificationStatus::Approved),
+            "rejected" => Ok(VerificationStatus::Rejected),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A document attached to a verification request.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct Attachment {
+    /// The file name given by the user.
+    pub name: String,
+    /// The MIME type of the document.
+    pub content_type: String,
+    /// The key of the document in the blob store.
+    pub key: String,
+}
+
+/// A state transition of a verification request.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VerificationEvent {
+    /// UNIX timestamp of the transition.
+    pub time: i64,
+    /// The new status.
+    pub status: String,
+    /// Who made the transition, such as `user:12` or `client:my_app`.
+    pub actor: String,
+    /// The reason given for the transition, if any.
+    pub reason: Option<String>,
+}
+
+/// An identity verification request.
+#[derive(Debug, Clone)]
+pub struct Verification {
+    /// The ID of the request.
+    pub id: u64,
+    /// The ID of the user.
+    pub user_id: u64,
+    /// The fields to confirm, with their values when the request was submitted.
+    pub fields: Vec<(VerificationField, String)>,
+    /// The current status.
+    pub status: VerificationStatus,
+    /// When the request was submitted.
+    pub created: DateTime<UTC>,
+    /// When the request was reviewed, if it was.
+    pub reviewed: Option<DateTime<UTC>>,
+    /// The reason of the rejection, if it was rejected.
+    pub reason: Option<String>,
+    /// The attached documents.
+    pub attachments: Vec<Attachment>,
+    /// Every state transition of the request.
+    pub history: Vec<VerificationEvent>,
+}
+
+/// Methods working with identity verifications
+impl Database {
+    /// Creates a pending verification request for the current values of the given fields of the
+    /// user
+    ///
+    /// The ID must come from `increment_verification_id()`, and the attachments must already be
+    /// in the blob store.
+    pub fn create_verification<S: AsRef<str>>(&self,
+                                              id: u64,
+                                              user: &User,
+                                              fields: &[VerificationField],
+                                              attachments: &[Attachment],
+                                              actor: S)
+                                              -> Result<()> {
+        let key = format!("verifications:{}", id);
+        let mut data = vec![("user_id".to_owned(), format!("{}", user.get_id())),
+                            ("status".to_owned(), format!("{}", VerificationStatus::Pending)),
+                            ("created".to_owned(), format!("{}", UTC::now().timestamp())),
+                            ("reviewed".to_owned(), String::new()),
+                            ("reason".to_owned(), String::new())];
+        for field in fields {
+            let value = field.get_value(user).unwrap_or(String::new());
+            data.push((format!("field:{}", field), value));
+        }
+
+        {
+            let db = self.inner.lock().unwrap();
+            try!(db.connection.hset_multiple(&key, &data[..]));
+            for attachment in attachments {
+                try!(db.connection.rpush(format!("{}:attachments", key),
+                                         json::encode(attachment).unwrap()));
+            }
+            try!(db.connection.lpush(format!("users:{}:verifications", user.get_id()), id));
+            try!(db.connection.set(format!("users:{}:pending_verification", user.get_id()), id));
+            try!(db.connection.sadd("pending_verifications", id));
+        }
+        self.add_verification_event(id, VerificationStatus::Pending, actor, None)
+    }
+
+    /// Returns the pending verification request of the user, if any
+    pub fn get_user_pending_verification(&self, user_id: u64) -> Result<Option<u64>> {
+        let key = format!("users:{}:pending_verification", user_id);
+        Ok(try!(self.inner.lock().unwrap().connection.get(key)))
+    }
+
+    /// Returns the verification request with the given ID
+    pub fn get_verification(&self, id: u64) -> Result<Option<Verification>> {
+        let key = format!("verifications:{}", id);
+        let (data, attachments, history): (HashMap<String, String>, Vec<String>, Vec<String>) = {
+            let db = self.inner.lock().unwrap();
+            (try!(db.connection.hgetall(&key)),
+             try!(db.connection.lrange(format!("{}:attachments", key), 0, -1)),
+             try!(db.connection.lrange(format!("{}:history", key), 0, -1)))
+        };
+        if data.len() == 0 {
+            return Ok(None);
+        }
+
+        let mut verification = Verification {
+            id: id,
+            user_id: 0,
+            fields: Vec::new(),
+            status: VerificationStatus::Pending,
+            created: UTC::now(),
+            reviewed: None,
+            reason: None,
+            attachments: attachments.iter().map(|a| json::decode(a).unwrap()).collect(),
+            history: history.iter().map(|e| json::decode(e).unwrap()).collect(),
+        };
+        for (key, value) in data {
+            match key.as_str() {
+                "user_id" => verification.user_id = value.parse().unwrap(),
+                "status" => verification.status = value.parse().unwrap(),
+                "created" => verification.created = from_timestamp(&value),
+                "reviewed" => {
+                    if value.len() > 0 {
+                        verification.reviewed = Some(from_timestamp(&value))
+                    }
+                }
+                "reason" => {
+                    if value.len() > 0 {
+                        verification.reason = Some(value)
+                    }
+                }
+                k if k.starts_with("field:") => {
+                    verification.fields.push((k["field:".len()..].parse().unwrap(), value))
+                }
+                _ => unreachable!(),
+            }
+        }
+        Ok(Some(verification))
+    }
+
+    /// Returns all the verification requests of the user, newest first
+    pub fn get_user_verifications(&self, user_id: u64) -> Result<Vec<Verification>> {
+        let key = format!("users:{}:verifications", user_id);
+        let ids: Vec<u64> = try!(self.inner.lock().unwrap().connection.lrange(key, 0, -1));
+        let mut verifications = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(verification) = try!(self.get_verification(id)) {
+                verifications.push(verification);
+            }
+        }
+        Ok(verifications)
+    }
+
+    /// Returns all the verification requests waiting for review
+    pub fn get_pending_verifications(&self) -> Result<Vec<Verification>> {
+        let ids: Vec<u64> =
+            try!(self.inner.lock().unwrap().connection.smembers("pending_verifications"));
+        let mut verifications = Vec::with_capacity(ids.len());
+        for id in ids {
+            if let Some(verification) = try!(self.get_verification(id)) {
+                verifications.push(verification);
+            }
+        }
+        verifications.sort_by(|a, b| a.created.cmp(&b.created));
+        Ok(verifications)
+    }
+
+    /// Approves or rejects a pending verification request, and returns it updated
+    ///
+    /// Approving it confirms the fields of the user, as long as they still have the values that
+    /// were submitted for review.
+    pub fn review_verification<S: AsRef<str>>(&self,
+                                              id: u64,
+                                              approve: bool,
+                                              actor: S,
+                                              reason: Option<String>)
+                                              -> Result<Verification> {
+        let verification = match try!(self.get_verification(id)) {
+            Some(v) => v,
+            None => return Err(Error::IncorrectKey),
+        };
+        if verification.status != VerificationStatus::Pending {
+            return Err(Error::VerificationNotPending);
+        }
+
+        let status = if approve {
+            let user = match try!(self.get_user_by_id(verification.user_id)) {
+                Some(user) => user,
+                None => return Err(Error::UserDoesNotExist),
+            };
+            if verification.fields
+                .iter()
+                .any(|&(field, ref value)| field.get_value(&user).as_ref() != Some(value)) {
+                return Err(Error::VerificationOutdated);
+            }
+            let confirmed = verification.fields
+                .iter()
+                .map(|&(field, _)| (field.confirmed_key(), "1"))
+                .collect::<Vec<_>>();
+            try!(self.inner
+                .lock()
+                .unwrap()
+                .connection
+                .hset_multiple(format!("users:{}", verification.user_id), &confirmed[..]));
+            VerificationStatus::Approved
+        } else {
+            VerificationStatus::Rejected
+        };
+
+        {
+            let key = format!("verifications:{}", id);
+            let data = [("status", format!("{}", status)),
+                        ("reviewed", format!("{}", UTC::now().timestamp())),
+                        ("reason", reason.clone().unwrap_or(String::new()))];
+            let db = self.inner.lock().unwrap();
+            try!(db.connection.hset_multiple(key, &data));
+            try!(db.connection.srem("pending_verifications", id));
+            try!(db.connection
+                .del(format!("users:{}:pending_verification", verification.user_id)));
+        }
+        try!(self.add_verification_event(id, status, actor, reason));
+
+        match try!(self.get_verification(id)) {
+            Some(v) => Ok(v),
+            None => Err(Error::IncorrectKey),
+        }
+    }
+
+    /// Deletes all the verification requests of the user, along with their attachment lists and
+    /// histories
+    ///
+    /// The attached documents themselves are kept in the blob store, and must be deleted by the
+    /// caller.
+    pub fn delete_user_verifications(&self, user_id: u64) -> Result<()> {
+        let key = format!("users:{}:verifications", user_id);
+        let db = self.inner.lock().unwrap();
+        let ids: Vec<u64> = try!(db.connection.lrange(&key, 0, -1));
+        for id in ids {
+            try!(db.connection.del(format!("verifications:{}", id)));
+            try!(db.connection.del(format!("verifications:{}:attachments", id)));
+            try!(db.connection.del(format!("verifications:{}:history", id)));
+            try!(db.connection.srem("pending_verifications", id));
+        }
+        try!(db.connection.del(&key));
+        try!(db.connection.del(format!("users:{}:pending_verification", user_id)));
+        Ok(())
+    }
+
+    /// Adds a state transition to the history of the verification request
+    fn add_verification_event<S: AsRef<str>>(&self,
+                                             id: u64,
+                                             status: VerificationStatus,
+                                             actor: S,
+                                             reason: Option<String>)
+                                             -> Result<()> {
+        let event = VerificationEvent {
+            time: UTC::now().timestamp(),
+            status: format!("{}", status),
+            actor: String::from(actor.as_ref()),
+            reason: reason,
+        };
+        try!(self.inner
+            .lock()
+            .unwrap()
+            .connection
+            .rpush(format!("verifications:{}:history", id),
+                   json::encode(&event).unwrap()));
+        Ok(())
+    }
+}
+
+/// Returns the value of the address that gets verified
+fn address_value(address: &Address) -> String {
+    format!("{}, {}, {}, {}, {}, {}",
+            address.get_address1(),
+            address.get_address2().unwrap_or(""),
+            address.get_city(),
+            address.get_state(),
+            address.get_zip(),
+            address.get_country())
+}
+
+/// Converts a UNIX timestamp stored in the database to a `DateTime`
+fn from_timestamp(timestamp: &str) -> DateTime<UTC> {
+    DateTime::from_utc(NaiveDateTime::from_timestamp(timestamp.parse().unwrap(), 0), UTC)
+}
@@ -11,6 +11,8 @@ use std::{io, fmt, str, string};
 use std::result;
 
 
+use chrono::{DateTime, UTC};
+use iron::status::Status;
 use redis::RedisError;
 use rustc_serialize::base64;
 
@@ -50,6 +52,131 @@ pub enum Error {
     FailedCreatePNG,
     /// User does not exist
     UserDoesNotExist,
+    /// Email already in use by another user
+    EmailExists,
+    /// The SMS provider failed to send a message
+    SmsError(String),
+    /// The blob store failed to store or return a blob
+    StorageError(String),
+    /// The verification request has already been reviewed
+    VerificationNotPending,
+    /// The verified fields changed after the verification request was submitted
+    VerificationOutdated,
+    /// A user with that username already exists
+    UsernameExists,
+    /// The username, email, password or client secret are not correct
+    InvalidCredentials,
+    /// The authenticator or SMS code is not correct or has expired
+    IncorrectCode,
+    /// The user is banned
+    UserBanned,
+    /// The user account is disabled
+    UserDisabled,
+    /// The user is locked out after too many failed attempts, until the given time
+    UserLocked(DateTime<UTC>),
+    /// Two factor authentication is already enabled for the user
+    TwoFactorEnabled,
+    /// Two factor authentication is not enabled for the user
+    TwoFactorNotEnabled,
+    /// The phone of the user is already confirmed
+    PhoneConfirmed,
+    /// The user has no phone number
+    NoPhone,
+    /// Too many SMS codes were sent to the phone number recently
+    SmsLimitReached,
+    /// The body of the request is too big
+    PayloadTooLarge,
+    /// The uploaded image is not valid
+    InvalidImage,
+    /// A data export of the user is already being prepared
+    ExportPending,
+    /// The user already has a pending verification request
+    VerificationPending,
+    /// The requested resource does not exist
+    NotFound,
+}
+
+impl Error {
+    /// Returns the stable code of the error, that API clients can rely on.
+    pub fn get_code(&self) -> &'static str {
+        match *self {
+            Error::IO(_) => "io_error",
+            Error::StringUTF8(_) | Error::StrUTF8(_) => "invalid_utf8",
+            Error::Base64(_) => "invalid_base64",
+            Error::Redis(_) => "database_error",
+            Error::PasswordError(_) => "password_error",
+            Error::RequestError => "upstream_error",
+            Error::RequestLimitReached => "request_limit_reached",
+            Error::NoScopes => "no_scopes",
+            Error::ClientDoesNotExist => "client_not_found",
+            Error::ConnectionExists => "connection_exists",
+            Error::ClientExists => "client_exists",
+            Error::IncorrectKey => "invalid_key",
+            Error::FailedCreatePNG => "image_error",
+            Error::UserDoesNotExist => "user_not_found",
+            Error::EmailExists => "email_exists",
+            Error::SmsError(_) => "sms_error",
+            Error::StorageError(_) => "storage_error",
+            Error::VerificationNotPending => "verification_not_pending",
+            Error::VerificationOutdated => "verification_outdated",
+            Error::UsernameExists => "username_exists",
+            Error::InvalidCredentials => "invalid_credentials",
+            Error::IncorrectCode => "invalid_code",
+            Error::UserBanned => "user_banned",
+            Error::UserDisabled => "user_disabled",
+            Error::UserLocked(_) => "user_locked",
+            Error::TwoFactorEnabled => "two_factor_enabled",
+            Error::TwoFactorNotEnabled => "two_factor_not_enabled",
+            Error::PhoneConfirmed => "phone_confirmed",
+            Error::NoPhone => "no_phone",
+            Error::SmsLimitReached => "sms_limit_reached",
+            Error::PayloadTooLarge => "payload_too_large",
+            Error::InvalidImage => "invalid_image",
+            Error::ExportPending => "export_pending",
+            Error::VerificationPending => "verification_pending",
+            Error::NotFound => "not_found",
+        }
+    }
+
+    /// Returns the HTTP status code of the responses for the error.
+    pub fn get_status(&self) -> Status {
+        match *self {
+            Error::StringUTF8(_) |
+            Error::StrUTF8(_) |
+            Error::Base64(_) |
+            Error::NoScopes => Status::BadRequest,
+            Error::InvalidCredentials |
+            Error::IncorrectCode |
+            Error::UserBanned |
+            Error::UserDisabled |
+            Error::UserLocked(_) => Status::Forbidden,
+            Error::ClientDoesNotExist |
+            Error::IncorrectKey |
+            Error::UserDoesNotExist |
+            Error::NotFound => Status::NotFound,
+            Error::ConnectionExists |
+            Error::ClientExists |
+            Error::EmailExists |
+            Error::VerificationNotPending |
+            Error::VerificationOutdated |
+            Error::UsernameExists |
+            Error::TwoFactorEnabled |
+            Error::TwoFactorNotEnabled |
+            Error::PhoneConfirmed |
+            Error::NoPhone |
+            Error::ExportPending |
+            Error::VerificationPending => Status::Conflict,
+            Error::PayloadTooLarge => Status::PayloadTooLarge,
+            Error::InvalidImage => Status::UnprocessableEntity,
+            Error::RequestLimitReached | Error::SmsLimitReached => Status::TooManyRequests,
+            Error::RequestError | Error::SmsError(_) => Status::BadGateway,
+            Error::IO(_) |
+            Error::Redis(_) |
+            Error::PasswordError(_) |
+            Error::FailedCreatePNG |
+            Error::StorageError(_) => Status::InternalServerError,
+        }
+    }
 }
 
 impl fmt::Display for Error {
@@ -106,6 +233,27 @@ impl StdErr for Error {
             Error::IncorrectKey => "Incorrect key",
             Error::FailedCreatePNG => "Failed to create png",
             Error::UserDoesNotExist => "User does not exist",
+            Error::EmailExists => "Email already in use",
+            Error::SmsError(ref e) => e,
+            Error::StorageError(ref e) => e,
+            Error::VerificationNotPending => "The verification request was already reviewed",
+            Error::VerificationOutdated => "The verified fields changed after the request",
+            Error::UsernameExists => "Username already in use",
+            Error::InvalidCredentials => "Incorrect credentials",
+            Error::IncorrectCode => "Incorrect code",
+            Error::UserBanned => "The user is banned",
+            Error::UserDisabled => "The user is disabled",
+            Error::UserLocked(_) => "The user is locked",
+            Error::TwoFactorEnabled => "Two factor authentication is already enabled",
+            Error::TwoFactorNotEnabled => "Two factor authentication is not enabled",
+            Error::PhoneConfirmed => "The phone is already confirmed",
+            Error::NoPhone => "The user has no phone",
+            Error::SmsLimitReached => "Too many SMS were sent to the phone",
+            Error::PayloadTooLarge => "The request is too big",
+            Error::InvalidImage => "The image is not valid",
+            Error::ExportPending => "An export is already being prepared",
+            Error::VerificationPending => "A verification request is already pending",
+            Error::NotFound => "Not found",
         }
     }
 
@@ -31,8 +31,8 @@
 
 #[macro_use]
 extern crate iron;
+extern crate hyper;
 extern crate router;
-extern crate staticfile;
 extern crate mount;
 #[macro_use]
 extern crate lazy_static;
@@ -44,18 +44,17 @@ extern crate chrono;
 extern crate byteorder;
 extern crate lettre;
 extern crate crypto;
-extern crate otpauth;
 extern crate qrcode;
 extern crate image;
 extern crate data_encoding;
+extern crate idna;
 
 extern crate rest_api_data_utils as public_utils;
 extern crate rest_api_data_types as dto;
 
 use std::sync::{Arc, Mutex};
-use std::{thread, fs};
+use std::thread;
 use std::time::Duration;
-use std::path::Path;
 
 use lettre::transport::smtp::{SecurityLevel, SmtpTransportBuilder};
 use lettre::transport::smtp::authentication::Mechanism;
@@ -65,6 +64,11 @@ use lettre::transport::EmailTransport;
 pub mod config;
 pub mod error;
 pub mod utils;
+pub mod totp;
+pub mod password;
+pub mod validation;
+pub mod sms;
+pub mod storage;
 pub mod database;
 pub mod v1;
 
@@ -72,6 +76,8 @@ use v1::*;
 use config::Config;
 use utils::{EmailStruct, EmailType};
 use database::Database;
+use sms::SmsSender;
+use storage::BlobStore;
 
 const REDIS_URLS: [&'static str; 1] = ["redis://127.0.0.1/"];
 const ENCRYPTION_SERVERS: [&'static str; 1] = ["127.0.0.1:33384"];
@@ -86,18 +92,17 @@ lazy_static! {
     static ref DATABASES: [Database; 1] = [
         Database::new(REDIS_URLS[0 % REDIS_URLS.len()]).unwrap()];
     static ref EMAILS: Arc<Mutex<Vec<EmailStruct>>> = Arc::new(Mutex::new(Vec::new()));
+    static ref SMS: Box<SmsSender> = CONFIG.get_sms_sender();
+    static ref BLOBS: Box<BlobStore> = CONFIG.get_blob_store();
 }
 
 #[cfg(feature = "ssl")]
 fn main() {
     let server = route_server();
 
-    if !Path::new(QRCODES_PATH).exists() {
-        fs::create_dir(QRCODES_PATH).unwrap();
-    }
-
     let _ = thread::spawn(email_thread);
-    let _ = thread::spawn(clear_qrcode_directory);
+    let _ = thread::spawn(account_deletion_thread);
+    let _ = thread::spawn(data_export_thread);
 
     println!("Server running at https://{}/", WEB_URL);
     let _ = server.https(WEB_URL, CONFIG.get_ssl_cert(), CONFIG.get_ssl_key()).unwrap();
@@ -107,17 +112,37 @@ fn main() {
 fn main() {
     let server = route_server();
 
-    if !Path::new(QRCODES_PATH).exists() {
-        fs::create_dir(QRCODES_PATH).unwrap();
-    }
-
     let _ = thread::spawn(email_thread);
-    let _ = thread::spawn(clear_qrcode_directory);
+    let _ = thread::spawn(account_deletion_thread);
+    let _ = thread::spawn(data_export_thread);
 
     println!("Server running at http://{}/", WEB_URL);
     let _ = server.http(WEB_URL).unwrap();
 }
 
+/// Purges the accounts whose deletion grace period has ended every minute
+fn account_deletion_thread() {
+    loop {
+        thread::sleep(Duration::from_secs(60));
+        if let Err(e) = v1::user::purge_deleted_accounts() {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+        }
+    }
+}
+
+/// Prepares the requested personal data exports and deletes the expired ones every minute
+fn data_export_thread() {
+    loop {
+        thread::sleep(Duration::from_secs(60));
+        if let Err(e) = v1::export::prepare_data_exports() {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+        }
+        if let Err(e) = v1::export::delete_expired_data_exports() {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+        }
+    }
+}
+
 /// Sends the emails every minute
 fn email_thread() {
     let mut mailer = SmtpTransportBuilder::new(("smtp.mymaildomain.com", 587))
@@ -135,20 +160,73 @@ fn email_thread() {
         let mut ems = EMAILS.lock().unwrap();
         while let Some(email) = ems.pop() {
 
-            let email_type = match email.email_type {
-                EmailType::Email => "confirm_email",
-                EmailType::Password => "reset_password",
-            };
-
-            let subject = match email.email_type {
-                EmailType::Email => "Email Confirmation",
-                EmailType::Password => "Password Reset",
+            let (subject, body) = match email.email_type {
+                EmailType::Email => {
+                    ("Email Confirmation",
+                     format!("http://my.domain.com/confirm_email/{}", email.email_key))
+                }
+                EmailType::Password => {
+                    ("Password Reset",
+                     format!("http://my.domain.com/reset_password/{}", email.email_key))
+                }
+                EmailType::MagicLink => {
+                    ("Sign In Link",
+                     format!("http://my.domain.com/magic_login/{}", email.email_key))
+                }
+                EmailType::Lockout(until) => {
+                    ("Account Locked",
+                     format!("Your account has been locked until {} after too many failed \
+                              sign in attempts. If this was not you, please contact support.",
+                             until))
+                }
+                EmailType::PasswordChanged => {
+                    ("Password Changed",
+                     String::from("The password of your account has been changed, and you have \
+                                   been signed out of all your sessions. If this was not you, \
+                                   please contact support."))
+                }
+                EmailType::EmailChange => {
+                    ("Email Change Confirmation",
+                     format!("http://my.domain.com/confirm_email_change/{}", email.email_key))
+                }
+                EmailType::EmailChangeNotice(ref new_email) => {
+                    ("Email Change Requested",
+                     format!("A change of the email of your account to {} was requested. If \
+                              this was not you, cancel it at \
+                              http://my.domain.com/cancel_email_change/{} and please contact \
+                              support.",
+                             new_email,
+                             email.email_key))
+                }
+                EmailType::NewDeviceLogin(ref user_agent, ref ip) => {
+                    ("New Sign In",
+                     format!("Your account was signed in from a new device or location: {} \
+                              from {}. If this was not you, please change your password and sign \
+                              out of that session.",
+                             user_agent,
+                             ip))
+                }
+                EmailType::AccountDeletion(purge_at) => {
+                    ("Account Deletion Requested",
+                     format!("Your account has been disabled and will be deleted on {}. If you \
+                              want to keep it, cancel the deletion at \
+                              http://my.domain.com/cancel_account_deletion/{}.",
+                             purge_at,
+                             email.email_key))
+                }
+                EmailType::DataExport(expires) => {
+                    ("Your Data Export",
+                     format!("The export of your personal data is ready. Download it at \
+                              http://my.domain.com/data_export/{} before {}.",
+                             email.email_key,
+                             expires))
+                }
             };
 
             let new_email = EmailBuilder::new()
                 .to(email.email.as_str())
                 .from("no-reply@mydomain.com")
-                .body(&format!("http://my.domain.com/{}/{}", email_type, email.email_key))
+                .body(&body)
                 .subject(subject)
                 .build()
                 .unwrap();
@@ -159,32 +237,3 @@ fn email_thread() {
         }
     }
 }
-
-/// Deletes the qrcodes in the qrcode directory
-fn clear_qrcode_directory() {
-    loop {
-        thread::sleep(Duration::from_secs(10 * 60));
-
-        match fs::read_dir(QRCODES_PATH) {
-            Ok(dir) => {
-                for entry in dir {
-                    if let Ok(entry) = entry {
-                        if let Ok(metadata) = entry.metadata() {
-                            if let Ok(created) = metadata.created() {
-                                if created.elapsed().unwrap().as_secs() > 60 * 60 {
-                                    if let Err(e) = fs::remove_file(entry.path()) {
-                                        println!("Error removing QR code: {:?}", e);
-                                    }
-                                }
-                            }
-                        }
-                    }
-
-                }
-            }
-            Err(e) => {
-                println!("Error reading qrcode directory {:?}", e);
-            }
-        }
-    }
-}
@@ -0,0 +1,407 @@
+//! This module handles the hashing of the user passwords.
+//!
+//! Passwords are hashed with scrypt, and stored in its modular crypt format, that starts with the
+//! `$rscrypt$` prefix followed by the parameters used. Hashes from before the migration to scrypt
+//! use the `$rpbkdf2$` prefix; they can still be checked, and should be rehashed when the user
+//! logs in.
+//!
+//! It also contains the password strength policy applied when users choose a new password.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::mem;
+use std::result::Result as StdResult;
+
+use crypto::{pbkdf2, scrypt};
+use crypto::scrypt::ScryptParams;
+use byteorder::{LittleEndian, ByteOrder};
+use rustc_serialize::base64::FromBase64;
+
+use error::{Error, Result};
+
+/// Prefix of the scrypt hashes.
+const SCRYPT_PREFIX: &'static str = "$rscrypt$";
+/// Prefix of the legacy PBKDF2 hashes.
+const PBKDF2_PREFIX: &'static str = "$rpbkdf2$";
+
+/// Password hashing scheme, with the scrypt parameters used for new hashes.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordScheme {
+    /// Base 2 logarithm of the scrypt `N` parameter.
+    log_n: u8,
+    /// The scrypt `r` parameter.
+    r: u32,
+    /// The scrypt `p` parameter.
+    p: u32,
+}
+
+impl PasswordScheme {
+    /// Creates a new password scheme with the given scrypt parameters.
+    ///
+    /// The parameters must be valid according to `check_params()`.
+    pub fn new(log_n: u8, r: u32, p: u32) -> PasswordScheme {
+        PasswordScheme {
+            log_n: log_n,
+            r: r,
+            p: p,
+        }
+    }
+
+    /// Checks that the scrypt parameters are valid, so that hashing with them cannot panic.
+    ///
+    /// `N` must be greater than 1 and lower than `2^(16 r)`, `r` and `p` must be greater than 0,
+    /// `p r` must be lower than `2^30`, and the memory used, `128 N r` bytes, must fit in memory.
+    pub fn check_params(&self) -> StdResult<(), &'static str> {
+        if self.r == 0 || self.p == 0 {
+            return Err("scrypt_r and scrypt_p must be greater than 0");
+        }
+        if self.log_n == 0 || self.log_n as usize >= mem::size_of::<usize>() * 8 ||
+           self.log_n as u64 >= 16 * self.r as u64 {
+            return Err("scrypt_log_n must be greater than 0 and lower than 16 * scrypt_r");
+        }
+        if self.r as u64 * self.p as u64 >= 1 << 30 {
+            return Err("scrypt_r * scrypt_p must be lower than 2^30");
+        }
+        let r128 = (self.r as usize).checked_mul(128);
+        match (r128.and_then(|r128| r128.checked_mul(1 << self.log_n)),
+               r128.and_then(|r128| r128.checked_mul(self.p as usize))) {
+            (Some(_), Some(_)) => Ok(()),
+            _ => Err("the scrypt parameters need more memory than can be addressed"),
+        }
+    }
+
+    /// Hashes the password with the current parameters.
+    pub fn hash<S: AsRef<str>>(&self, password: S) -> Result<String> {
+        let params = ScryptParams::new(self.log_n, self.r, self.p);
+        Ok(try!(scrypt::scrypt_simple(password.as_ref(), &params)))
+    }
+
+    /// Checks the password against the given hash, that can be a scrypt or legacy PBKDF2 hash.
+    pub fn verify<S: AsRef<str>, H: AsRef<str>>(&self, password: S, hash: H) -> Result<bool> {
+        let hash = hash.as_ref();
+        let result = if hash.starts_with(SCRYPT_PREFIX) {
+            scrypt::scrypt_check(password.as_ref(), hash)
+        } else if hash.starts_with(PBKDF2_PREFIX) {
+            pbkdf2::pbkdf2_check(password.as_ref(), hash)
+        } else {
+            Err("unknown password hash format")
+        };
+        match result {
+            Ok(b) => Ok(b),
+            Err(e) => Err(Error::PasswordError(e)),
+        }
+    }
+
+    /// Checks if the hash was created with a different algorithm or parameters than the current
+    /// ones, and should be replaced after the next successful check.
+    pub fn needs_rehash<H: AsRef<str>>(&self, hash: H) -> bool {
+        let parts = hash.as_ref().split('$').collect::<Vec<_>>();
+        if parts.len() < 4 || parts[1] != "rscrypt" {
+            return true;
+        }
+        let params = match parts[3].from_base64() {
+            Ok(p) => p,
+            Err(_) => return true,
+        };
+        let (log_n, r, p) = match (parts[2], params.len()) {
+            ("0", 3) => (params[0], params[1] as u32, params[2] as u32),
+            ("1", 9) => {
+                (params[0],
+                 LittleEndian::read_u32(&params[1..5]),
+                 LittleEndian::read_u32(&params[5..9]))
+            }
+            _ => return true,
+        };
+        log_n != self.log_n || r != self.r || p != self.p
+    }
+}
+
+lazy_static! {
+    /// Bundled list of common and breached passwords, in lowercase.
+    static ref COMMON_PASSWORDS: HashSet<String> = include_str!("data/common_passwords.txt")
+        .lines()
+        .map(|p| p.trim().to_lowercase())
+        .filter(|p| !p.is_empty())
+        .collect();
+}
+
+/// A password policy rule that a password did not meet.
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordRule {
+    /// The password is shorter than the given length.
+    MinLength(usize),
+    /// The password has less than the given number of character classes (lowercase, uppercase,
+    /// digits and symbols).
+    CharacterClasses(u32),
+    /// The password contains the username.
+    ContainsUsername,
+    /// The password contains the local part of the email address.
+    ContainsEmail,
+    /// The estimated entropy of the password is lower than the given bits.
+    MinEntropy(u32),
+    /// The password is in the list of common or breached passwords.
+    CommonPassword,
+}
+
+impl PasswordRule {
+    /// Gets the machine readable code of the rule.
+    pub fn get_code(&self) -> &'static str {
+        match *self {
+            PasswordRule::MinLength(_) => "min_length",
+            PasswordRule::CharacterClasses(_) => "character_classes",
+            PasswordRule::ContainsUsername => "contains_username",
+            PasswordRule::ContainsEmail => "contains_email",
+            PasswordRule::MinEntropy(_) => "min_entropy",
+            PasswordRule::CommonPassword => "common_password",
+        }
+    }
+}
+
+impl fmt::Display for PasswordRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PasswordRule::MinLength(l) => {
+                write!(f, "the password must be at least {} characters long", l)
+            }
+            PasswordRule::CharacterClasses(c) => {
+                write!(f,
+                       "the password must contain at least {} of lowercase letters, uppercase \
+                        letters, digits and symbols",
+                       c)
+            }
+            PasswordRule::ContainsUsername => write!(f, "the password cannot contain the username"),
+            PasswordRule::ContainsEmail => {
+                write!(f, "the password cannot contain the email address")
+            }
+            PasswordRule::MinEntropy(_) => write!(f, "the password is too easy to guess"),
+            PasswordRule::CommonPassword => {
+                write!(f, "the password is too common or has appeared in a data breach")
+            }
+        }
+    }
+}
+
+/// Password strength policy.
+#[derive(Debug, Clone, Copy)]
+pub struct PasswordPolicy {
+    /// Minimum number of characters.
+    min_length: usize,
+    /// Minimum number of character classes.
+    min_classes: u32,
+    /// Minimum estimated entropy, in bits.
+    min_entropy: u32,
+    /// Whether to reject passwords containing the username or email.
+    reject_personal: bool,
+    /// Whether to reject passwords in the bundled common passwords list.
+    reject_common: bool,
+}
+
+impl PasswordPolicy {
+    /// Creates a new password policy.
+    pub fn new(min_length: usize,
+               min_classes: u32,
+               min_entropy: u32,
+               reject_personal: bool,
+               reject_common: bool)
+               -> PasswordPolicy {
+        PasswordPolicy {
+            min_length: min_length,
+            min_classes: min_classes,
+            min_entropy: min_entropy,
+            reject_personal: reject_personal,
+            reject_common: reject_common,
+        }
+    }
+
+    /// Checks the password of the user with the given username and email against the policy.
+    ///
+    /// Returns every rule the password did not meet, or an empty vector if it is valid.
+    pub fn check<P: AsRef<str>, U: AsRef<str>, E: AsRef<str>>(&self,
+                                                             password: P,
+                                                             username: U,
+                                                             email: E)
+                                                             -> Vec<PasswordRule> {
+        let password = password.as_ref();
+        let lowercase = password.to_lowercase();
+        let mut failed = Vec::new();
+
+        if password.chars().count() < self.min_length {
+            failed.push(PasswordRule::MinLength(self.min_length));
+        }
+
+        if character_classes(password) < self.min_classes {
+            failed.push(PasswordRule::CharacterClasses(self.min_classes));
+        }
+
+        if self.reject_personal {
+            let username = username.as_ref().to_lowercase();
+            if !username.is_empty() && lowercase.contains(&username) {
+                failed.push(PasswordRule::ContainsUsername);
+            }
+            let email = email.as_ref().to_lowercase();
+            let local_part = email.split('@').next().unwrap_or("");
+            if local_part.len() >= 3 && lowercase.contains(local_part) {
+                failed.push(PasswordRule::ContainsEmail);
+            }
+        }
+
+        if estimate_entropy(password) < self.min_entropy as f64 {
+            failed.push(PasswordRule::MinEntropy(self.min_entropy));
+        }
+
+        if self.reject_common && COMMON_PASSWORDS.contains(&lowercase) {
+            failed.push(PasswordRule::CommonPassword);
+        }
+
+        failed
+    }
+}
+
+/// Counts the character classes (lowercase, uppercase, digits and symbols) in the password.
+fn character_classes(password: &str) -> u32 {
+    let mut classes = 0;
+    if password.chars().any(|c| c.is_lowercase()) {
+        classes += 1;
+    }
+    if password.chars().any(|c| c.is_uppercase()) {
+        classes += 1;
+    }
+    if password.chars().any(|c| c.is_numeric()) {
+        classes += 1;
+    }
+    if password.chars().any(|c| !c.is_alphanumeric()) {
+        classes += 1;
+    }
+    classes
+}
+
+/// Estimates the entropy of the password, in bits.
+///
+/// It uses the size of the alphabet of the character classes in the password, and only counts
+/// each distinct character once, so that repeated characters do not make a password stronger.
+fn estimate_entropy(password: &str) -> f64 {
+    let mut alphabet = 0u32;
+    if password.chars().any(|c| c >= 'a' && c <= 'z') {
+        alphabet += 26;
+    }
+    if password.chars().any(|c| c >= 'A' && c <= 'Z') {
+        alphabet += 26;
+    }
+    if password.chars().any(|c| c >= '0' && c <= '9') {
+        alphabet += 10;
+    }
+    if password.chars().any(|c| (c as u32) < 128 && !c.is_alphanumeric()) {
+        alphabet += 33;
+    }
+    if password.chars().any(|c| (c as u32) >= 128) {
+        alphabet += 100;
+    }
+
+    if alphabet == 0 {
+        return 0.0;
+    }
+    let distinct = password.chars().collect::<HashSet<_>>().len();
+    distinct as f64 * (alphabet as f64).log2()
+}
+
+#[cfg(test)]
+mod tests {
+    use crypto::pbkdf2;
+    use super::{PasswordScheme, PasswordPolicy, PasswordRule};
+
+    /// Cheap parameters, so that the tests run fast.
+    fn scheme() -> PasswordScheme {
+        PasswordScheme::new(4, 1, 1)
+    }
+
+    #[test]
+    fn valid_params() {
+        assert!(PasswordScheme::new(15, 8, 1).check_params().is_ok());
+        assert!(scheme().check_params().is_ok());
+    }
+
+    #[test]
+    fn invalid_params() {
+        assert!(PasswordScheme::new(0, 8, 1).check_params().is_err());
+        assert!(PasswordScheme::new(15, 0, 1).check_params().is_err());
+        assert!(PasswordScheme::new(15, 8, 0).check_params().is_err());
+        assert!(PasswordScheme::new(16, 1, 1).check_params().is_err());
+        assert!(PasswordScheme::new(64, 8, 1).check_params().is_err());
+        assert!(PasswordScheme::new(15, 1 << 15, 1 << 15).check_params().is_err());
+    }
+
+    #[test]
+    fn hash_and_verify() {
+        let scheme = scheme();
+        let hash = scheme.hash("correct horse").unwrap();
+        assert!(hash.starts_with("$rscrypt$"));
+        assert!(scheme.verify("correct horse", &hash).unwrap());
+        assert!(!scheme.verify("wrong horse", &hash).unwrap());
+        assert!(scheme.verify("correct horse", "not a hash").is_err());
+    }
+
+    #[test]
+    fn legacy_hashes() {
+        let hash = pbkdf2::pbkdf2_simple("correct horse", 1000).unwrap();
+        assert!(scheme().verify("correct horse", &hash).unwrap());
+        assert!(!scheme().verify("wrong horse", &hash).unwrap());
+        assert!(scheme().needs_rehash(&hash));
+    }
+
+    #[test]
+    fn rehash_on_new_params() {
+        let hash = scheme().hash("correct horse").unwrap();
+        assert!(!scheme().needs_rehash(&hash));
+        assert!(PasswordScheme::new(5, 1, 1).needs_rehash(&hash));
+        assert!(PasswordScheme::new(4, 2, 1).needs_rehash(&hash));
+        assert!(PasswordScheme::new(4, 1, 2).needs_rehash(&hash));
+    }
+
+    /// The default policy of the configuration.
+    fn policy() -> PasswordPolicy {
+        PasswordPolicy::new(10, 3, 40, true, true)
+    }
+
+    #[test]
+    fn strong_password() {
+        assert!(policy().check("Tr0ub4dor&3x!", "alice", "alice@example.com").is_empty());
+    }
+
+    #[test]
+    fn short_password() {
+        let failed = policy().check("Ab1!x", "alice", "alice@example.com");
+        assert!(failed.contains(&PasswordRule::MinLength(10)));
+    }
+
+    #[test]
+    fn character_classes() {
+        let failed = policy().check("onlylowercaseletters", "alice", "alice@example.com");
+        assert!(failed.contains(&PasswordRule::CharacterClasses(3)));
+        let failed = policy().check("Lowercase and UPPER 42", "alice", "alice@example.com");
+        assert!(!failed.contains(&PasswordRule::CharacterClasses(3)));
+    }
+
+    #[test]
+    fn personal_information() {
+        let failed = policy().check("xX-Alice-2016-Xx", "alice", "bob@example.com");
+        assert!(failed.contains(&PasswordRule::ContainsUsername));
+        let failed = policy().check("xX-Bobby-2016-Xx", "alice", "bobby@example.com");
+        assert!(failed.contains(&PasswordRule::ContainsEmail));
+
+        let lenient = PasswordPolicy::new(10, 3, 40, false, true);
+        assert!(lenient.check("xX-Alice-2016-Xx", "alice", "alice@example.com").is_empty());
+    }
+
+    #[test]
+    fn repeated_characters_are_weak() {
+        let failed = policy().check("Aa1!Aa1!Aa1!Aa1!", "alice", "alice@example.com");
+        assert!(failed.contains(&PasswordRule::MinEntropy(40)));
+    }
+
+    #[test]
+    fn common_password() {
+        let failed = policy().check("password", "alice", "alice@example.com");
+        assert!(failed.contains(&PasswordRule::CommonPassword));
+        assert_eq!(failed[0].get_code(), "min_length");
+    }
+}
@@ -0,0 +1,128 @@
+//! This module contains the SMS providers used to send the phone verification codes.
+//!
+//! Providers implement the `SmsSender` trait. The `LogSmsSender` writes the messages to a file,
+//! for local testing, and the `WebhookSmsSender` posts them as JSON to an HTTP endpoint, that
+//! can forward them to any SMS gateway.
+
+use std::fs::OpenOptions;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use chrono::UTC;
+use hyper::Client;
+use hyper::header::{Authorization, Bearer, ContentType};
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+
+use error::{Error, Result};
+
+/// The available SMS providers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SmsProvider {
+    /// Writes the messages to a file, `LogSmsSender`.
+    Log,
+    /// Posts the messages to an HTTP endpoint, `WebhookSmsSender`.
+    Webhook,
+}
+
+impl FromStr for SmsProvider {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<SmsProvider, ()> {
+        match s.to_lowercase().as_str() {
+            "log" => Ok(SmsProvider::Log),
+            "webhook" => Ok(SmsProvider::Webhook),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Provider that sends SMS messages.
+pub trait SmsSender: Send + Sync {
+    /// Sends the message to the given phone number, in E.164 format.
+    fn send(&self, phone: &str, message: &str) -> Result<()>;
+}
+
+/// SMS provider that appends the messages to a file instead of sending them.
+pub struct LogSmsSender {
+    /// Path of the file.
+    path: PathBuf,
+}
+
+impl LogSmsSender {
+    /// Creates a new SMS logger writing to the given file.
+    pub fn new<P: Into<PathBuf>>(path: P) -> LogSmsSender {
+        LogSmsSender { path: path.into() }
+    }
+}
+
+impl SmsSender for LogSmsSender {
+    fn send(&self, phone: &str, message: &str) -> Result<()> {
+        let mut file = try!(OpenOptions::new().create(true).append(true).open(&self.path));
+        try!(writeln!(file, "{} to {}: {}", UTC::now(), phone, message));
+        Ok(())
+    }
+}
+
+/// Body posted to the SMS webhook.
+#[derive(Debug, Clone, RustcEncodable)]
+struct SmsWebhookRequest<'a> {
+    /// The phone number, in E.164 format.
+    to: &'a str,
+    /// The text of the message.
+    message: &'a str,
+}
+
+/// SMS provider that posts the messages to an HTTP webhook.
+pub struct WebhookSmsSender {
+    /// URL of the webhook.
+    url: String,
+    /// Bearer token sent to the webhook, if any.
+    token: Option<String>,
+}
+
+impl WebhookSmsSender {
+    /// Creates a new webhook SMS provider.
+    pub fn new<S: Into<String>>(url: S, token: Option<String>) -> WebhookSmsSender {
+        WebhookSmsSender {
+            url: url.into(),
+            token: token,
+        }
+    }
+}
+
+impl SmsSender for WebhookSmsSender {
+    fn send(&self, phone: &str, message: &str) -> Result<()> {
+        let body = json::encode(&SmsWebhookRequest {
+                to: phone,
+                message: message,
+            })
+            .unwrap();
+        let client = Client::new();
+        let mut request = client.post(&self.url)
+            .header(ContentType::json())
+            .body(&body);
+        if let Some(ref token) = self.token {
+            request = request.header(Authorization(Bearer { token: token.clone() }));
+        }
+
+        let mut response = match request.send() {
+            Ok(r) => r,
+            Err(e) => return Err(Error::SmsError(format!("{}", e))),
+        };
+        if response.status.is_success() {
+            Ok(())
+        } else {
+            let mut error = String::new();
+            let _ = response.read_to_string(&mut error);
+            Err(Error::SmsError(format!("the SMS webhook returned {}: {}", response.status, error)))
+        }
+    }
+}
+
+/// Generates a random numeric verification code with the given number of digits.
+pub fn generate_code(digits: u32) -> String {
+    let mut rng = thread_rng();
+    (0..digits).map(|_| (b'0' + rng.gen_range(0, 10)) as char).collect()
+}
@@ -0,0 +1,158 @@
+//! This module contains the blob stores used to keep the files uploaded by the users, such as
+//! identity verification documents.
+//!
+//! Stores implement the `BlobStore` trait. The `FileBlobStore` keeps the blobs in a local
+//! directory, and the `HttpBlobStore` stores them in an HTTP blob service, with `PUT`, `GET` and
+//! `DELETE` requests to `{url}/{key}`.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use hyper::Client;
+use hyper::client::RequestBuilder;
+use hyper::header::{Authorization, Bearer};
+use hyper::status::StatusCode;
+
+use error::{Error, Result};
+
+/// The available blob stores.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlobStoreKind {
+    /// Local directory, `FileBlobStore`.
+    File,
+    /// HTTP blob service, `HttpBlobStore`.
+    Http,
+}
+
+impl FromStr for BlobStoreKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> ::std::result::Result<BlobStoreKind, ()> {
+        match s.to_lowercase().as_str() {
+            "file" => Ok(BlobStoreKind::File),
+            "http" => Ok(BlobStoreKind::Http),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Storage of binary blobs by key.
+///
+/// Keys are generated by the server, and are made of ASCII letters, digits and `/`.
+pub trait BlobStore: Send + Sync {
+    /// Stores the blob with the given key, replacing it if it exists.
+    fn put(&self, key: &str, data: &[u8]) -> Result<()>;
+
+    /// Gets the blob with the given key.
+    fn get(&self, key: &str) -> Result<Vec<u8>>;
+
+    /// Deletes the blob with the given key, if it exists.
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Blob store that keeps the blobs as files in a local directory.
+pub struct FileBlobStore {
+    /// The root directory.
+    root: PathBuf,
+}
+
+impl FileBlobStore {
+    /// Creates a new blob store in the given directory.
+    pub fn new<P: Into<PathBuf>>(root: P) -> FileBlobStore {
+        FileBlobStore { root: root.into() }
+    }
+}
+
+impl BlobStore for FileBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            try!(fs::create_dir_all(parent));
+        }
+        let mut file = try!(fs::File::create(path));
+        try!(file.write_all(data));
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let mut file = try!(fs::File::open(self.root.join(key)));
+        let mut data = Vec::new();
+        let _ = try!(file.read_to_end(&mut data));
+        Ok(data)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let path = self.root.join(key);
+        if path.exists() {
+            try!(fs::remove_file(path));
+        }
+        Ok(())
+    }
+}
+
+/// Blob store backed by an HTTP blob service.
+pub struct HttpBlobStore {
+    /// Base URL of the service.
+    url: String,
+    /// Bearer token sent to the service, if any.
+    token: Option<String>,
+}
+
+impl HttpBlobStore {
+    /// Creates a new HTTP blob store with the given base URL.
+    pub fn new<S: Into<String>>(url: S, token: Option<String>) -> HttpBlobStore {
+        HttpBlobStore {
+            url: url.into(),
+            token: token,
+        }
+    }
+
+    /// Returns the URL of the blob with the given key.
+    fn blob_url(&self, key: &str) -> String {
+        format!("{}/{}", self.url.trim_right_matches('/'), key)
+    }
+
+    /// Adds the authorization to the request and sends it, returning the response body.
+    fn send(&self, request: RequestBuilder, allow_not_found: bool) -> Result<Vec<u8>> {
+        let request = match self.token {
+            Some(ref token) => request.header(Authorization(Bearer { token: token.clone() })),
+            None => request,
+        };
+        let mut response = match request.send() {
+            Ok(r) => r,
+            Err(e) => return Err(Error::StorageError(format!("{}", e))),
+        };
+        let mut body = Vec::new();
+        let _ = try!(response.read_to_end(&mut body));
+        if response.status.is_success() ||
+           (allow_not_found && response.status == StatusCode::NotFound) {
+            Ok(body)
+        } else {
+            Err(Error::StorageError(format!("the blob store returned {}", response.status)))
+        }
+    }
+}
+
+impl BlobStore for HttpBlobStore {
+    fn put(&self, key: &str, data: &[u8]) -> Result<()> {
+        let client = Client::new();
+        let url = self.blob_url(key);
+        let _ = try!(self.send(client.put(&url).body(data), false));
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> Result<Vec<u8>> {
+        let client = Client::new();
+        let url = self.blob_url(key);
+        self.send(client.get(&url), false)
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        let client = Client::new();
+        let url = self.blob_url(key);
+        let _ = try!(self.send(client.delete(&url), true));
+        Ok(())
+    }
+}
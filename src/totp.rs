@@ -0,0 +1,276 @@
+//! This module implements the time based one time passwords (RFC 6238) used for two factor
+//! authentication.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crypto::digest::Digest;
+use crypto::hmac::Hmac;
+use crypto::mac::Mac;
+use crypto::sha1::Sha1;
+use crypto::sha2::{Sha256, Sha512};
+use byteorder::{BigEndian, ByteOrder};
+
+/// Minimum number of digits of the codes.
+pub const MIN_DIGITS: u32 = 6;
+/// Maximum number of digits of the codes. Longer codes would not fit in the 31 bits taken from
+/// the HMAC.
+pub const MAX_DIGITS: u32 = 8;
+
+/// HMAC algorithm used to generate the codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TotpAlgorithm {
+    /// HMAC-SHA1, the only one supported by most authenticator apps.
+    Sha1,
+    /// HMAC-SHA256.
+    Sha256,
+    /// HMAC-SHA512.
+    Sha512,
+}
+
+impl fmt::Display for TotpAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   TotpAlgorithm::Sha1 => "SHA1",
+                   TotpAlgorithm::Sha256 => "SHA256",
+                   TotpAlgorithm::Sha512 => "SHA512",
+               })
+    }
+}
+
+impl FromStr for TotpAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<TotpAlgorithm, ()> {
+        match s.to_uppercase().as_str() {
+            "SHA1" => Ok(TotpAlgorithm::Sha1),
+            "SHA256" => Ok(TotpAlgorithm::Sha256),
+            "SHA512" => Ok(TotpAlgorithm::Sha512),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Time based one time password generator and verifier.
+#[derive(Debug, Clone, Copy)]
+pub struct Totp {
+    /// HMAC algorithm of the codes.
+    algorithm: TotpAlgorithm,
+    /// Number of digits of the generated codes.
+    digits: u32,
+    /// Seconds each code is valid for.
+    period: u64,
+    /// Number of time steps before and after the current one that are also accepted.
+    skew: u64,
+}
+
+impl Totp {
+    /// Creates a new TOTP generator.
+    ///
+    /// The digits must be between `MIN_DIGITS` and `MAX_DIGITS`, and the period must not be 0.
+    pub fn new(algorithm: TotpAlgorithm, digits: u32, period: u64, skew: u64) -> Totp {
+        Totp {
+            algorithm: algorithm,
+            digits: digits,
+            period: period,
+            skew: skew,
+        }
+    }
+
+    /// Parses the settings stored with `to_settings()`, using the given clock skew.
+    pub fn from_settings<S: AsRef<str>>(settings: S, skew: u64) -> Option<Totp> {
+        let parts = settings.as_ref().split(':').collect::<Vec<_>>();
+        if parts.len() != 3 {
+            return None;
+        }
+        match (parts[0].parse(), parts[1].parse::<u32>(), parts[2].parse::<u64>()) {
+            (Ok(algorithm), Ok(digits), Ok(period)) if digits >= MIN_DIGITS &&
+                                                        digits <= MAX_DIGITS &&
+                                                        period > 0 => {
+                Some(Totp::new(algorithm, digits, period, skew))
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the algorithm, digits and period, in the form `SHA1:6:30`, to store them.
+    ///
+    /// The clock skew is not included, since it does not change the generated codes.
+    pub fn to_settings(&self) -> String {
+        format!("{}:{}:{}", self.algorithm, self.digits, self.period)
+    }
+
+    /// Returns the same generator with a different clock skew.
+    pub fn with_skew(&self, skew: u64) -> Totp {
+        Totp { skew: skew, ..*self }
+    }
+
+    /// Returns the HMAC algorithm of the codes.
+    pub fn get_algorithm(&self) -> TotpAlgorithm {
+        self.algorithm
+    }
+
+    /// Returns the number of digits of the codes.
+    pub fn get_digits(&self) -> u32 {
+        self.digits
+    }
+
+    /// Returns the seconds each code is valid for.
+    pub fn get_period(&self) -> u64 {
+        self.period
+    }
+
+    /// Returns the number of time steps before and after the current one that are accepted.
+    pub fn get_skew(&self) -> u64 {
+        self.skew
+    }
+
+    /// Returns the time step for the given UNIX timestamp.
+    pub fn get_time_step(&self, timestamp: u64) -> u64 {
+        timestamp / self.period
+    }
+
+    /// Generates the code for the given secret and time step.
+    pub fn generate(&self, secret: &[u8], time_step: u64) -> u32 {
+        let mut counter = [0u8; 8];
+        BigEndian::write_u64(&mut counter, time_step);
+
+        let hash = match self.algorithm {
+            TotpAlgorithm::Sha1 => hmac(Sha1::new(), secret, &counter),
+            TotpAlgorithm::Sha256 => hmac(Sha256::new(), secret, &counter),
+            TotpAlgorithm::Sha512 => hmac(Sha512::new(), secret, &counter),
+        };
+
+        let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+        let binary = ((hash[offset] & 0x7f) as u32) << 24 | (hash[offset + 1] as u32) << 16 |
+                     (hash[offset + 2] as u32) << 8 | hash[offset + 3] as u32;
+        binary % 10u32.pow(self.digits)
+    }
+
+    /// Verifies the code for the given secret at the given UNIX timestamp.
+    ///
+    /// It returns the time step the code belongs to if it is valid. Time steps up to
+    /// `last_time_step` are rejected, so that an accepted code cannot be used again.
+    pub fn verify(&self,
+                  secret: &[u8],
+                  code: u32,
+                  timestamp: u64,
+                  last_time_step: Option<u64>)
+                  -> Option<u64> {
+        let current = self.get_time_step(timestamp);
+        for time_step in current.saturating_sub(self.skew)..current + self.skew + 1 {
+            if let Some(last) = last_time_step {
+                if time_step <= last {
+                    continue;
+                }
+            }
+            if self.generate(secret, time_step) == code {
+                return Some(time_step);
+            }
+        }
+        None
+    }
+}
+
+/// Computes the HMAC of the message with the given digest.
+fn hmac<D: Digest>(digest: D, key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(digest, key);
+    hmac.input(message);
+    hmac.result().code().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Totp, TotpAlgorithm};
+
+    /// Secret of the SHA-1 test vectors of RFC 6238.
+    const SHA1_SECRET: &'static [u8] = b"12345678901234567890";
+    /// Secret of the SHA-256 test vectors of RFC 6238.
+    const SHA256_SECRET: &'static [u8] = b"12345678901234567890123456789012";
+    /// Secret of the SHA-512 test vectors of RFC 6238.
+    const SHA512_SECRET: &'static [u8] = b"1234567890123456789012345678901234567890123456789012\
+                                            345678901234";
+
+    /// Test vectors of RFC 6238, appendix B: the time and the SHA-1, SHA-256 and SHA-512 codes.
+    const VECTORS: [(u64, u32, u32, u32); 6] = [(59, 94287082, 46119246, 90693936),
+                                                (1111111109, 7081804, 68084774, 25091201),
+                                                (1111111111, 14050471, 67062674, 99943326),
+                                                (1234567890, 89005924, 91819424, 93441116),
+                                                (2000000000, 69279037, 90698825, 38618901),
+                                                (20000000000, 65353130, 77737706, 47863826)];
+
+    #[test]
+    fn rfc_6238_vectors() {
+        let sha1 = Totp::new(TotpAlgorithm::Sha1, 8, 30, 0);
+        let sha256 = Totp::new(TotpAlgorithm::Sha256, 8, 30, 0);
+        let sha512 = Totp::new(TotpAlgorithm::Sha512, 8, 30, 0);
+        for &(time, sha1_code, sha256_code, sha512_code) in VECTORS.iter() {
+            assert_eq!(sha1.generate(SHA1_SECRET, sha1.get_time_step(time)), sha1_code);
+            assert_eq!(sha256.generate(SHA256_SECRET, sha256.get_time_step(time)),
+                       sha256_code);
+            assert_eq!(sha512.generate(SHA512_SECRET, sha512.get_time_step(time)),
+                       sha512_code);
+        }
+    }
+
+    #[test]
+    fn six_digit_codes() {
+        let totp = Totp::new(TotpAlgorithm::Sha1, 6, 30, 0);
+        assert_eq!(totp.generate(SHA1_SECRET, totp.get_time_step(59)), 287082);
+        assert_eq!(totp.generate(SHA1_SECRET, totp.get_time_step(1111111109)), 81804);
+    }
+
+    #[test]
+    fn verify_with_skew() {
+        let totp = Totp::new(TotpAlgorithm::Sha1, 8, 30, 1);
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109, None),
+                   Some(37037036));
+        // The code of the previous and the next time steps are accepted.
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109 + 30, None),
+                   Some(37037036));
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109 - 30, None),
+                   Some(37037036));
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109 + 60, None), None);
+        assert_eq!(totp.with_skew(0).verify(SHA1_SECRET, 7081804, 1111111109 + 30, None),
+                   None);
+    }
+
+    #[test]
+    fn verify_rejects_used_time_steps() {
+        let totp = Totp::new(TotpAlgorithm::Sha1, 8, 30, 1);
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109, Some(37037035)),
+                   Some(37037036));
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109, Some(37037036)),
+                   None);
+        assert_eq!(totp.verify(SHA1_SECRET, 7081804, 1111111109, Some(37037037)),
+                   None);
+    }
+
+    #[test]
+    fn settings() {
+        let totp = Totp::new(TotpAlgorithm::Sha256, 8, 60, 1);
+        assert_eq!(totp.to_settings(), "SHA256:8:60");
+
+        let parsed = Totp::from_settings("SHA256:8:60", 2).unwrap();
+        assert_eq!(parsed.get_algorithm(), TotpAlgorithm::Sha256);
+        assert_eq!(parsed.get_digits(), 8);
+        assert_eq!(parsed.get_period(), 60);
+        assert_eq!(parsed.get_skew(), 2);
+
+        assert!(Totp::from_settings("SHA256:8", 1).is_none());
+        assert!(Totp::from_settings("MD5:6:30", 1).is_none());
+        assert!(Totp::from_settings("SHA1:10:30", 1).is_none());
+        assert!(Totp::from_settings("SHA1:5:30", 1).is_none());
+        assert!(Totp::from_settings("SHA1:6:0", 1).is_none());
+    }
+
+    #[test]
+    fn algorithm_names() {
+        assert_eq!("sha1".parse(), Ok(TotpAlgorithm::Sha1));
+        assert_eq!("SHA512".parse(), Ok(TotpAlgorithm::Sha512));
+        assert_eq!("SHA3".parse::<TotpAlgorithm>(), Err(()));
+        assert_eq!(format!("{}", TotpAlgorithm::Sha256), "SHA256");
+    }
+}
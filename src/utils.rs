@@ -5,6 +5,7 @@ use std::io::{Read, Write};
 use std::sync::{Arc, Mutex};
 
 use byteorder::{NetworkEndian, ByteOrder};
+use chrono::{DateTime, NaiveDateTime, Timelike, UTC};
 
 use ENCRYPTION_SERVERS;
 use error::{Error, Result};
@@ -96,20 +97,67 @@ impl EncryptionClient {
     }
 }
 
-/// Whether the email being sent is a password reset or email confirmation email
+/// The kind of email being sent to the user
 pub enum EmailType {
     /// An email confirmation email
     Email,
     /// A password reset email
     Password,
+    /// A passwordless login link
+    MagicLink,
+    /// A notification that the account was locked until the given time after too many failed
+    /// login or authenticator attempts
+    Lockout(DateTime<UTC>),
+    /// A notification that the password of the account was changed
+    PasswordChanged,
+    /// A confirmation of a new email address, sent to the new address
+    EmailChange,
+    /// A notice sent to the old address that the email is being changed to the given one, with
+    /// the key to cancel the change
+    EmailChangeNotice(String),
+    /// A notice of a login from a device or IP address never seen before, with the user agent
+    /// and the IP address of the login
+    NewDeviceLogin(String, String),
+    /// A notice that the account will be deleted at the given time, with the key to cancel the
+    /// deletion
+    AccountDeletion(DateTime<UTC>),
+    /// A link to download the export of the personal data of the user, valid until the given time
+    DataExport(DateTime<UTC>),
 }
 
 /// The basics needed to generate an email and send it to the user
 pub struct EmailStruct {
     /// the email address the email is being sent to
     pub email: String,
-    /// The key for the email
+    /// The key for the email, empty for notification emails
     pub email_key: String,
     /// The type of email being generated and sent
     pub email_type: EmailType,
 }
+
+/// Returns the milliseconds since the UNIX epoch of the given time.
+pub fn to_millis(time: &DateTime<UTC>) -> i64 {
+    time.timestamp() * 1000 + (time.nanosecond() / 1_000_000) as i64 % 1000
+}
+
+/// Returns the time of the given milliseconds since the UNIX epoch, that cannot be negative.
+pub fn from_millis(millis: i64) -> DateTime<UTC> {
+    let nanos = (millis % 1000) as u32 * 1_000_000;
+    DateTime::from_utc(NaiveDateTime::from_timestamp(millis / 1000, nanos), UTC)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, NaiveDateTime, UTC};
+    use super::{to_millis, from_millis};
+
+    #[test]
+    fn millis() {
+        let time = DateTime::from_utc(NaiveDateTime::from_timestamp(1476817410, 123456789), UTC);
+        assert_eq!(to_millis(&time), 1476817410123);
+        assert_eq!(from_millis(1476817410123),
+                   DateTime::from_utc(NaiveDateTime::from_timestamp(1476817410, 123000000), UTC));
+        assert_eq!(to_millis(&from_millis(0)), 0);
+        assert!(from_millis(1476817410124) > time);
+    }
+}
@@ -0,0 +1,406 @@
+//! Admin module. It contains the administrative actions on user accounts and the assignment of
+//! roles, that are recorded in the audit log.
+use std::io::Read;
+
+use iron::prelude::*;
+use iron::status;
+
+use chrono::{DateTime, UTC, NaiveDate, NaiveDateTime};
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+use rustc_serialize::base64::{ToBase64, URL_SAFE};
+use dto::ResponseDTO;
+
+use {DATABASES, EMAILS, CONFIG};
+use database::{AuditAction, AuditEvent, AuditFilter, Permission, Role, User};
+use error::{Error, Result};
+use utils::{EmailStruct, EmailType};
+use super::{audit_event, record_audit_event, invalid_field};
+use super::auth::{require_token, require_permission};
+use super::types::{AdminActionDTO, AuditEventDTO, AuditEventsDTO, RolesDTO};
+
+/// Number of audit log events returned when no limit is given.
+const DEFAULT_AUDIT_LIMIT: usize = 100;
+/// Maximum number of audit log events returned at once.
+const MAX_AUDIT_LIMIT: usize = 1000;
+
+/// Bans the given user.
+///
+/// - Method: `POST`
+/// - URL: `/ban_user/:user_id`
+/// - Scopes: the `users:ban` permission
+/// - Returns: an `OK` status code if the user was banned, or a `NotFound` status code if a user
+///   with the given ID was not found.
+///
+/// The `AdminActionDTO` must be in the body. The user is banned until the `until` time, or
+/// indefinitely if it is not set, and all of its tokens are revoked.
+pub fn ban_user(req: &mut Request) -> IronResult<Response> {
+    admin_action(req, AuditAction::BanUser, "user banned", |user, dto, event| {
+        let until = match dto.until {
+            // The expiry was checked to be a valid time in `admin_action`.
+            Some(until) => NaiveDateTime::from_timestamp_opt(until, 0).unwrap(),
+            None => NaiveDate::from_ymd(9999, 12, 31).and_hms(23, 59, 59),
+        };
+        let until = DateTime::from_utc(until, UTC);
+        event.before = user.get_banned().map(|banned| banned.to_rfc3339());
+        event.after = Some(until.to_rfc3339());
+        try!(user.ban(until));
+        user.revoke_sessions()
+    })
+}
+
+/// Lifts the ban of the given user.
+///
+/// - Method: `POST`
+/// - URL: `/unban_user/:user_id`
+/// - Scopes: the `users:ban` permission
+/// - Returns: an `OK` status code if the ban was lifted, or a `NotFound` status code if a user
+///   with the given ID was not found.
+///
+/// The `AdminActionDTO` must be in the body.
+pub fn unban_user(req: &mut Request) -> IronResult<Response> {
+    admin_action(req, AuditAction::UnbanUser, "user unbanned", |user, _, event| {
+        event.before = user.get_banned().map(|banned| banned.to_rfc3339());
+        user.unban()
+    })
+}
+
+/// Enables the account of the given user.
+///
+/// - Method: `POST`
+/// - URL: `/enable_user/:user_id`
+/// - Scopes: the `users:write` permission
+/// - Returns: an `OK` status code if the account was enabled, or a `NotFound` status code if a
+///   user with the given ID was not found.
+///
+/// The `AdminActionDTO` must be in the body.
+pub fn enable_user(req: &mut Request) -> IronResult<Response> {
+    admin_action(req, AuditAction::EnableUser, "user enabled", |user, _, _| user.enable())
+}
+
+/// Disables the account of the given user.
+///
+/// - Method: `POST`
+/// - URL: `/disable_user/:user_id`
+/// - Scopes: the `users:write` permission
+/// - Returns: an `OK` status code if the account was disabled, or a `NotFound` status code if a
+///   user with the given ID was not found.
+///
+/// The `AdminActionDTO` must be in the body. All the tokens of the user are revoked.
+pub fn disable_user(req: &mut Request) -> IronResult<Response> {
+    admin_action(req, AuditAction::DisableUser, "user disabled", |user, _, _| {
+        try!(user.disable());
+        user.revoke_sessions()
+    })
+}
+
+/// Logs the given user out of all of its sessions.
+///
+/// - Method: `POST`
+/// - URL: `/force_logout/:user_id`
+/// - Scopes: the `users:write` permission
+/// - Returns: an `OK` status code if the tokens were revoked, or a `NotFound` status code if a
+///   user with the given ID was not found.
+///
+/// The `AdminActionDTO` must be in the body. All the tokens issued to the user until now stop
+/// working.
+pub fn force_logout(req: &mut Request) -> IronResult<Response> {
+    admin_action(req, AuditAction::ForceLogout, "user logged out", |user, _, _| {
+        user.revoke_sessions()
+    })
+}
+
+/// Forces the given user to reset its password.
+///
+/// - Method: `POST`
+/// - URL: `/force_password_reset/:user_id`
+/// - Scopes: the `users:write` permission
+/// - Returns: an `OK` status code if the password was invalidated, or a `NotFound` status code if
+///   a user with the given ID was not found.
+///
+/// The `AdminActionDTO` must be in the body. The current password stops working, all the tokens of
+/// the user are revoked, and a password reset email is sent to the user.
+pub fn force_password_reset(req: &mut Request) -> IronResult<Response> {
+    admin_action(req, AuditAction::ForcePasswordReset, "password reset forced", |user, _, _| {
+        let mut password = [0u8; 32];
+        thread_rng().fill_bytes(&mut password[0..]);
+        try!(user.set_password(&CONFIG.get_password_scheme(), password.to_base64(URL_SAFE)));
+        try!(user.revoke_sessions());
+
+        let mut reset_password_key = [0u8; 24];
+        thread_rng().fill_bytes(&mut reset_password_key[0..]);
+        let reset_password_str = reset_password_key.to_base64(URL_SAFE);
+        try!(user.start_reset_password(&reset_password_str, CONFIG.get_reset_password_ttl()));
+        let email = EmailStruct {
+            email: String::from(user.get_email()),
+            email_key: reset_password_str,
+            email_type: EmailType::Password,
+        };
+        EMAILS.lock().unwrap().push(email);
+        Ok(())
+    })
+}
+
+/// Queries the audit log.
+///
+/// - Method: `GET`
+/// - URL: `/audit_log`
+/// - Scopes: the `audit:read` permission
+/// - Returns: an `AuditEventsDTO` with the latest events matching the query, newest first.
+///
+/// The optional `user_id`, `app_id` and `action` query parameters filter the events by the user
+/// they affect, the app that performed them and the action. `from` and `to` are UNIX timestamps
+/// limiting the time range, and `limit` the number of events returned, up to 1000.
+pub fn audit_log(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::AuditRead));
+    let mut res = Response::new();
+
+    let mut filter = AuditFilter::default();
+    if let Some(user_id) = query_param!(req, "user_id") {
+        filter.user_id = Some(itry!(user_id.parse::<u64>(), status::BadRequest));
+    }
+    filter.app_id = query_param!(req, "app_id");
+    if let Some(action) = query_param!(req, "action") {
+        match action.parse() {
+            Ok(action) => filter.action = Some(action),
+            Err(_) => return invalid_field("action", format!("unknown action '{}'", action)),
+        }
+    }
+    if let Some(from) = query_param!(req, "from") {
+        let from = itry!(from.parse::<i64>(), status::BadRequest);
+        filter.from = Some(DateTime::from_utc(NaiveDateTime::from_timestamp(from, 0), UTC));
+    }
+    if let Some(to) = query_param!(req, "to") {
+        let to = itry!(to.parse::<i64>(), status::BadRequest);
+        filter.to = Some(DateTime::from_utc(NaiveDateTime::from_timestamp(to, 0), UTC));
+    }
+    let limit = match query_param!(req, "limit") {
+        Some(limit) => itry!(limit.parse::<usize>(), status::BadRequest),
+        None => DEFAULT_AUDIT_LIMIT,
+    };
+    if limit == 0 || limit > MAX_AUDIT_LIMIT {
+        return invalid_field("limit",
+                             format!("the limit must be between 1 and {}", MAX_AUDIT_LIMIT));
+    }
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let events = itry!(db.get_audit_events(&filter, limit));
+    let dto = AuditEventsDTO { events: events.iter().map(AuditEventDTO::new).collect() };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Gets the roles of the given user.
+///
+/// - Method: `GET`
+/// - URL: `/user_roles/:user_id`
+/// - Scopes: the `users:read` permission
+/// - Returns: a `RolesDTO` with the roles of the user, or a `NotFound` status code if a user with
+///   the given ID was not found.
+pub fn get_user_roles(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::UsersRead));
+    let mut res = Response::new();
+    let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if itry!(db.get_user_by_id(user_id)).is_none() {
+        return Err(Error::UserDoesNotExist.into());
+    }
+    let dto = RolesDTO {
+        roles: itry!(db.get_user_roles(user_id)).iter().map(|role| format!("{}", role)).collect(),
+    };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Sets the roles of the given user.
+///
+/// - Method: `POST`
+/// - URL: `/user_roles/:user_id`
+/// - Scopes: the `roles:write` permission
+/// - Returns: an `OK` status code if the roles were changed, an `UnprocessableEntity` status code
+///   if one of the roles does not exist, or a `NotFound` status code if a user with the given ID
+///   was not found.
+///
+/// The `RolesDTO` must be in the body. The roles replace the current roles of the user, and apply
+/// to all of its tokens from then on.
+pub fn set_user_roles(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::RolesWrite));
+    let mut res = Response::new();
+    let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
+    let mut body = String::new();
+    let _ = itry!(req.body.read_to_string(&mut body));
+    let dto = itry!(json::decode::<RolesDTO>(&body), status::BadRequest);
+    let roles = match parse_roles(&dto) {
+        Ok(roles) => roles,
+        Err(role) => return invalid_field("roles", format!("unknown role '{}'", role)),
+    };
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if itry!(db.get_user_by_id(user_id)).is_none() {
+        return Err(Error::UserDoesNotExist.into());
+    }
+    let mut event = audit_event(req, token.get_app_id(), Some(user_id), AuditAction::RolesChange);
+    event.before = Some(format_roles(&itry!(db.get_user_roles(user_id))));
+    event.after = Some(format_roles(&roles));
+    itry!(db.set_user_roles(user_id, &roles));
+    record_audit_event(db, &event);
+
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("user roles changed")).unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Gets the roles of the given OAuth client.
+///
+/// - Method: `GET`
+/// - URL: `/client_roles/:client_id`
+/// - Scopes: the `users:read` permission
+/// - Returns: a `RolesDTO` with the roles of the client, or a `NotFound` status code if the client
+///   does not exist.
+pub fn get_client_roles(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::UsersRead));
+    let mut res = Response::new();
+    let client_id = param!(req, "client_id");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if itry!(db.get_client(&client_id)).is_none() {
+        return Err(Error::ClientDoesNotExist.into());
+    }
+    let dto = RolesDTO {
+        roles: itry!(db.get_client_roles(&client_id))
+            .iter()
+            .map(|role| format!("{}", role))
+            .collect(),
+    };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Sets the roles of the given OAuth client.
+///
+/// - Method: `POST`
+/// - URL: `/client_roles/:client_id`
+/// - Scopes: the `roles:write` permission
+/// - Returns: an `OK` status code if the roles were changed, an `UnprocessableEntity` status code
+///   if one of the roles does not exist, or a `NotFound` status code if the client does not exist.
+///
+/// The `RolesDTO` must be in the body. The roles replace the current roles of the client, and
+/// apply to the tokens of the client that are not issued to a user.
+pub fn set_client_roles(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::RolesWrite));
+    let mut res = Response::new();
+    let client_id = param!(req, "client_id");
+    let mut body = String::new();
+    let _ = itry!(req.body.read_to_string(&mut body));
+    let dto = itry!(json::decode::<RolesDTO>(&body), status::BadRequest);
+    let roles = match parse_roles(&dto) {
+        Ok(roles) => roles,
+        Err(role) => return invalid_field("roles", format!("unknown role '{}'", role)),
+    };
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if itry!(db.get_client(&client_id)).is_none() {
+        return Err(Error::ClientDoesNotExist.into());
+    }
+    let mut event = audit_event(req, token.get_app_id(), None, AuditAction::RolesChange);
+    event.before = Some(format!("{}: {}",
+                                client_id,
+                                format_roles(&itry!(db.get_client_roles(&client_id)))));
+    event.after = Some(format!("{}: {}", client_id, format_roles(&roles)));
+    itry!(db.set_client_roles(&client_id, &roles));
+    record_audit_event(db, &event);
+
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("client roles changed")).unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Performs the `action` on the user in the `user_id` parameter and records it in the audit log.
+///
+/// The token must grant the permission needed for the action, and the body must contain an
+/// `AdminActionDTO` with a reason. The action can add the values it changes to the audit event.
+fn admin_action<F>(req: &mut Request,
+                   action: AuditAction,
+                   message: &str,
+                   perform: F)
+                   -> IronResult<Response>
+    where F: FnOnce(&mut User, &AdminActionDTO, &mut AuditEvent) -> Result<()>
+{
+    let token = try!(require_token(req));
+    try!(require_permission(&token, action_permission(&action)));
+    let mut res = Response::new();
+
+    let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
+    let mut body = String::new();
+    let _ = itry!(req.body.read_to_string(&mut body));
+    let dto = itry!(json::decode::<AdminActionDTO>(&body), status::BadRequest);
+
+    if dto.reason.trim().is_empty() {
+        return invalid_field("reason", "a reason is required");
+    }
+    if let Some(until) = dto.until {
+        if NaiveDateTime::from_timestamp_opt(until, 0).is_none() {
+            return invalid_field("until", "the ban expiry is not a valid time");
+        }
+        if until <= UTC::now().timestamp() {
+            return invalid_field("until", "the ban expiry must be in the future");
+        }
+    }
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match db.get_user_by_id(user_id) {
+        Ok(Some(mut user)) => {
+            let mut event = audit_event(req, token.get_app_id(), Some(user_id), action);
+            event.reason = Some(String::from(dto.reason.trim()));
+            if let Err(e) = perform(&mut user, &dto, &mut event) {
+                println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+                itry!(Err(e));
+            }
+            record_audit_event(db, &event);
+            let _ = res.set_mut(json::encode(&ResponseDTO::new(message)).unwrap())
+                .set_mut(status::Ok);
+        }
+        Ok(None) => return Err(Error::UserDoesNotExist.into()),
+        Err(e) => {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+            itry!(Err(e));
+        }
+    }
+
+    Ok(res)
+}
+
+/// Returns the permission needed to perform the given action on a user.
+fn action_permission(action: &AuditAction) -> Permission {
+    match *action {
+        AuditAction::BanUser | AuditAction::UnbanUser => Permission::UsersBan,
+        _ => Permission::UsersWrite,
+    }
+}
+
+/// Parses the roles of the `RolesDTO`, without duplicates, or returns the first unknown role.
+fn parse_roles(dto: &RolesDTO) -> ::std::result::Result<Vec<Role>, String> {
+    let mut roles = Vec::with_capacity(dto.roles.len());
+    for role in &dto.roles {
+        match role.parse::<Role>() {
+            Ok(role) => {
+                if !roles.contains(&role) {
+                    roles.push(role);
+                }
+            }
+            Err(_) => return Err(role.clone()),
+        }
+    }
+    Ok(roles)
+}
+
+/// Formats the roles as a comma separated list for the audit log.
+fn format_roles(roles: &[Role]) -> String {
+    roles.iter().map(|role| format!("{}", role)).collect::<Vec<_>>().join(", ")
+}
@@ -0,0 +1,168 @@
+//! Authentication module. It contains the middleware that checks the bearer token of each request
+//! once, and the extractors the handlers use to get the checked token.
+use std::error::Error as StdErr;
+use std::fmt;
+
+use iron::prelude::*;
+use iron::{status, BeforeMiddleware};
+use iron::headers::{Authorization, Bearer};
+use iron::typemap::Key;
+
+use database::Permission;
+use super::oauth::AccessToken;
+
+/// Middleware that decodes and validates the bearer token of the requests.
+///
+/// Valid tokens are stored in the request extensions, and can be retrieved with the extractors of
+/// this module. Requests with an invalid, expired or revoked token are answered with a `401
+/// Unauthorized` status code, and requests without a token are left for the handlers to decide.
+pub struct Authentication;
+
+impl Key for Authentication {
+    type Value = AccessToken;
+}
+
+impl BeforeMiddleware for Authentication {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let token = match req.headers.get::<Authorization<Bearer>>() {
+            Some(auth) => {
+                match AccessToken::from_token(&auth.0.token) {
+                    Ok(token) => token,
+                    Err(_) => return Err(AuthError::InvalidToken.into()),
+                }
+            }
+            None => return Ok(()),
+        };
+        if token.has_expired() {
+            return Err(AuthError::ExpiredToken.into());
+        }
+        if itry!(token.is_revoked()) {
+            return Err(AuthError::RevokedToken.into());
+        }
+        let _ = req.extensions.insert::<Authentication>(token);
+        Ok(())
+    }
+}
+
+/// Reasons for a request not to be authorized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthError {
+    /// The request has no bearer token.
+    MissingToken,
+    /// The bearer token could not be decoded.
+    InvalidToken,
+    /// The bearer token has expired.
+    ExpiredToken,
+    /// The bearer token has been revoked.
+    RevokedToken,
+    /// The token is valid, but does not give access to the resource.
+    InsufficientScope,
+}
+
+impl AuthError {
+    /// Returns the stable code of the error.
+    pub fn get_code(&self) -> &'static str {
+        match *self {
+            AuthError::MissingToken => "missing_token",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::ExpiredToken => "expired_token",
+            AuthError::RevokedToken => "revoked_token",
+            AuthError::InsufficientScope => "insufficient_scope",
+        }
+    }
+
+    /// Returns the HTTP status code of the error.
+    pub fn get_status(&self) -> status::Status {
+        match *self {
+            AuthError::InsufficientScope => status::Forbidden,
+            _ => status::Unauthorized,
+        }
+    }
+
+    /// Returns the `WWW-Authenticate` challenge of the error, as described in RFC 6750.
+    pub fn get_challenge(&self) -> String {
+        match *self {
+            AuthError::MissingToken => String::from("Bearer"),
+            AuthError::InsufficientScope => {
+                format!("Bearer error=\"insufficient_scope\", error_description=\"{}\"",
+                        self.description())
+            }
+            _ => {
+                format!("Bearer error=\"invalid_token\", error_description=\"{}\"",
+                        self.description())
+            }
+        }
+    }
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.description())
+    }
+}
+
+impl StdErr for AuthError {
+    fn description(&self) -> &str {
+        match *self {
+            AuthError::MissingToken => "a bearer token must be provided",
+            AuthError::InvalidToken => "the token is not valid",
+            AuthError::ExpiredToken => "the token has expired",
+            AuthError::RevokedToken => "the token has been revoked",
+            AuthError::InsufficientScope => "token does not have correct permissions",
+        }
+    }
+}
+
+/// Creates the response of the error with its `WWW-Authenticate` challenge. The body of the
+/// response is added by the `ErrorResponses` middleware.
+impl From<AuthError> for IronError {
+    fn from(error: AuthError) -> IronError {
+        let mut res = Response::with(error.get_status());
+        res.headers.set_raw("WWW-Authenticate", vec![error.get_challenge().into_bytes()]);
+        IronError {
+            error: Box::new(error),
+            response: res,
+        }
+    }
+}
+
+/// Returns the token of the request, or a `401 Unauthorized` error if it has none.
+pub fn require_token(req: &Request) -> IronResult<AccessToken> {
+    match req.extensions.get::<Authentication>() {
+        Some(token) => Ok(token.clone()),
+        None => Err(AuthError::MissingToken.into()),
+    }
+}
+
+/// Returns the `Public` scoped token of the request, or an error if it has no token or the token
+/// is not `Public` scoped.
+pub fn require_public(req: &Request) -> IronResult<AccessToken> {
+    let token = try!(require_token(req));
+    if token.is_public() {
+        Ok(token)
+    } else {
+        Err(AuthError::InsufficientScope.into())
+    }
+}
+
+/// Returns the `User` scoped token of the request along with the ID of its user, or an error if
+/// it has no token or the token is not issued to a user.
+pub fn require_user(req: &Request) -> IronResult<(AccessToken, u64)> {
+    let token = try!(require_token(req));
+    match token.get_user_id() {
+        Some(user_id) => Ok((token, user_id)),
+        None => Err(AuthError::InsufficientScope.into()),
+    }
+}
+
+/// Returns a `403 Forbidden` error if the token does not grant the given permission.
+///
+/// `Admin` scoped tokens have all the permissions, so this also takes the place of requiring an
+/// admin token.
+pub fn require_permission(token: &AccessToken, permission: Permission) -> IronResult<()> {
+    if itry!(token.has_permission(permission)) {
+        Ok(())
+    } else {
+        Err(AuthError::InsufficientScope.into())
+    }
+}
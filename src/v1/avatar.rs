@@ -0,0 +1,206 @@
+//! Avatar module. It contains the methods for users to upload their profile images, that are
+//! cropped, resized and kept in the blob store, and to serve them.
+use std::cmp;
+use std::io::{Cursor, Read};
+
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+
+use image::{self, ColorType, FilterType, GenericImage, ImageDecoder, ImageFormat};
+use image::gif;
+use image::jpeg::JPEGDecoder;
+use image::png::{PNGDecoder, PNGEncoder};
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+use dto::ResponseDTO;
+
+use {DATABASES, BLOBS};
+use error::{Error, Result};
+use super::auth::require_user;
+use super::types::AvatarDTO;
+
+/// Maximum size of the uploaded images, in bytes.
+const MAX_AVATAR_SIZE: usize = 5 * 1024 * 1024;
+/// Maximum width and height of the uploaded images, in pixels.
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+/// Widths of the square avatars stored for each upload, in pixels, from smallest to largest.
+const AVATAR_SIZES: [u32; 3] = [64, 128, 256];
+
+/// Uploads a new profile image for the user.
+///
+/// - Method: `POST`
+/// - URL: `/avatar`
+/// - Scopes: `User`
+/// - Returns: an `AvatarDTO` with the URL of the new profile image if successful, a
+///   `PayloadTooLarge` status code if the image is bigger than 5 MiB, or an `UnprocessableEntity`
+///   status code if it is not a valid image.
+///
+/// The body must be a PNG, JPEG or GIF image of up to 5 MiB. It is cropped to a centered square
+/// and stored as PNG in each of the avatar sizes, without any of the metadata of the upload. The
+/// largest size becomes the profile image of the user, and the previous avatar is deleted.
+pub fn upload_avatar(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let mut data = Vec::new();
+    let _ = itry!((&mut req.body).take(MAX_AVATAR_SIZE as u64 + 1).read_to_end(&mut data));
+    if data.len() > MAX_AVATAR_SIZE {
+        return Err(Error::PayloadTooLarge.into());
+    }
+    let avatars = match resize_avatar(&data) {
+        Ok(Some(avatars)) => avatars,
+        Ok(None) => return Err(Error::InvalidImage.into()),
+        Err(e) => {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+            itry!(Err(e));
+        }
+    };
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let mut user = match itry!(db.get_user_by_id(user_id)) {
+        Some(user) => user,
+        None => return Err(Error::UserDoesNotExist.into()),
+    };
+
+    // The version is part of the keys of the blobs, so it can only have letters and digits.
+    let version = thread_rng().gen_ascii_chars().take(16).collect::<String>();
+    for (&size, avatar) in AVATAR_SIZES.iter().zip(avatars) {
+        itry!(BLOBS.put(&avatar_blob_key(user_id, &version, size), &avatar));
+    }
+    let image_url = format!("/avatars/{}/{}/{}",
+                            user_id,
+                            version,
+                            AVATAR_SIZES[AVATAR_SIZES.len() - 1]);
+    if let Some(old_version) = itry!(user.set_avatar(version.as_str(), image_url.as_str())) {
+        itry!(delete_avatar_blobs(user_id, &old_version));
+    }
+
+    let dto = AvatarDTO {
+        image_url: image_url,
+        sizes: AVATAR_SIZES.to_vec(),
+    };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Removes the profile image of the user.
+///
+/// - Method: `DELETE`
+/// - URL: `/avatar`
+/// - Scopes: `User`
+/// - Returns: a successful response once the profile image is removed.
+pub fn delete_avatar(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match itry!(db.get_user_by_id(user_id)) {
+        Some(mut user) => {
+            if let Some(version) = itry!(user.remove_avatar()) {
+                itry!(delete_avatar_blobs(user_id, &version));
+            }
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("profile image removed")).unwrap())
+                .set_mut(status::Ok);
+        }
+        None => return Err(Error::UserDoesNotExist.into()),
+    }
+    Ok(res)
+}
+
+/// Gets an avatar image.
+///
+/// - Method: `GET`
+/// - URL: `/avatars/:user_id/:version/:size`
+/// - Scopes: none, no token is needed
+/// - Returns: the PNG image, or a `NotFound` status code if it is not the current avatar of the
+///   user or the size is not one of the avatar sizes.
+pub fn get_avatar(req: &mut Request) -> IronResult<Response> {
+    let mut res = Response::new();
+    let user_id = param!(req, "user_id").parse::<u64>().ok();
+    let version = param!(req, "version");
+    let size = param!(req, "size").parse::<u32>().ok();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let current = match user_id {
+        Some(user_id) => itry!(db.get_user_avatar(user_id)),
+        None => None,
+    };
+    match (user_id, size) {
+        (Some(user_id), Some(size)) if current == Some(version.clone()) &&
+                                       AVATAR_SIZES.contains(&size) => {
+            let data = itry!(BLOBS.get(&avatar_blob_key(user_id, &version, size)));
+            let _ = res.set_mut(data)
+                .set_mut("image/png".parse::<Mime>().unwrap())
+                .set_mut(status::Ok);
+        }
+        _ => return Err(Error::NotFound.into()),
+    }
+    Ok(res)
+}
+
+/// Deletes the images of the given avatar version of the user from the blob store.
+pub fn delete_avatar_blobs(user_id: u64, version: &str) -> Result<()> {
+    for &size in AVATAR_SIZES.iter() {
+        try!(BLOBS.delete(&avatar_blob_key(user_id, version, size)));
+    }
+    Ok(())
+}
+
+/// Returns the key of the avatar image of the given size in the blob store.
+fn avatar_blob_key(user_id: u64, version: &str, size: u32) -> String {
+    format!("avatars/{}/{}/{}", user_id, version, size)
+}
+
+/// Crops the image to a centered square and encodes it as PNG in each of the avatar sizes.
+///
+/// `None` is returned if the data is not a PNG, JPEG or GIF image, or if it is too big. Since the
+/// images are decoded and encoded again, none of their metadata is kept.
+fn resize_avatar(data: &[u8]) -> Result<Option<Vec<Vec<u8>>>> {
+    let format = match image::guess_format(data) {
+        Ok(format) => format,
+        Err(_) => return Ok(None),
+    };
+    // The size is checked before decoding, since a small file can hold a huge image.
+    let (width, height) = match header_dimensions(data, format) {
+        Some(dimensions) => dimensions,
+        None => return Ok(None),
+    };
+    if width == 0 || height == 0 || width > MAX_AVATAR_DIMENSION ||
+       height > MAX_AVATAR_DIMENSION {
+        return Ok(None);
+    }
+    let mut image = match image::load_from_memory_with_format(data, format) {
+        Ok(image) => image,
+        Err(_) => return Ok(None),
+    };
+    if image.dimensions() != (width, height) {
+        return Ok(None);
+    }
+
+    let side = cmp::min(width, height);
+    let square = image.crop((width - side) / 2, (height - side) / 2, side, side);
+    let mut avatars = Vec::with_capacity(AVATAR_SIZES.len());
+    for &size in AVATAR_SIZES.iter() {
+        let resized = square.resize_exact(size, size, FilterType::Lanczos3).to_rgba();
+        let mut png = Vec::new();
+        if PNGEncoder::new(&mut png)
+            .encode(&resized, size, size, ColorType::RGBA(8))
+            .is_err() {
+            return Err(Error::FailedCreatePNG);
+        }
+        avatars.push(png);
+    }
+    Ok(Some(avatars))
+}
+
+/// Reads the width and height of a PNG, JPEG or GIF image from its header, without decoding it.
+fn header_dimensions(data: &[u8], format: ImageFormat) -> Option<(u32, u32)> {
+    let dimensions = match format {
+        ImageFormat::PNG => PNGDecoder::new(Cursor::new(data)).dimensions(),
+        ImageFormat::JPEG => JPEGDecoder::new(Cursor::new(data)).dimensions(),
+        ImageFormat::GIF => gif::Decoder::new(Cursor::new(data)).dimensions(),
+        _ => return None,
+    };
+    dimensions.ok()
+}
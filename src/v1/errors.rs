@@ -0,0 +1,432 @@
+//! Errors module. It contains the middleware that gives an ID to each request and turns the errors
+//! of the handlers into responses with a stable error code, a localized message and the ID of the
+//! request.
+use std::{cmp, str};
+
+use iron::prelude::*;
+use iron::{status, AfterMiddleware, BeforeMiddleware};
+use iron::modifier::Modifier;
+use iron::typemap::Key;
+
+use chrono::UTC;
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+
+use error::Error;
+use super::auth::AuthError;
+use super::types::{ErrorDTO, ValidationErrorDTO, PasswordPolicyErrorDTO};
+
+/// Header with the ID of the request in the responses.
+const REQUEST_ID_HEADER: &'static str = "X-Request-Id";
+/// Header with the seconds until a locked out user can try again.
+const RETRY_AFTER_HEADER: &'static str = "Retry-After";
+
+/// Messages of the error codes, in English and Spanish.
+static MESSAGES: [(&'static str, &'static str, &'static str); 51] =
+    [("io_error",
+      "the server could not read or write a file",
+      "el servidor no ha podido leer o escribir un archivo"),
+     ("invalid_utf8", "the data is not valid UTF-8 text", "los datos no son texto UTF-8 válido"),
+     ("invalid_base64", "the data is not valid base64", "los datos no son base64 válido"),
+     ("database_error", "the database is not available", "la base de datos no está disponible"),
+     ("password_error",
+      "the password could not be checked",
+      "no se ha podido comprobar la contraseña"),
+     ("upstream_error",
+      "an external service did not respond correctly",
+      "un servicio externo no ha respondido correctamente"),
+     ("request_limit_reached",
+      "the client reached its request limit, try again later",
+      "el cliente ha alcanzado su límite de peticiones, inténtelo más tarde"),
+     ("no_scopes",
+      "no scopes were given for the client",
+      "no se han indicado permisos para el cliente"),
+     ("client_not_found", "the client does not exist", "el cliente no existe"),
+     ("connection_exists", "the connection already exists", "la conexión ya existe"),
+     ("client_exists", "the client already exists", "el cliente ya existe"),
+     ("invalid_key", "the key is not valid or has expired", "la clave no es válida o ha caducado"),
+     ("image_error", "the image could not be created", "no se ha podido crear la imagen"),
+     ("user_not_found", "the user does not exist", "el usuario no existe"),
+     ("email_exists",
+      "the email is already in use by another user",
+      "el correo electrónico ya está en uso por otro usuario"),
+     ("sms_error", "the SMS could not be sent", "no se ha podido enviar el SMS"),
+     ("storage_error",
+      "the file could not be stored or read",
+      "no se ha podido guardar o leer el archivo"),
+     ("verification_not_pending",
+      "the verification request was already reviewed",
+      "la solicitud de verificación ya ha sido revisada"),
+     ("verification_outdated",
+      "the verified fields changed after the request was submitted",
+      "los campos verificados han cambiado después de enviar la solicitud"),
+     ("username_exists",
+      "the username is already in use by another user",
+      "el nombre de usuario ya está en uso por otro usuario"),
+     ("invalid_credentials",
+      "the username, email, password or client secret are not correct",
+      "el nombre de usuario, el correo electrónico, la contraseña o el secreto del cliente no \
+       son correctos"),
+     ("invalid_code",
+      "the code is not correct or has expired",
+      "el código no es correcto o ha caducado"),
+     ("user_banned", "the user is banned", "el usuario está suspendido"),
+     ("user_disabled", "the user account is disabled", "la cuenta del usuario está desactivada"),
+     ("user_locked",
+      "the user is locked out after too many failed attempts, try again later",
+      "el usuario está bloqueado tras demasiados intentos fallidos, inténtelo más tarde"),
+     ("two_factor_enabled",
+      "two factor authentication is already enabled",
+      "la autenticación en dos pasos ya está activada"),
+     ("two_factor_not_enabled",
+      "two factor authentication is not enabled",
+      "la autenticación en dos pasos no está activada"),
+     ("phone_confirmed", "the phone is already confirmed", "el teléfono ya está confirmado"),
+     ("no_phone", "the user has no phone number", "el usuario no tiene número de teléfono"),
+     ("sms_limit_reached",
+      "too many codes were sent to this number, try again later",
+      "se han enviado demasiados códigos a este número, inténtelo más tarde"),
+     ("payload_too_large", "the request is too big", "la petición es demasiado grande"),
+     ("invalid_image",
+      "the image must be a PNG, JPEG or GIF file of up to 4096x4096 pixels",
+      "la imagen debe ser un archivo PNG, JPEG o GIF de hasta 4096x4096 píxeles"),
+     ("export_pending",
+      "an export is already being prepared",
+      "ya se está preparando una exportación"),
+     ("verification_pending",
+      "a verification request is already pending",
+      "ya hay una solicitud de verificación pendiente"),
+     ("validation_error", "some fields are not valid", "algunos campos no son válidos"),
+     ("weak_password",
+      "the password does not meet the password policy",
+      "la contraseña no cumple la política de contraseñas"),
+     ("missing_token",
+      "a bearer token must be provided",
+      "se debe proporcionar un token de portador"),
+     ("invalid_token", "the token is not valid", "el token no es válido"),
+     ("expired_token", "the token has expired", "el token ha caducado"),
+     ("revoked_token", "the token has been revoked", "el token ha sido revocado"),
+     ("insufficient_scope",
+      "the token does not have the required permissions",
+      "el token no tiene los permisos necesarios"),
+     ("bad_request", "the request is not valid", "la petición no es válida"),
+     ("unauthorized", "authentication is required", "se requiere autenticación"),
+     ("forbidden", "the request is not allowed", "la petición no está permitida"),
+     ("not_found", "the resource was not found", "no se ha encontrado el recurso"),
+     ("method_not_allowed",
+      "the method is not allowed for the resource",
+      "el método no está permitido para el recurso"),
+     ("conflict",
+      "the request conflicts with the current state of the resource",
+      "la petición entra en conflicto con el estado actual del recurso"),
+     ("unsupported_media_type",
+      "the format of the body is not supported",
+      "el formato del cuerpo no está soportado"),
+     ("too_many_requests",
+      "too many requests, try again later",
+      "demasiadas peticiones, inténtelo más tarde"),
+     ("client_error",
+      "the request could not be processed",
+      "no se ha podido procesar la petición"),
+     ("internal_error", "an unexpected error occurred", "se ha producido un error inesperado")];
+
+/// Languages of the error messages.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Language {
+    /// English, the default language.
+    English,
+    /// Spanish.
+    Spanish,
+}
+
+impl Language {
+    /// Returns the supported language the request prefers, according to its `Accept-Language`
+    /// header, or English if it does not accept any of them.
+    pub fn from_request(req: &Request) -> Language {
+        let header = match req.headers.get_raw("Accept-Language") {
+            Some(values) => {
+                values.iter()
+                    .filter_map(|value| str::from_utf8(value).ok())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            }
+            None => return Language::English,
+        };
+
+        let mut language = Language::English;
+        let mut quality = 0.0;
+        for item in header.split(',') {
+            let mut params = item.split(';');
+            let tag = params.next().unwrap_or("").trim().to_lowercase();
+            let item_quality = params.filter_map(|param| {
+                    let param = param.trim();
+                    if param.starts_with("q=") {
+                        param[2..].parse::<f32>().ok()
+                    } else {
+                        None
+                    }
+                })
+                .next()
+                .unwrap_or(1.0);
+            let item_language = match tag.split('-').next() {
+                Some("en") => Language::English,
+                Some("es") => Language::Spanish,
+                _ => continue,
+            };
+            if item_quality > quality {
+                language = item_language;
+                quality = item_quality;
+            }
+        }
+        language
+    }
+}
+
+/// Returns the message of the given error code in the given language.
+pub fn get_message(code: &str, language: Language) -> &'static str {
+    let &(_, english, spanish) = MESSAGES.iter()
+        .find(|&&(message_code, _, _)| message_code == code)
+        .unwrap_or(&MESSAGES[MESSAGES.len() - 1]);
+    match language {
+        Language::English => english,
+        Language::Spanish => spanish,
+    }
+}
+
+/// Sets the `Retry-After` header for the errors that end at a known time, such as a lockout.
+fn set_retry_after(error: &Error, res: &mut Response) {
+    if let Error::UserLocked(until) = *error {
+        let seconds = cmp::max((until - UTC::now()).num_seconds(), 0);
+        res.headers.set_raw(RETRY_AFTER_HEADER, vec![format!("{}", seconds).into_bytes()]);
+    }
+}
+
+/// Sets the status code of the error and an `ErrorDTO` body with its code and English message.
+impl Modifier<Response> for Error {
+    fn modify(self, res: &mut Response) {
+        let code = self.get_code();
+        let dto = ErrorDTO {
+            code: String::from(code),
+            message: String::from(get_message(code, Language::English)),
+            request_id: None,
+        };
+        set_retry_after(&self, res);
+        let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(self.get_status());
+    }
+}
+
+/// Lets the handlers return the errors with `try!`. The body of the response is added by the
+/// `ErrorResponses` middleware.
+impl From<Error> for IronError {
+    fn from(error: Error) -> IronError {
+        let status = error.get_status();
+        let mut response = Response::with(status);
+        set_retry_after(&error, &mut response);
+        IronError {
+            error: Box::new(error),
+            response: response,
+        }
+    }
+}
+
+/// Sets an `UnprocessableEntity` status code and the body with the errors of each field.
+impl Modifier<Response> for ValidationErrorDTO {
+    fn modify(self, res: &mut Response) {
+        let _ = res.set_mut(json::encode(&self).unwrap()).set_mut(status::UnprocessableEntity);
+    }
+}
+
+/// Sets an `UnprocessableEntity` status code and the body with the failed password rules.
+impl Modifier<Response> for PasswordPolicyErrorDTO {
+    fn modify(self, res: &mut Response) {
+        let _ = res.set_mut(json::encode(&self).unwrap()).set_mut(status::UnprocessableEntity);
+    }
+}
+
+/// Middleware that gives a random ID to each request, to find it in the logs.
+pub struct RequestId;
+
+impl Key for RequestId {
+    type Value = String;
+}
+
+impl BeforeMiddleware for RequestId {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let request_id = thread_rng().gen_ascii_chars().take(16).collect::<String>();
+        let _ = req.extensions.insert::<RequestId>(request_id);
+        Ok(())
+    }
+}
+
+/// Middleware that adds the ID of the request to the responses, and turns the errors of the
+/// handlers into `ErrorDTO` responses with the status code of the error.
+///
+/// The errors of this crate and the authentication errors have their own codes. Other errors,
+/// such as the ones of the body or parameter parsing, keep their status and get a generic code
+/// for it.
+pub struct ErrorResponses;
+
+impl AfterMiddleware for ErrorResponses {
+    fn after(&self, req: &mut Request, mut res: Response) -> IronResult<Response> {
+        if let Some(request_id) = req.extensions.get::<RequestId>() {
+            res.headers.set_raw(REQUEST_ID_HEADER, vec![request_id.clone().into_bytes()]);
+        }
+        Ok(res)
+    }
+
+    fn catch(&self, req: &mut Request, mut err: IronError) -> IronResult<Response> {
+        let (code, status) = get_code_and_status(&err);
+        let request_id = req.extensions.get::<RequestId>().cloned();
+        if status.is_server_error() {
+            println!("Error: {:?}, request: {}",
+                     err.error,
+                     request_id.as_ref().map(|id| id.as_str()).unwrap_or("-"));
+        }
+
+        let dto = ErrorDTO {
+            code: String::from(code),
+            message: String::from(get_message(code, Language::from_request(req))),
+            request_id: request_id.clone(),
+        };
+        let _ = err.response.set_mut(json::encode(&dto).unwrap()).set_mut(status);
+        if let Some(request_id) = request_id {
+            err.response.headers.set_raw(REQUEST_ID_HEADER, vec![request_id.into_bytes()]);
+        }
+        Err(err)
+    }
+}
+
+/// Returns the error code and the status code of the response for the error.
+fn get_code_and_status(err: &IronError) -> (&'static str, status::Status) {
+    if let Some(error) = err.error.downcast_ref::<Error>() {
+        (error.get_code(), error.get_status())
+    } else if let Some(error) = err.error.downcast_ref::<AuthError>() {
+        (error.get_code(), error.get_status())
+    } else {
+        match err.response.status {
+            Some(status) if status.is_client_error() || status.is_server_error() => {
+                (get_status_code(status), status)
+            }
+            _ => ("internal_error", status::InternalServerError),
+        }
+    }
+}
+
+/// Returns the generic error code of an error status code.
+fn get_status_code(status: status::Status) -> &'static str {
+    match status {
+        status::BadRequest => "bad_request",
+        status::Unauthorized => "unauthorized",
+        status::Forbidden => "forbidden",
+        status::NotFound => "not_found",
+        status::MethodNotAllowed => "method_not_allowed",
+        status::Conflict => "conflict",
+        status::PayloadTooLarge => "payload_too_large",
+        status::UnsupportedMediaType => "unsupported_media_type",
+        status::UnprocessableEntity => "validation_error",
+        status::TooManyRequests => "too_many_requests",
+        status if status.is_client_error() => "client_error",
+        _ => "internal_error",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use iron::prelude::*;
+    use iron::status;
+    use chrono::{Duration, UTC};
+
+    use error::Error;
+    use super::super::auth::AuthError;
+    use super::{MESSAGES, Language, get_message, get_code_and_status};
+
+    #[test]
+    fn crate_error_codes() {
+        let err: IronError = Error::UserDoesNotExist.into();
+        assert_eq!(get_code_and_status(&err), ("user_not_found", status::NotFound));
+        let err: IronError = Error::SmsLimitReached.into();
+        assert_eq!(get_code_and_status(&err),
+                   ("sms_limit_reached", status::TooManyRequests));
+
+        let err: IronError = AuthError::ExpiredToken.into();
+        assert_eq!(get_code_and_status(&err), ("expired_token", status::Unauthorized));
+    }
+
+    #[test]
+    fn other_error_codes() {
+        let error = || io::Error::new(io::ErrorKind::Other, "other");
+        let err = IronError::new(error(), status::BadRequest);
+        assert_eq!(get_code_and_status(&err), ("bad_request", status::BadRequest));
+        let err = IronError::new(error(), status::Forbidden);
+        assert_eq!(get_code_and_status(&err), ("forbidden", status::Forbidden));
+        let err = IronError::new(error(), status::PayloadTooLarge);
+        assert_eq!(get_code_and_status(&err),
+                   ("payload_too_large", status::PayloadTooLarge));
+        let err = IronError::new(error(), status::ImATeapot);
+        assert_eq!(get_code_and_status(&err), ("client_error", status::ImATeapot));
+        let err = IronError::new(error(), status::ServiceUnavailable);
+        assert_eq!(get_code_and_status(&err),
+                   ("internal_error", status::ServiceUnavailable));
+
+        let err = IronError::new(error(), status::Ok);
+        assert_eq!(get_code_and_status(&err),
+                   ("internal_error", status::InternalServerError));
+        let err = IronError::new(error(), ());
+        assert_eq!(get_code_and_status(&err),
+                   ("internal_error", status::InternalServerError));
+    }
+
+    #[test]
+    fn messages() {
+        assert_eq!(get_message("user_not_found", Language::English),
+                   "the user does not exist");
+        assert_eq!(get_message("forbidden", Language::Spanish),
+                   "la petición no está permitida");
+        assert_eq!(get_message("unknown_code", Language::English),
+                   "an unexpected error occurred");
+
+        let errors = vec![Error::RequestLimitReached,
+                          Error::ClientDoesNotExist,
+                          Error::IncorrectKey,
+                          Error::UserDoesNotExist,
+                          Error::EmailExists,
+                          Error::UsernameExists,
+                          Error::InvalidCredentials,
+                          Error::IncorrectCode,
+                          Error::UserBanned,
+                          Error::UserDisabled,
+                          Error::UserLocked(UTC::now()),
+                          Error::TwoFactorEnabled,
+                          Error::TwoFactorNotEnabled,
+                          Error::PhoneConfirmed,
+                          Error::NoPhone,
+                          Error::SmsLimitReached,
+                          Error::PayloadTooLarge,
+                          Error::InvalidImage,
+                          Error::ExportPending,
+                          Error::VerificationPending,
+                          Error::VerificationNotPending,
+                          Error::VerificationOutdated,
+                          Error::NotFound];
+        for error in errors {
+            let code = error.get_code();
+            assert!(MESSAGES.iter().any(|&(message_code, _, _)| message_code == code),
+                    "no message for {}",
+                    code);
+        }
+    }
+
+    #[test]
+    fn lockout_retry_after() {
+        let err: IronError = Error::UserLocked(UTC::now() + Duration::minutes(5)).into();
+        let retry_after = err.response.headers.get_raw("Retry-After").unwrap();
+        let seconds = String::from_utf8(retry_after[0].clone()).unwrap().parse::<i64>().unwrap();
+        assert!(seconds > 290 && seconds <= 300);
+
+        let err: IronError = Error::UserLocked(UTC::now() - Duration::minutes(5)).into();
+        let retry_after = err.response.headers.get_raw("Retry-After").unwrap();
+        assert_eq!(retry_after[0], b"0".to_vec());
+    }
+}
@@ -0,0 +1,166 @@
+//! Data export module. It contains the methods for users to get an export of all the personal
+//! data stored about them, that is prepared in the background and sent by email.
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+
+use chrono::UTC;
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+use dto::ResponseDTO;
+
+use {DATABASES, EMAILS, CONFIG, BLOBS};
+use database::{AuditAction, AuditFilter, Database, export_blob_key};
+use error::{Error, Result};
+use utils::{EmailStruct, EmailType};
+use super::{audit_event, record_audit_event};
+use super::auth::require_user;
+use super::types::{DataExportDTO, TwoFactorStateDTO, PendingEmailChangeDTO, SessionDTO,
+                   LoginAttemptDTO, VerificationDTO, AuditEventDTO, ProfileVisibilityDTO};
+
+/// Maximum number of audit log events of the user included in an export, the newest ones.
+const MAX_EXPORT_AUDIT_EVENTS: usize = 5000;
+
+/// Requests an export of the personal data of the user.
+///
+/// - Method: `POST`
+/// - URL: `/data_export`
+/// - Scopes: `User`
+/// - Returns: a successful response if the export was requested, or a `Conflict` status code if
+///   an export of the user is already being prepared.
+///
+/// The export is prepared in the background, and a link to download it is sent by email. The link
+/// is valid for a limited time.
+pub fn request_data_export(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if !itry!(db.queue_data_export(user_id)) {
+        return Err(Error::ExportPending.into());
+    }
+    let event =
+        audit_event(req, token.get_app_id(), Some(user_id), AuditAction::DataExportRequested);
+    record_audit_event(db, &event);
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("the export is being prepared, and a \
+                                                         download link will be sent by email"))
+            .unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Downloads the export of the personal data of the user.
+///
+/// - Method: `GET`
+/// - URL: `/data_export/:export_key`
+/// - Scopes: `User`
+/// - Returns: the `DataExportDTO` of the user, or a `NotFound` status code if the key is not valid,
+///   has expired or belongs to another user.
+pub fn download_data_export(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+    let export_key = param!(req, "export_key");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if itry!(db.get_data_export_user(&export_key)) != Some(user_id) {
+        return Err(Error::NotFound.into());
+    }
+
+    let data = itry!(BLOBS.get(&export_blob_key(user_id, &export_key)));
+    let event =
+        audit_event(req, token.get_app_id(), Some(user_id), AuditAction::DataExportDownloaded);
+    record_audit_event(db, &event);
+    let _ = res.set_mut(data)
+        .set_mut("application/json".parse::<Mime>().unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Prepares the queued data exports, stores them in the blob store and emails their download
+/// links to the users.
+pub fn prepare_data_exports() -> Result<()> {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    while let Some(user_id) = try!(db.next_data_export()) {
+        if let Err(e) = prepare_data_export(db, user_id) {
+            try!(db.abort_data_export(user_id));
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Deletes the data exports whose download links have expired from the blob store.
+pub fn delete_expired_data_exports() -> Result<()> {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    for blob_key in try!(db.get_expired_data_exports(UTC::now())) {
+        try!(BLOBS.delete(&blob_key));
+        try!(db.remove_data_export(&blob_key));
+    }
+    Ok(())
+}
+
+/// Prepares the data export of the given user.
+fn prepare_data_export(db: &Database, user_id: u64) -> Result<()> {
+    let user = match try!(db.get_user_by_id(user_id)) {
+        Some(user) => user,
+        None => return db.abort_data_export(user_id),
+    };
+
+    let totp = user.get_totp().cloned().unwrap_or(CONFIG.get_totp());
+    let two_factor = TwoFactorStateDTO {
+        enabled: user.is_two_factor_enabled(),
+        algorithm: format!("{}", totp.get_algorithm()),
+        digits: totp.get_digits(),
+        period: totp.get_period(),
+        recovery_codes_left: try!(user.get_recovery_codes_left()),
+    };
+    let pending_email_change = try!(user.get_pending_email_change()).map(|pending| {
+        PendingEmailChangeDTO {
+            user_id: user_id,
+            email: String::from(user.get_email()),
+            new_email: pending.new_email,
+            requested: pending.requested.timestamp(),
+        }
+    });
+    let profile_visibility = ProfileVisibilityDTO::new(&try!(user.get_profile_visibility()));
+    let filter = AuditFilter { user_id: Some(user_id), ..AuditFilter::default() };
+    let email = String::from(user.get_email());
+    let dto = DataExportDTO {
+        generated: UTC::now().timestamp(),
+        profile_visibility: profile_visibility,
+        two_factor: two_factor,
+        pending_email_change: pending_email_change,
+        sessions: try!(db.get_user_sessions(user_id))
+            .iter()
+            .map(|s| SessionDTO::new(s, false))
+            .collect(),
+        login_history: try!(db.get_login_history(user_id))
+            .iter()
+            .map(LoginAttemptDTO::new)
+            .collect(),
+        verifications: try!(db.get_user_verifications(user_id))
+            .iter()
+            .map(VerificationDTO::new)
+            .collect(),
+        audit_events: try!(db.get_audit_events(&filter, MAX_EXPORT_AUDIT_EVENTS))
+            .iter()
+            .map(AuditEventDTO::new)
+            .collect(),
+        user: user.into(),
+    };
+
+    // The key is part of the key of the blob, so it can only have letters and digits.
+    let export_key = thread_rng().gen_ascii_chars().take(32).collect::<String>();
+    let expires = UTC::now() + CONFIG.get_data_export_ttl();
+    try!(BLOBS.put(&export_blob_key(user_id, &export_key),
+                   json::encode(&dto).unwrap().as_bytes()));
+    try!(db.finish_data_export(user_id, &export_key, expires));
+
+    let email = EmailStruct {
+        email: email,
+        email_key: export_key,
+        email_type: EmailType::DataExport(expires),
+    };
+    EMAILS.lock().unwrap().push(email);
+    Ok(())
+}
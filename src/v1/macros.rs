@@ -1,37 +1,5 @@
 //! Macros module.
 
-#[macro_export]
-macro_rules! get_token {
-    ($req:ident) => (
-        match $req.headers.get::<::iron::headers::Authorization<::iron::headers::Bearer>>() {
-        Some(auth) => {
-            match ::v1::oauth::AccessToken::from_token(&auth.0.token) {
-                Ok(token) => if !token.has_expired() {
-                    token
-                } else {
-                    let mut res = ::iron::Response::new();
-                    let _ = res.set_mut(::rustc_serialize::json::encode(
-                                    &::dto::ResponseDTO::new("the token has expired")).unwrap())
-                               .set_mut(::iron::status::Forbidden);
-                    return Ok(res);
-                },
-                Err(_) => {
-                    let mut res = ::iron::Response::new();
-                    let _ = res.set_mut(::iron::status::BadRequest);
-                    return Ok(res);
-                }
-            }
-        }
-        None => {
-            let mut res = Response::new();
-            let _ = res.set_mut(json::encode(
-                            &ResponseDTO::new("a bearer token must be provided")).unwrap())
-                       .set_mut(::iron::status::Forbidden);
-            return Ok(res);
-        }
-    }
-)}
-
 #[macro_export]
 macro_rules! param {
     ($req: ident, $param: expr) => (
@@ -60,3 +28,25 @@ macro_rules! option_param {
         }
     )
 }
+
+#[macro_export]
+macro_rules! query_param {
+    ($req: ident, $param: expr) => (
+        match $req.url.query() {
+            Some(query) => {
+                query.split('&')
+                    .filter_map(|pair| {
+                        let mut key_value = pair.splitn(2, '=');
+                        match (key_value.next(), key_value.next()) {
+                            (Some(key), Some(value)) if ::v1::query_decode(key) == $param => {
+                                Some(::v1::query_decode(value))
+                            }
+                            _ => None,
+                        }
+                    })
+                    .next()
+            }
+            None => None
+        }
+    )
+}
@@ -1,68 +1,411 @@
 //! The first version module of the API
 
-use std::path::Path;
+use std::fmt;
+use std::result::Result as StdResult;
+use std::str::{self, FromStr};
 
 use iron::prelude::*;
 use mount::Mount;
 use router::Router;
-use staticfile::Static;
 
 use image;
+use image::ColorType;
+use image::png::PNGEncoder;
 use data_encoding::base32;
-use qrcode::QrCode;
+use qrcode::{QrCode, EcLevel};
+use rustc_serialize::base64::{ToBase64, STANDARD};
 
-use error::Result;
+use {EMAILS, CONFIG};
+use database::{AuditAction, AuditEvent, Database, User};
+use error::{Error, Result};
+use totp::Totp;
+use utils::{EmailStruct, EmailType};
+use validation::FieldError;
 
 #[macro_use]
 pub mod macros;
+pub mod admin;
+pub mod auth;
+pub mod avatar;
+pub mod errors;
+pub mod export;
 pub mod oauth;
+pub mod profile;
 pub mod public;
+pub mod session;
 pub mod user;
+pub mod verification;
+pub mod types;
 
+use self::admin::*;
+use self::auth::Authentication;
+use self::errors::{RequestId, ErrorResponses};
+use self::avatar::*;
+use self::export::*;
 use self::oauth::*;
+use self::profile::*;
 use self::public::*;
+use self::session::*;
+use self::types::ValidationErrorDTO;
 use self::user::*;
-
-/// where the Qrcodes are stored
-pub const QRCODES_PATH: &'static str = "qrcodes/";
+use self::verification::*;
 
 /// Routes the server.
-pub fn route_server() -> Iron<Mount> {
+///
+/// The bearer tokens of all the requests are checked by the `Authentication` middleware before
+/// they reach the handlers, and the errors are turned into `ErrorDTO` responses with the ID of
+/// the request by the `ErrorResponses` middleware.
+pub fn route_server() -> Iron<Chain> {
     let mut router = Router::new();
 
     // OAuth
     let _ = router.get("/v1/token", token)
                   .post("/v1/create_client", create_client)
+                  .post("/v1/client_totp_settings/:client_id", set_client_totp_settings)
                   // Public
                   .post("/v1/register", register)
                   .post("/v1/login", login)
+                  .post("/v1/login/mfa/:mfa_token", login_mfa)
+                  .post("/v1/login/recovery/:mfa_token", login_recovery)
+                  .post("/v1/start_magic_login", start_magic_login)
+                  .post("/v1/magic_login/:login_key", magic_login)
                   .post("/v1/start_reset_password", start_reset_password)
                   .post("/v1/reset_password/:pass_key", reset_password)
                   .post("/v1/confirm_email/:email_key", confirm_email)
+                  .post("/v1/confirm_email_change/:email_key", confirm_email_change)
+                  .post("/v1/cancel_email_change/:email_key", cancel_email_change)
+                  .post("/v1/cancel_account_deletion/:deletion_key", cancel_account_deletion)
+                  .get("/v1/profile/:user_id", get_profile)
+                  .get("/v1/profile/by-username/:username", get_profile_by_username)
                   // User
                   .get("/v1/user/:user_id", get_user)
                   .post("/v1/update_user/:user_id", update_user)
+                  .post("/v1/delete_account", delete_account)
+                  .post("/v1/data_export", request_data_export)
+                  .get("/v1/data_export/:export_key", download_data_export)
+                  .get("/v1/profile_visibility", get_profile_visibility)
+                  .post("/v1/avatar", upload_avatar)
+                  .delete("/v1/avatar", delete_avatar)
+                  .post("/v1/profile_visibility", set_profile_visibility)
                   .get("/v1/resend_email_confirmation", resend_email_confirmation)
                   .get("/v1/generate_authenticator_code", generate_authenticator_code)
-                  .post("/v1/authenticate", authenticate);
+                  .post("/v1/authenticate", authenticate)
+                  .post("/v1/enable_two_factor", enable_two_factor)
+                  .post("/v1/disable_two_factor", disable_two_factor)
+                  .post("/v1/regenerate_recovery_codes", regenerate_recovery_codes)
+                  .get("/v1/recovery_codes_left", recovery_codes_left)
+                  .post("/v1/send_phone_verification", send_phone_verification)
+                  .post("/v1/verify_phone", verify_phone)
+                  .post("/v1/verification", submit_verification)
+                  .get("/v1/verifications", get_verifications)
+                  .get("/v1/verification/:verification_id", get_verification)
+                  .get("/v1/sessions", get_sessions)
+                  .delete("/v1/session/:session_id", revoke_session)
+                  .post("/v1/revoke_other_sessions", revoke_other_sessions)
+                  .get("/v1/login_history/:user_id", get_login_history)
+                  // Admin
+                  .get("/v1/all_users", get_all_users)
+                  .delete("/v1/user/:user_id", delete_user)
+                  .post("/v1/unlock_user/:user_id", unlock_user)
+                  .post("/v1/ban_user/:user_id", ban_user)
+                  .post("/v1/unban_user/:user_id", unban_user)
+                  .post("/v1/enable_user/:user_id", enable_user)
+                  .post("/v1/disable_user/:user_id", disable_user)
+                  .post("/v1/force_logout/:user_id", force_logout)
+                  .post("/v1/force_password_reset/:user_id", force_password_reset)
+                  .get("/v1/audit_log", audit_log)
+                  .get("/v1/user_roles/:user_id", get_user_roles)
+                  .post("/v1/user_roles/:user_id", set_user_roles)
+                  .get("/v1/client_roles/:client_id", get_client_roles)
+                  .post("/v1/client_roles/:client_id", set_client_roles)
+                  .get("/v1/pending_email_changes", pending_email_changes)
+                  .get("/v1/pending_verifications", pending_verifications)
+                  .get("/v1/verification/:verification_id/attachments/:index",
+                       get_verification_attachment)
+                  .post("/v1/review_verification/:verification_id", review_verification);
+
+    let mut avatars = Router::new();
+    let _ = avatars.get("/:user_id/:version/:size", get_avatar);
 
     let mut mount = Mount::new();
-    let _ = mount.mount("/", router)
-        .mount("/qrcodes", Static::new(QRCODES_PATH));
+    let _ = mount.mount("/", router).mount("/avatars", avatars);
+
+    let mut chain = Chain::new(mount);
+    let _ = chain.link_before(RequestId)
+                 .link_before(Authentication)
+                 .link_after(ErrorResponses);
+
+    Iron::new(chain)
+}
+
+/// Output format of the authenticator QR codes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BarcodeFormat {
+    /// PNG image, returned as a `data:image/png;base64` URI.
+    Png,
+    /// SVG image, returned as a `data:image/svg+xml;base64` URI.
+    Svg,
+    /// Text made of block characters, to be printed in a terminal.
+    Ascii,
+}
+
+impl fmt::Display for BarcodeFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f,
+               "{}",
+               match *self {
+                   BarcodeFormat::Png => "png",
+                   BarcodeFormat::Svg => "svg",
+                   BarcodeFormat::Ascii => "ascii",
+               })
+    }
+}
+
+impl FromStr for BarcodeFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> StdResult<BarcodeFormat, ()> {
+        match s.to_lowercase().as_str() {
+            "png" => Ok(BarcodeFormat::Png),
+            "svg" => Ok(BarcodeFormat::Svg),
+            "ascii" | "terminal" => Ok(BarcodeFormat::Ascii),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Creates an audit event for the action performed by the given app in the request.
+pub fn audit_event(req: &Request,
+                   app_id: &str,
+                   user_id: Option<u64>,
+                   action: AuditAction)
+                   -> AuditEvent {
+    AuditEvent::new(app_id, Some(format!("{}", req.remote_addr.ip())), user_id, action)
+}
+
+/// Records the event in the audit log.
+///
+/// Events are recorded once the action has already been performed, so a failure to store the
+/// event is logged instead of failing the request.
+pub fn record_audit_event(db: &Database, event: &AuditEvent) {
+    if let Err(e) = db.add_audit_event(event) {
+        println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+    }
+}
+
+/// Registers a failed password or second factor attempt of the user.
+///
+/// If the attempt locks the user out, the lockout is recorded in the audit log, with the given
+/// reason, and the user is emailed about it.
+pub fn register_failed_attempt(req: &Request,
+                               db: &Database,
+                               app_id: &str,
+                               user: &mut User,
+                               reason: &str)
+                               -> Result<()> {
+    if try!(user.register_failed_attempt(CONFIG.get_max_failed_logins(),
+                                         CONFIG.get_lockout_time())) {
+        let mut event = audit_event(req, app_id, Some(user.get_id()), AuditAction::Lockout);
+        event.reason = Some(String::from(reason));
+        event.after = user.get_locked().map(|l| l.to_rfc3339());
+        record_audit_event(db, &event);
+        let email = EmailStruct {
+            email: String::from(user.get_email()),
+            email_key: String::new(),
+            email_type: EmailType::Lockout(*user.get_locked().unwrap()),
+        };
+        EMAILS.lock().unwrap().push(email);
+    }
+    Ok(())
+}
+
+/// Returns the error for a user that is locked out, with the time the lock ends.
+pub fn locked_error(user: &User) -> IronError {
+    Error::UserLocked(*user.get_locked().unwrap()).into()
+}
+
+/// Creates an `UnprocessableEntity` response for a single field that is not valid.
+pub fn invalid_field<S: Into<String>>(field: &'static str, message: S) -> IronResult<Response> {
+    Ok(Response::with(ValidationErrorDTO::new(&[FieldError::new(field, message)])))
+}
+
+/// Parses a QR code error correction level, one of `L`, `M`, `Q` or `H`.
+pub fn parse_ec_level<S: AsRef<str>>(level: S) -> Option<EcLevel> {
+    match level.as_ref().to_uppercase().as_str() {
+        "L" => Some(EcLevel::L),
+        "M" => Some(EcLevel::M),
+        "Q" => Some(EcLevel::Q),
+        "H" => Some(EcLevel::H),
+        _ => None,
+    }
+}
+
+/// Gets the `otpauth://` URI with the TOTP data of the given secret for the given email.
+///
+/// The algorithm, digits and period in the URI must be the ones the secret was enrolled with, so
+/// that the authenticator app generates the codes the server expects.
+pub fn get_authenticator_uri<S: AsRef<str>, I: AsRef<str>, E: AsRef<str>>(secret: S,
+                                                                          issuer: I,
+                                                                          user_email: E,
+                                                                          totp: &Totp)
+                                                                          -> String {
+    let issuer = uri_encode(issuer.as_ref());
+    format!("otpauth://totp/{}:{}?secret={}&issuer={}&algorithm={}&digits={}&period={}",
+            issuer,
+            uri_encode(user_email.as_ref()),
+            base32::encode(secret.as_ref().as_bytes()),
+            issuer,
+            totp.get_algorithm(),
+            totp.get_digits(),
+            totp.get_period())
+}
+
+/// Percent-encodes everything but the unreserved characters of the given URI component.
+fn uri_encode(component: &str) -> String {
+    let mut encoded = String::with_capacity(component.len());
+    for byte in component.bytes() {
+        match byte {
+            b'A'...b'Z' | b'a'...b'z' | b'0'...b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Decodes a percent-encoded query string component, in which `+` also stands for a space.
+///
+/// Malformed escapes are kept as they are, and bytes that are not valid UTF-8 are replaced.
+pub fn query_decode(component: &str) -> String {
+    let bytes = component.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Creates the barcode for the given data, rendered in the given format.
+///
+/// PNG and SVG images will be at least `size` pixels wide, and are returned as data URIs. The
+/// barcode is only kept in memory, so that the TOTP secrets never touch the filesystem.
+pub fn create_barcode<D: AsRef<[u8]>>(data: D,
+                                      format: BarcodeFormat,
+                                      size: u32,
+                                      ec_level: EcLevel)
+                                      -> Result<String> {
+    let code = match QrCode::with_error_correction_level(data.as_ref(), ec_level) {
+        Ok(code) => code,
+        Err(_) => return Err(Error::FailedCreatePNG),
+    };
+
+    match format {
+        BarcodeFormat::Png => {
+            let image = code.render::<image::Rgba<u8>>().min_width(size).to_image();
+            let mut png = Vec::new();
+            if PNGEncoder::new(&mut png)
+                .encode(&image, image.width(), image.height(), ColorType::RGBA(8))
+                .is_err() {
+                return Err(Error::FailedCreatePNG);
+            }
+            Ok(format!("data:image/png;base64,{}", png.to_base64(STANDARD)))
+        }
+        BarcodeFormat::Svg => {
+            let svg = render_svg(&code, size);
+            Ok(format!("data:image/svg+xml;base64,{}", svg.as_bytes().to_base64(STANDARD)))
+        }
+        BarcodeFormat::Ascii => Ok(render_ascii(&code)),
+    }
+}
+
+/// Number of modules of the blank margin around the QR codes.
+const QUIET_ZONE: usize = 4;
+
+/// Renders the QR code as an SVG image at least `size` pixels wide.
+fn render_svg(code: &QrCode, size: u32) -> String {
+    let width = code.width();
+    let modules = code.to_vec();
+    let full_width = width + 2 * QUIET_ZONE;
+    let module_size = (size as usize + full_width - 1) / full_width;
+
+    let mut path = String::new();
+    for y in 0..width {
+        for x in 0..width {
+            if modules[y * width + x] {
+                path.push_str(&format!("M{},{}h1v1h-1z", x + QUIET_ZONE, y + QUIET_ZONE));
+            }
+        }
+    }
 
-    Iron::new(mount)
+    format!("<?xml version=\"1.0\" encoding=\"UTF-8\"?><svg \
+             xmlns=\"http://www.w3.org/2000/svg\" width=\"{0}\" height=\"{0}\" \
+             viewBox=\"0 0 {1} {1}\" shape-rendering=\"crispEdges\"><rect width=\"{1}\" \
+             height=\"{1}\" fill=\"#fff\"/><path fill=\"#000\" d=\"{2}\"/></svg>",
+            full_width * module_size,
+            full_width,
+            path)
 }
 
-/// Creates the barcode for the given secret.
+/// Renders the QR code as text, using two characters per module.
 ///
-/// It will save it in the given path, and it will contain the TOTP data for the given email.
-pub fn create_barcode<S: AsRef<str>, E: AsRef<str>, P: AsRef<Path>>(secret: S,
-                                                                    user_email: E,
-                                                                    path: P)
-                                                                    -> Result<()> {
-    let info = format!("otpauth://totp/{}?secret={}&issuer=%20Auth%20Example&digits=6&period=30",
-                       user_email.as_ref(),
-                       base32::encode(secret.as_ref().as_bytes()));
-    let code = QrCode::new(info.as_bytes()).unwrap(); // TODO NO UNWRAP
-    Ok(try!(code.render::<image::Rgba<u8>>().min_width(100).to_image().save(path)))
+/// Light modules are drawn with block characters and dark ones are left blank, so that the code
+/// can be scanned from terminals with a dark background.
+fn render_ascii(code: &QrCode) -> String {
+    let width = code.width();
+    let modules = code.to_vec();
+    let full_width = width + 2 * QUIET_ZONE;
+
+    let mut text = String::new();
+    for y in 0..full_width {
+        for x in 0..full_width {
+            let dark = x >= QUIET_ZONE && x < width + QUIET_ZONE && y >= QUIET_ZONE &&
+                       y < width + QUIET_ZONE &&
+                       modules[(y - QUIET_ZONE) * width + x - QUIET_ZONE];
+            text.push_str(if dark { "  " } else { "\u{2588}\u{2588}" });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BarcodeFormat, query_decode};
+
+    #[test]
+    fn barcode_format_names() {
+        assert_eq!("PNG".parse(), Ok(BarcodeFormat::Png));
+        assert_eq!("svg".parse(), Ok(BarcodeFormat::Svg));
+        assert_eq!("terminal".parse(), Ok(BarcodeFormat::Ascii));
+        assert_eq!("gif".parse::<BarcodeFormat>(), Err(()));
+
+        let format = "Terminal".parse::<BarcodeFormat>().unwrap();
+        assert_eq!(format.to_string(), "ascii");
+    }
+
+    #[test]
+    fn query_decoding() {
+        assert_eq!(query_decode("png"), "png");
+        assert_eq!(query_decode("a%20b+c"), "a b c");
+        assert_eq!(query_decode("user%40example.com"), "user@example.com");
+        assert_eq!(query_decode("%C3%B1"), "ñ");
+        assert_eq!(query_decode("100%"), "100%");
+        assert_eq!(query_decode("%zz%4"), "%zz%4");
+    }
 }
@@ -14,9 +14,14 @@ use chrono::{Duration, DateTime, UTC, NaiveDateTime};
 use dto::{TokenTypeDTO as TokenType, ScopeDTO as Scope, AccessTokenDTO, ResponseDTO,
           CreateClientDTO, ClientInfoDTO};
 
-use DATABASES;
-use utils::ENCRYPTION_CLIENT;
-use error::Result;
+use {DATABASES, CONFIG};
+use utils::{ENCRYPTION_CLIENT, to_millis, from_millis};
+use database::{AuditAction, Permission};
+use error::{Error, Result};
+use totp::{Totp, TotpAlgorithm};
+use super::{audit_event, record_audit_event, invalid_field};
+use super::auth::{require_token, require_permission};
+use super::types::ClientTotpSettingsDTO;
 
 /// Access Token Struct
 #[derive(Clone, Debug)]
@@ -25,6 +30,8 @@ pub struct AccessToken {
     scopes: Vec<Scope>,
     token_type: TokenType,
     expiration: DateTime<UTC>,
+    issued: DateTime<UTC>,
+    session: Option<String>,
 }
 
 impl AccessToken {
@@ -39,6 +46,9 @@ impl AccessToken {
             scopes: Vec::from(scopes),
             token_type: token_type,
             expiration: UTC::now() + expiration,
+            // Only milliseconds are kept in the encoded token.
+            issued: from_millis(to_millis(&UTC::now())),
+            session: None,
         }
     }
 
@@ -67,6 +77,22 @@ impl AccessToken {
         self.scopes.contains(&Scope::User(user_id))
     }
 
+    /// Returns whether the token grants the given permission.
+    ///
+    /// `Admin` scoped tokens have every permission. User tokens have the permissions of the roles
+    /// of the user, and the tokens of clients the permissions of the roles of the client.
+    pub fn has_permission(&self, permission: Permission) -> Result<bool> {
+        if self.is_admin() {
+            return Ok(true);
+        }
+        let db = thread_rng().choose(&DATABASES[..]).unwrap();
+        let roles = match self.get_user_id() {
+            Some(user_id) => try!(db.get_user_roles(user_id)),
+            None => try!(db.get_client_roles(&self.app_id)),
+        };
+        Ok(roles.iter().any(|role| role.has_permission(permission)))
+    }
+
     /// Gets the user ID if the token is a user token.
     pub fn get_user_id(&self) -> Option<u64> {
         for scope in &self.scopes {
@@ -88,6 +114,60 @@ impl AccessToken {
         self.expiration <= UTC::now()
     }
 
+    /// Returns the time the token was issued.
+    pub fn get_issued(&self) -> &DateTime<UTC> {
+        &self.issued
+    }
+
+    /// Returns the ID of the login session of the token, if it has one.
+    pub fn get_session_id(&self) -> Option<&str> {
+        self.session.as_ref().map(|s| s.as_str())
+    }
+
+    /// Sets the ID of the login session of the token.
+    pub fn set_session_id<S: AsRef<str>>(&mut self, session_id: S) {
+        self.session = Some(String::from(session_id.as_ref()));
+    }
+
+    /// Returns wether the token has been revoked, because it is a user token issued before all
+    /// the sessions of the user were revoked, or its login session was revoked. User tokens
+    /// without a login session are revoked along with the other sessions of the user.
+    ///
+    /// Both times are compared in milliseconds, so that a token issued right after a revocation
+    /// is not taken as revoked.
+    ///
+    /// If the token is still valid, the last seen time of its session is updated.
+    pub fn is_revoked(&self) -> Result<bool> {
+        match self.get_user_id() {
+            Some(user_id) => {
+                let db = thread_rng().choose(&DATABASES[..]).unwrap();
+                if let Some(revoked) = try!(db.get_sessions_revoked_time(user_id)) {
+                    if self.issued <= revoked {
+                        return Ok(true);
+                    }
+                }
+                match self.session {
+                    Some(ref session_id) => {
+                        match try!(db.get_session(session_id)) {
+                            Some(session) => {
+                                try!(db.touch_session(&session));
+                                Ok(false)
+                            }
+                            None => Ok(true),
+                        }
+                    }
+                    None => {
+                        match try!(db.get_sessionless_revoked_time(user_id)) {
+                            Some(revoked) => Ok(self.issued <= revoked),
+                            None => Ok(false),
+                        }
+                    }
+                }
+            }
+            None => Ok(false),
+        }
+    }
+
     /// returns a token from the stream and json.
     pub fn from_token<S: AsRef<str>>(token: S) -> Result<AccessToken> {
         let bytes = try!(ENCRYPTION_CLIENT.aes_decrypt(&try!(token.as_ref().from_base64())))
@@ -99,6 +179,8 @@ impl AccessToken {
             scopes: Vec::new(),
             token_type: TokenType::Bearer,
             expiration: UTC::now(),
+            issued: DateTime::from_utc(NaiveDateTime::from_timestamp(0, 0), UTC),
+            session: None,
         };
         for (key, value) in decoded {
             match key.as_str() {
@@ -110,6 +192,14 @@ impl AccessToken {
                         DateTime::from_utc(NaiveDateTime::from_timestamp(value.parse().unwrap(), 0),
                                            UTC)
                 }
+                // Tokens issued before the time was stored in milliseconds.
+                "issued" => {
+                    token.issued =
+                        DateTime::from_utc(NaiveDateTime::from_timestamp(value.parse().unwrap(), 0),
+                                           UTC)
+                }
+                "issued_ms" => token.issued = from_millis(value.parse().unwrap()),
+                "session" => token.session = Some(value),
                 _ => unreachable!(),
             }
         }
@@ -126,6 +216,10 @@ impl AccessToken {
         let _ = enc_hm.insert("scopes", scopes.clone());
         let _ = enc_hm.insert("token_type", format!("{}", self.token_type));
         let _ = enc_hm.insert("expiration", format!("{}", self.expiration.timestamp()));
+        let _ = enc_hm.insert("issued_ms", format!("{}", to_millis(&self.issued)));
+        if let Some(ref session) = self.session {
+            let _ = enc_hm.insert("session", session.clone());
+        }
         let json_to_encrypt = json::encode(&enc_hm).unwrap();
         let encrypted = try!(ENCRYPTION_CLIENT.aes_encrypt(&json_to_encrypt.into_bytes()));
 
@@ -161,13 +255,7 @@ pub fn token(req: &mut Request) -> IronResult<Response> {
                     let _ = res.set_mut(itry!(json::encode(&dto)))
                         .set_mut(status::Ok);
                 }
-                _ => {
-                    let _ =
-                        res.set_mut(json::encode(&ResponseDTO::new("unauthorized client or \
-                                                                     secret"))
-                                .unwrap())
-                            .set_mut(status::Forbidden);
-                }
+                _ => return Err(Error::InvalidCredentials.into()),
             }
         }
         _ => {
@@ -184,16 +272,12 @@ pub fn token(req: &mut Request) -> IronResult<Response> {
 /// - URL: `/create_client`
 /// - Returns: the `ClientInfoDTO` object with information about the created client, if successful.
 ///
-/// Requires an `Admin` scoped token.
+/// Requires the `clients:write` permission.
 pub fn create_client(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::ClientsWrite));
 
     let mut res = Response::new();
-    if !token.is_admin() {
-        let _ = res.set_mut(json::encode(&ResponseDTO::new("unauthorized token")).unwrap())
-            .set_mut(status::Forbidden);
-        return Ok(res);
-    }
 
     let mut body = String::new();
     let _ = itry!(req.body.read_to_string(&mut body));
@@ -203,6 +287,9 @@ pub fn create_client(req: &mut Request) -> IronResult<Response> {
     let db = thread_rng().choose(&DATABASES[..]).unwrap();
     match db.create_developer_client(&dto.name, dto.scopes.as_slice(), dto.request_limit) {
         Ok((id, secret)) => {
+            let mut event = audit_event(req, token.get_app_id(), None, AuditAction::ClientCreated);
+            event.after = Some(id.clone());
+            record_audit_event(db, &event);
             let res_dto = ClientInfoDTO {
                 id: id,
                 name: dto.name,
@@ -220,3 +307,71 @@ pub fn create_client(req: &mut Request) -> IronResult<Response> {
 
     Ok(res)
 }
+
+/// Sets the TOTP settings of an OAuth client.
+///
+/// - Method: `POST`
+/// - URL: `/client_totp_settings/:client_id`
+/// - Returns: an `OK` status code if the settings were changed, an `UnprocessableEntity` status
+///   code if they are not valid, or a `NotFound` status code if the client does not exist.
+///
+/// Requires the `clients:write` permission. The settings only apply to secrets generated after
+/// the change, since users keep the settings they enrolled with.
+pub fn set_client_totp_settings(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::ClientsWrite));
+
+    let mut res = Response::new();
+
+    let client_id = param!(req, "client_id");
+    let mut body = String::new();
+    let _ = itry!(req.body.read_to_string(&mut body));
+    let dto = itry!(json::decode::<ClientTotpSettingsDTO>(&body), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let mut client = match itry!(db.get_client(&client_id)) {
+        Some(client) => client,
+        None => return Err(Error::ClientDoesNotExist.into()),
+    };
+
+    let current = client.get_totp(&CONFIG.get_totp());
+    let algorithm = match dto.algorithm {
+        Some(ref algorithm) => {
+            match algorithm.parse::<TotpAlgorithm>() {
+                Ok(algorithm) => algorithm,
+                Err(_) => {
+                    return invalid_field("algorithm",
+                                         "the algorithm must be SHA1, SHA256 or SHA512")
+                }
+            }
+        }
+        None => current.get_algorithm(),
+    };
+    let digits = dto.digits.unwrap_or(current.get_digits());
+    let period = dto.period.unwrap_or(current.get_period());
+    if digits < 6 || digits > 8 {
+        return invalid_field("digits", "the codes must have between 6 and 8 digits");
+    }
+    if period == 0 {
+        return invalid_field("period", "the period cannot be zero");
+    }
+
+    let issuer = match dto.issuer {
+        Some(issuer) => if issuer.is_empty() { None } else { Some(issuer) },
+        None => client.get_totp_issuer().map(String::from),
+    };
+    let totp = Totp::new(algorithm, digits, period, current.get_skew());
+    let mut event = audit_event(req, token.get_app_id(), None, AuditAction::ClientTotpSettings);
+    event.before = Some(format!("{}: {}, {} digits, {}s",
+                                client_id,
+                                current.get_algorithm(),
+                                current.get_digits(),
+                                current.get_period()));
+    event.after = Some(format!("{}: {}, {} digits, {}s", client_id, algorithm, digits, period));
+    itry!(client.set_totp_settings(issuer, Some(totp)));
+    record_audit_event(db, &event);
+
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("client TOTP settings changed")).unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
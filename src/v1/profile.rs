@@ -0,0 +1,120 @@
+//! Profile module. It contains the methods to get the public profiles of the users, and for users
+//! to choose which fields of their profile are shown.
+use std::io::Read;
+
+use iron::prelude::*;
+use iron::status;
+
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+use dto::{ProfileDTO, ResponseDTO};
+
+use DATABASES;
+use database::{ProfileVisibility, User};
+use error::Error;
+use super::auth::{require_token, require_user};
+use super::types::ProfileVisibilityDTO;
+
+/// Gets the public profile of the given user.
+///
+/// - Method: `GET`
+/// - URL: `/profile/:user_id`
+/// - Scopes: `Public`, `User`, `Admin`
+/// - Returns: the `ProfileDTO` with the fields the user chose to show, or a `NotFound` status code
+///   if the user does not exist, is disabled or has hidden its profile.
+pub fn get_profile(req: &mut Request) -> IronResult<Response> {
+    let _ = try!(require_token(req));
+    let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    profile_response(itry!(db.get_user_by_id(user_id)))
+}
+
+/// Gets the public profile of the user with the given username.
+///
+/// - Method: `GET`
+/// - URL: `/profile/by-username/:username`
+/// - Scopes: `Public`, `User`, `Admin`
+/// - Returns: the `ProfileDTO` with the fields the user chose to show, or a `NotFound` status code
+///   if the user does not exist, is disabled or has hidden its profile.
+pub fn get_profile_by_username(req: &mut Request) -> IronResult<Response> {
+    let _ = try!(require_token(req));
+    let username = param!(req, "username");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    profile_response(itry!(db.get_user_by_username(username)))
+}
+
+/// Gets which fields of the public profile of the user are shown.
+///
+/// - Method: `GET`
+/// - URL: `/profile_visibility`
+/// - Scopes: `User`
+/// - Returns: the `ProfileVisibilityDTO` of the user.
+pub fn get_profile_visibility(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match itry!(db.get_user_by_id(user_id)) {
+        Some(user) => {
+            let visibility = itry!(user.get_profile_visibility());
+            let _ = res.set_mut(json::encode(&ProfileVisibilityDTO::new(&visibility)).unwrap())
+                .set_mut(status::Ok);
+        }
+        None => return Err(Error::UserDoesNotExist.into()),
+    }
+    Ok(res)
+}
+
+/// Sets which fields of the public profile of the user are shown.
+///
+/// - Method: `POST`
+/// - URL: `/profile_visibility`
+/// - Scopes: `User`
+/// - Returns: a successful response once the settings are saved.
+///
+/// The `ProfileVisibilityDTO` must be in the body. If `hidden` is set, the profile is not shown at
+/// all.
+pub fn set_profile_visibility(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+    let mut body = String::new();
+    let _ = itry!(req.body.read_to_string(&mut body));
+    let dto = itry!(json::decode::<ProfileVisibilityDTO>(&body), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match itry!(db.get_user_by_id(user_id)) {
+        Some(user) => {
+            let visibility = ProfileVisibility {
+                hidden: dto.hidden,
+                first_name: dto.first_name,
+                last_name: dto.last_name,
+                image: dto.image,
+                age: dto.age,
+                address: dto.address,
+            };
+            itry!(user.set_profile_visibility(&visibility));
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("profile visibility updated"))
+                    .unwrap())
+                .set_mut(status::Ok);
+        }
+        None => return Err(Error::UserDoesNotExist.into()),
+    }
+    Ok(res)
+}
+
+/// Creates the response with the public profile of the user, or a `NotFound` response if it must
+/// not be shown.
+fn profile_response(user: Option<User>) -> IronResult<Response> {
+    let mut res = Response::new();
+    if let Some(user) = user {
+        let visibility = itry!(user.get_profile_visibility());
+        if user.is_enabled() && !visibility.hidden {
+            let profile: ProfileDTO = user.get_visible_profile(&visibility).into();
+            let _ = res.set_mut(json::encode(&profile).unwrap()).set_mut(status::Ok);
+            return Ok(res);
+        }
+    }
+    Err(Error::NotFound.into())
+}
@@ -2,29 +2,155 @@
 //! registration, start reset password, and reset password methods for iron to route to.
 use iron::prelude::*;
 use iron::status;
+use iron::headers::UserAgent;
 use std::io::Read;
 
 use rand::{Rng, thread_rng};
 use rustc_serialize::json;
 use rustc_serialize::base64::{ToBase64, URL_SAFE};
 use chrono::Duration;
-use dto::{RegisterDTO, LoginDTO, ResetPasswordDTO, NewPasswordDTO, ResponseDTO, ScopeDTO as Scope,
-          TokenTypeDTO as TokenType};
+use dto::{RegisterDTO, LoginDTO, NewPasswordDTO, ResponseDTO, ScopeDTO as Scope,
+          TokenTypeDTO as TokenType, AuthenticationCodeDTO};
 
 use {DATABASES, EMAILS, CONFIG};
 use utils::{EmailStruct, EmailType};
+use database::{AuditAction, AuditEvent, Database, LoginAttempt, MfaPendingLogin, Session, User};
+use error::{Error, Result};
+use validation;
+use super::{audit_event, record_audit_event, register_failed_attempt, locked_error};
+use super::auth::require_public;
 use super::oauth::AccessToken;
+use super::types::{MfaPendingDTO, RecoveryCodeDTO, PasswordPolicyErrorDTO, ValidationErrorDTO,
+                   ResetPasswordRequestDTO, MagicLinkRequestDTO};
+
+/// Seconds a login waiting for the second factor stays valid.
+const MFA_PENDING_TIME: usize = 5 * 60;
+
+/// Creates a `User` scoped token for the given user, and the login session it belongs to.
+///
+/// The session records the user agent and the IP address of the request.
+fn new_user_token(req: &Request,
+                  app_id: &str,
+                  user_id: u64,
+                  remember_me: bool)
+                  -> Result<AccessToken> {
+    let duration = if remember_me {
+        Duration::seconds(CONFIG.get_session_remember().num_seconds())
+    } else {
+        Duration::seconds(60 * 60)
+    };
+    let mut token = AccessToken::new(app_id, &[Scope::User(user_id)], TokenType::Bearer, duration);
+
+    let mut session_key = [0u8; 24];
+    thread_rng().fill_bytes(&mut session_key[0..]);
+    let session = Session {
+        id: session_key.to_base64(URL_SAFE),
+        user_id: user_id,
+        app_id: String::from(app_id),
+        user_agent: req.headers.get::<UserAgent>().map(|ua| ua.0.clone()),
+        ip: Some(format!("{}", req.remote_addr.ip())),
+        created: *token.get_issued(),
+        last_seen: *token.get_issued(),
+        remember_me: remember_me,
+        expiration: *token.get_expiration(),
+    };
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    try!(db.create_session(&session));
+    token.set_session_id(&session.id);
+    Ok(token)
+}
+
+/// Continues a login once the first factor of the user has been checked, and returns the body of
+/// the response.
+///
+/// If the user has two factor authentication enabled, a pending login is created and a
+/// `MfaPendingDTO` is returned, so that the login is finished in `/login/mfa/:mfa_token`.
+/// Otherwise a `User` scoped token is returned.
+///
+/// The failed attempts of the user are only cleared once the login is complete, so that a correct
+/// password does not reset the lockout counter between guesses of the second factor.
+fn first_factor_login(req: &Request,
+                      app_id: &str,
+                      user: &mut User,
+                      remember_me: bool)
+                      -> Result<String> {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if user.is_two_factor_enabled() {
+        let pending_dto = try!(start_mfa_login(db, app_id, user, remember_me));
+        Ok(json::encode(&pending_dto).unwrap())
+    } else {
+        try!(user.clear_failed_attempts());
+        let new_token = try!(new_user_token(req, app_id, user.get_id(), remember_me));
+        let token_result = try!(new_token.into_dto());
+        let _ = user.set_last_activity_time();
+        Ok(json::encode(&token_result).unwrap())
+    }
+}
+
+/// Creates a login waiting for the second factor of the user.
+fn start_mfa_login(db: &Database,
+                   app_id: &str,
+                   user: &User,
+                   remember_me: bool)
+                   -> Result<MfaPendingDTO> {
+    let mut mfa_key = [0u8; 15];
+    thread_rng().fill_bytes(&mut mfa_key[0..]);
+    let mfa_str = mfa_key.to_base64(URL_SAFE);
+    let pending = MfaPendingLogin {
+        user_id: user.get_id(),
+        app_id: String::from(app_id),
+        remember_me: remember_me,
+    };
+    try!(db.create_mfa_pending_login(&mfa_str, &pending, MFA_PENDING_TIME));
+    Ok(MfaPendingDTO {
+        mfa_token: mfa_str,
+        expiration: MFA_PENDING_TIME as i64,
+    })
+}
+
+/// Records the login attempt described by the audit event in the audit log and in the login
+/// history of the user.
+///
+/// If a successful login comes from a device or IP address never seen before for the user, an
+/// alert is emailed to the user.
+fn record_login(req: &Request, user: &User, event: &AuditEvent) -> Result<()> {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    record_audit_event(db, event);
+
+    let success = event.action == AuditAction::Login;
+    let attempt = LoginAttempt {
+        time: event.time.timestamp(),
+        ip: event.ip.clone(),
+        user_agent: req.headers.get::<UserAgent>().map(|ua| ua.0.clone()),
+        app_id: event.app_id.clone(),
+        success: success,
+        reason: event.reason.clone(),
+    };
+    try!(db.add_login_attempt(user.get_id(), &attempt, CONFIG.get_login_history_len()));
+    if success && try!(db.register_login_device(user.get_id(), &attempt)) {
+        let email = EmailStruct {
+            email: String::from(user.get_email()),
+            email_key: String::new(),
+            email_type: EmailType::NewDeviceLogin(String::from(attempt.get_device()),
+                                                  attempt.ip
+                                                      .clone()
+                                                      .unwrap_or(String::from("an unknown IP"))),
+        };
+        EMAILS.lock().unwrap().push(email);
+    }
+    Ok(())
+}
 
 /// Registers the given user.
 ///
 /// - Method: `POST`
 /// - URL: `/register`
 /// - Scopes: `Public`
-/// - Returns: a successfully registered response. If an error occurred, such as an already
-///   existing username or email, an `Accepted` status code will be returned, with the error message
-///   in a `ResponseDTO` object.
+/// - Returns: a successfully registered response, a `username_exists` or `email_exists` error if
+///   the username or email are already in use, a `ValidationErrorDTO` if they are not valid, or a
+///   `PasswordPolicyErrorDTO` if the password does not meet the password policy.
 pub fn register(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_public(req));
 
     let mut register_str = String::new();
     let _ = req.body.read_to_string(&mut register_str);
@@ -33,50 +159,46 @@ pub fn register(req: &mut Request) -> IronResult<Response> {
 
     let mut res = Response::new();
 
-    if token.is_public() {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-        let user_exists = itry!(db.check_username_exists(&register.username));
-        if user_exists {
-            let _ =
-                res.set_mut(json::encode(&ResponseDTO::new("user with that username already \
-                                                             exists"))
-                        .unwrap())
-                    .set_mut(status::Accepted);
-        } else {
-            let email_exists = itry!(db.check_email_exists(&register.email));
-            if email_exists {
-                let _ = res.set_mut(json::encode(&ResponseDTO::new("user with that email already \
-                                                             exists"))
-                        .unwrap())
-                    .set_mut(status::Accepted);
-            } else {
-                let mut email_key = [0u8; 5];
-                thread_rng().fill_bytes(&mut email_key[0..]);
-                let email_str = email_key.to_base64(URL_SAFE);
-                let _ = itry!(db.create_user_simple(&register.username,
-                                                    &register.password,
-                                                    &register.email,
-                                                    &email_str));
-                let email = EmailStruct {
-                    email: register.email,
-                    email_key: email_str,
-                    email_type: EmailType::Email,
-                };
-                EMAILS.lock().unwrap().push(email);
-                let _ =
-                    res.set_mut(json::encode(&ResponseDTO::new("successfully registered!"))
-                            .unwrap())
-                        .set_mut(status::Ok);
-            }
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let username = validation::validate_username(&register.username);
+    let email = validation::normalize_email(&register.email);
+    let (username, email) = match (username, email) {
+        (Ok(username), Ok(email)) => (username, email),
+        (username, email) => {
+            let errors = username.err().into_iter().chain(email.err()).collect::<Vec<_>>();
+            return Ok(Response::with(ValidationErrorDTO::new(&errors)));
         }
-    } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
-                    .unwrap())
-                .set_mut(status::Forbidden);
+    };
+    let failed_rules = CONFIG.get_password_policy()
+        .check(&register.password, &username, &email);
+    if !failed_rules.is_empty() {
+        return Ok(Response::with(PasswordPolicyErrorDTO::new(&failed_rules)));
+    }
+    if itry!(db.check_username_exists(&username)) {
+        return Err(Error::UsernameExists.into());
+    }
+    if itry!(db.check_email_exists(&email)) {
+        return Err(Error::EmailExists.into());
     }
 
+    let mut email_key = [0u8; 5];
+    thread_rng().fill_bytes(&mut email_key[0..]);
+    let email_str = email_key.to_base64(URL_SAFE);
+    let user_id = itry!(db.create_user_simple(&username,
+                                              &register.password,
+                                              &email,
+                                              &email_str,
+                                              &CONFIG.get_password_scheme()));
+    let event = audit_event(req, token.get_app_id(), Some(user_id), AuditAction::Register);
+    record_audit_event(db, &event);
+    let email = EmailStruct {
+        email: email,
+        email_key: email_str,
+        email_type: EmailType::Email,
+    };
+    EMAILS.lock().unwrap().push(email);
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully registered!")).unwrap())
+        .set_mut(status::Ok);
     Ok(res)
 }
 
@@ -86,152 +208,328 @@ pub fn register(req: &mut Request) -> IronResult<Response> {
 /// - URL: `/login`
 /// - Scopes: `Public`
 /// - Returns: A `User` scoped token, for the logged in user if the user provided the succesful
-///   credentials, or an `Accepted` status code if username/email or password were not correct.
+///   credentials, or an `invalid_credentials` error if username/email or password were not
+///   correct.
+///
+/// Banned, disabled and locked out users get a `user_banned`, `user_disabled` or `user_locked`
+/// error instead. The `user_locked` error has a `Retry-After` header with the seconds until the
+/// lockout ends.
 ///
-/// It will return an `Accepted` response code if the user was successfully authenticated but a new
-/// error was found, such a banned or disabled user. The `ResponseDTO` in the response body will
-/// contain the error in the `message` parameter.
+/// If the user has two factor authentication enabled, a `MfaPendingDTO` will be returned instead
+/// of the token, and the login must be finished in `/login/mfa/:mfa_token`.
 pub fn login(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_public(req));
     let mut login_str = String::new();
     let _ = req.body.read_to_string(&mut login_str);
     let login = itry!(json::decode::<LoginDTO>(&login_str), status::BadRequest);
     let mut res = Response::new();
-    if token.is_public() {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-
-        let user = if let Some(user) = itry!(db.get_user_by_email(&login.user_email)) {
-            Some(user)
-        } else if let Some(user) = itry!(db.get_user_by_username(&login.user_email)) {
-            Some(user)
-        } else {
-            None
-        };
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+
+    let user = if let Some(user) = itry!(db.get_user_by_email(&login.user_email)) {
+        Some(user)
+    } else if let Some(user) = itry!(db.get_user_by_username(&login.user_email)) {
+        Some(user)
+    } else {
+        None
+    };
 
-        match user {
-            Some(mut user) => {
-                if user.is_banned() {
-                    let _ =
-                        res.set_mut(json::encode(&ResponseDTO::new(format!("user is banned \
-                                                                             until {}",
-                                                                            user.get_banned()
-                                                                                .unwrap())))
-                                .unwrap())
-                            .set_mut(status::Accepted);
-                } else if !user.is_enabled() {
-                    let _ =
-                        res.set_mut(json::encode(&ResponseDTO::new("user is disabled")).unwrap())
-                            .set_mut(status::Accepted);
+    match user {
+        Some(mut user) => {
+            let mut event =
+                audit_event(req, token.get_app_id(), Some(user.get_id()), AuditAction::Login);
+            let mut failure: Option<IronError> = None;
+            if user.is_banned() {
+                event.action = AuditAction::LoginFailed;
+                event.reason = Some(String::from("the user is banned"));
+                failure = Some(Error::UserBanned.into());
+            } else if !user.is_enabled() {
+                event.action = AuditAction::LoginFailed;
+                event.reason = Some(String::from("the user is disabled"));
+                failure = Some(Error::UserDisabled.into());
+            } else if user.is_locked() {
+                event.action = AuditAction::LoginFailed;
+                event.reason = Some(String::from("the user is locked"));
+                failure = Some(locked_error(&user));
+            } else {
+                let is_correct_pass =
+                    itry!(user.check_password(&CONFIG.get_password_scheme(), login.password));
+                if is_correct_pass {
+                    let body = itry!(first_factor_login(req,
+                                                        token.get_app_id(),
+                                                        &mut user,
+                                                        login.remember_me));
+                    let _ = res.set_mut(body).set_mut(status::Ok);
                 } else {
-                    let is_correct_pass = itry!(user.check_password(login.password));
-                    if is_correct_pass {
-                        let new_token = AccessToken::new(token.get_app_id(),
-                                                         &[Scope::User(user.get_id())],
-                                                         TokenType::Bearer,
-                                                         if login.remember_me {
-                                                             Duration::seconds(
-                                                                 CONFIG.get_session_remember()
-                                                                    .num_seconds())
-                                                         } else {
-                                                             Duration::seconds(60 * 60)
-                                                         });
-                        let token_result = itry!(new_token.into_dto());
-                        let _ = user.set_last_activity_time();
-                        let _ = res.set_mut(json::encode(&token_result).unwrap())
-                            .set_mut(status::Ok);
-
-                    } else {
-                        let _ =
-                            res.set_mut(json::encode(&ResponseDTO::new("incorrect username, \
-                                                                         email or password"))
-                                    .unwrap())
-                                .set_mut(status::Accepted);
-                    }
+                    event.action = AuditAction::LoginFailed;
+                    event.reason = Some(String::from("incorrect password"));
+                    itry!(register_failed_attempt(req,
+                                                  db,
+                                                  token.get_app_id(),
+                                                  &mut user,
+                                                  "incorrect password"));
+                    failure = Some(Error::InvalidCredentials.into());
                 }
             }
-            None => {
-                let _ = res.set_mut(json::encode(&ResponseDTO::new("incorrect username, email or \
-                                                             password"))
-                        .unwrap())
-                    .set_mut(status::Accepted);
+            // Logins with two factor authentication are recorded once the second factor
+            // has been checked.
+            if event.action != AuditAction::Login || !user.is_two_factor_enabled() {
+                itry!(record_login(req, &user, &event));
+            }
+            if let Some(failure) = failure {
+                return Err(failure);
             }
         }
+        None => {
+            let mut event =
+                audit_event(req, token.get_app_id(), None, AuditAction::LoginFailed);
+            event.reason = Some(String::from("unknown user"));
+            record_audit_event(db, &event);
+            return Err(Error::InvalidCredentials.into());
+        }
+    }
 
-    } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
-                    .unwrap())
-                .set_mut(status::Forbidden);
+    Ok(res)
+}
+
+/// Finishes the login of a user with two factor authentication enabled.
+///
+/// - Method: `POST`
+/// - URL: `/login/mfa/:mfa_token`
+/// - Scopes: `Public`
+/// - Returns: A `User` scoped token for the user if the authenticator code is correct, an
+///   `invalid_code` error if it was not, or an `invalid_key` error if the pending login expired.
+///
+/// The `mfa_token` is the one returned by `/login` for users with two factor authentication.
+pub fn login_mfa(req: &mut Request) -> IronResult<Response> {
+    let mut code_str = String::new();
+    let _ = req.body.read_to_string(&mut code_str);
+    let code_dto = itry!(json::decode::<AuthenticationCodeDTO>(&code_str),
+                         status::BadRequest);
+    finish_mfa_login(req,
+                     |user| user.check_authenticator_code(&CONFIG.get_totp(), code_dto.code))
+}
+
+/// Finishes the login of a user with two factor authentication enabled using a recovery code.
+///
+/// - Method: `POST`
+/// - URL: `/login/recovery/:mfa_token`
+/// - Scopes: `Public`
+/// - Returns: A `User` scoped token for the user if the recovery code is correct, an
+///   `invalid_code` error if it was not, or an `invalid_key` error if the pending login expired.
+///
+/// Each recovery code can only be used once.
+pub fn login_recovery(req: &mut Request) -> IronResult<Response> {
+    let mut code_str = String::new();
+    let _ = req.body.read_to_string(&mut code_str);
+    let code_dto = itry!(json::decode::<RecoveryCodeDTO>(&code_str), status::BadRequest);
+    finish_mfa_login(req, |user| user.use_recovery_code(&code_dto.recovery_code))
+}
+
+/// Finishes the pending login in the `mfa_token` parameter if `verify` accepts the second factor.
+fn finish_mfa_login<F>(req: &mut Request, verify: F) -> IronResult<Response>
+    where F: FnOnce(&mut User) -> Result<bool>
+{
+    let _ = try!(require_public(req));
+    let mfa_key = param!(req, "mfa_token");
+    let mut res = Response::new();
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let pending = match itry!(db.get_mfa_pending_login(&mfa_key)) {
+        Some(pending) => pending,
+        None => return Err(Error::IncorrectKey.into()),
+    };
+
+    match itry!(db.get_user_by_id(pending.user_id)) {
+        Some(mut user) => {
+            let mut event =
+                audit_event(req, &pending.app_id, Some(user.get_id()), AuditAction::Login);
+            let mut failure: Option<IronError> = None;
+            if user.is_banned() || !user.is_enabled() {
+                event.action = AuditAction::LoginFailed;
+                event.reason = Some(String::from("the user is banned or disabled"));
+                itry!(db.delete_mfa_pending_login(&mfa_key));
+                failure = Some(if user.is_banned() {
+                    Error::UserBanned.into()
+                } else {
+                    Error::UserDisabled.into()
+                });
+            } else if user.is_locked() {
+                event.action = AuditAction::LoginFailed;
+                event.reason = Some(String::from("the user is locked"));
+                itry!(db.delete_mfa_pending_login(&mfa_key));
+                failure = Some(locked_error(&user));
+            } else if itry!(verify(&mut user)) {
+                itry!(user.clear_failed_attempts());
+                itry!(db.delete_mfa_pending_login(&mfa_key));
+                let new_token = itry!(new_user_token(req,
+                                                     &pending.app_id,
+                                                     user.get_id(),
+                                                     pending.remember_me));
+                let token_result = itry!(new_token.into_dto());
+                let _ = user.set_last_activity_time();
+                let _ = res.set_mut(json::encode(&token_result).unwrap())
+                    .set_mut(status::Ok);
+            } else {
+                event.action = AuditAction::LoginFailed;
+                event.reason = Some(String::from("incorrect second factor"));
+                itry!(register_failed_attempt(req,
+                                              db,
+                                              &pending.app_id,
+                                              &mut user,
+                                              "incorrect second factor"));
+                failure = Some(Error::IncorrectCode.into());
+            }
+            itry!(record_login(req, &user, &event));
+            if let Some(failure) = failure {
+                return Err(failure);
+            }
+        }
+        None => return Err(Error::UserDoesNotExist.into()),
     }
     Ok(res)
 }
 
+/// Sends a passwordless login link to the account with the given email.
+///
+/// - Method: `POST`
+/// - URL: `/start_magic_login`
+/// - Scopes: `Public`
+/// - Returns: a successful response, whether an account with that email exists or not, so that
+///   the response cannot be used to find out which emails are registered.
+///
+/// The link is only sent if the email of the account is confirmed. It can be used once, it
+/// expires after the configured `magic_link_ttl`, and any previous link of the account stops
+/// working.
+pub fn start_magic_login(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_public(req));
+    let mut body = String::new();
+    let _ = req.body.read_to_string(&mut body);
+    let dto = itry!(json::decode::<MagicLinkRequestDTO>(&body), status::BadRequest);
+    let mut res = Response::new();
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(user) = itry!(db.get_user_by_email(&dto.email)) {
+        if user.is_email_confirmed() {
+            let mut login_key = [0u8; 24];
+            thread_rng().fill_bytes(&mut login_key[0..]);
+            let login_str = login_key.to_base64(URL_SAFE);
+            itry!(user.start_magic_login(login_str.as_str(),
+                                         token.get_app_id(),
+                                         dto.remember_me,
+                                         CONFIG.get_magic_link_ttl()));
+            let event = audit_event(req,
+                                    token.get_app_id(),
+                                    Some(user.get_id()),
+                                    AuditAction::MagicLinkRequested);
+            record_audit_event(db, &event);
+            let email = EmailStruct {
+                email: String::from(user.get_email()),
+                email_key: login_str,
+                email_type: EmailType::MagicLink,
+            };
+            EMAILS.lock().unwrap().push(email);
+        }
+    }
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("if an account with that email \
+                                                         exists, a login link has been sent"))
+            .unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
 
-/// Begins the reset password process.
+/// Logs in with a passwordless login link.
+///
+/// - Method: `POST`
+/// - URL: `/magic_login/:login_key`
+/// - Scopes: `Public`
+/// - Returns: A `User` scoped token for the user, an `invalid_key` error if the key is not valid,
+///   or a `user_banned`, `user_disabled` or `user_locked` error.
+///
+/// The key replaces the password, so if the user has two factor authentication enabled, a
+/// `MfaPendingDTO` will be returned instead of the token, and the login must be finished in
+/// `/login/mfa/:mfa_token`.
+pub fn magic_login(req: &mut Request) -> IronResult<Response> {
+    let _ = try!(require_public(req));
+    let login_key = param!(req, "login_key");
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let login = match itry!(db.use_magic_login(&login_key)) {
+        Some(login) => login,
+        None => return Err(Error::IncorrectKey.into()),
+    };
+    let mut user = match itry!(db.get_user_by_id(login.user_id)) {
+        Some(user) => user,
+        None => return Err(Error::UserDoesNotExist.into()),
+    };
+
+    let mut event = audit_event(req, &login.app_id, Some(user.get_id()), AuditAction::Login);
+    let failure: Option<IronError> = if user.is_banned() {
+        Some(Error::UserBanned.into())
+    } else if !user.is_enabled() {
+        Some(Error::UserDisabled.into())
+    } else if user.is_locked() {
+        Some(locked_error(&user))
+    } else {
+        None
+    };
+    if failure.is_some() {
+        event.action = AuditAction::LoginFailed;
+        event.reason = Some(String::from("the user is banned, disabled or locked"));
+    } else {
+        let body = itry!(first_factor_login(req, &login.app_id, &mut user, login.remember_me));
+        let _ = res.set_mut(body).set_mut(status::Ok);
+    }
+    // Logins with two factor authentication are recorded once the second factor has been
+    // checked.
+    if event.action != AuditAction::Login || !user.is_two_factor_enabled() {
+        itry!(record_login(req, &user, &event));
+    }
+    match failure {
+        Some(failure) => Err(failure),
+        None => Ok(res),
+    }
+}
+
+/// Starts the password reset of the account with the given email.
 ///
 /// - Method: `POST`
 /// - URL: `/start_reset_password`
 /// - Scopes: `Public`
-/// - Returns: An `OK` status code if the reset was successfully started, or a `Forbidden` status
-//    code if the token is not authorized or if the credentials were not correct. The `ResponseDTO`
-///   in the response body will contain the error in the `message` parameter.
+/// - Returns: a successful response, whether an account with that email exists or not, so that
+///   the response cannot be used to find out which emails are registered.
+///
+/// If the account exists, a single use reset key is emailed to it, and any previous reset key of
+/// the account stops working. The key expires after the configured `reset_password_ttl`.
 pub fn start_reset_password(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_public(req));
     let mut start_reset_pass_str = String::new();
     let _ = req.body.read_to_string(&mut start_reset_pass_str);
-    let start_reset_pass = itry!(json::decode::<ResetPasswordDTO>(&start_reset_pass_str),
+    let start_reset_pass = itry!(json::decode::<ResetPasswordRequestDTO>(&start_reset_pass_str),
                                  status::BadRequest);
     let mut res = Response::new();
-    if token.is_public() {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-        let exists = itry!(db.check_username_exists(&start_reset_pass.username));
-        if exists {
-            let user = itry!(db.get_user_by_username(&start_reset_pass.username));
-            match user {
-                Some(user) => {
-                    if user.get_email().to_lowercase() == start_reset_pass.email.to_lowercase() {
-                        let mut reset_password_key = [0u8; 10];
-                        thread_rng().fill_bytes(&mut reset_password_key[0..]);
-                        let reset_password_str = reset_password_key.to_base64(URL_SAFE);
-                        let email = EmailStruct {
-                            email: start_reset_pass.email,
-                            email_key: reset_password_str,
-                            email_type: EmailType::Password,
-                        };
-                        EMAILS.lock().unwrap().push(email);
-                        let _ = res.set_mut(json::encode(&ResponseDTO::new("began reset password \
-                                                                     process"))
-                                .unwrap())
-                            .set_mut(status::Ok);
-                    } else {
-                        let _ = res.set_mut(json::encode(&ResponseDTO::new("incorrect username or \
-                                                                     email"))
-                                .unwrap())
-                            .set_mut(status::Forbidden);
-                    }
-                }
-                None => {
-                    let _ =
-                        res.set_mut(json::encode(&ResponseDTO::new("incorrect username or \
-                                                                     email"))
-                                .unwrap())
-                            .set_mut(status::Forbidden);
-                }
-            }
-        } else {
-            let _ =
-                res.set_mut(json::encode(&ResponseDTO::new("incorrect username or email"))
-                        .unwrap())
-                    .set_mut(status::Forbidden);
-        }
-    } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
-                    .unwrap())
-                .set_mut(status::Forbidden);
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(user) = itry!(db.get_user_by_email(&start_reset_pass.email)) {
+        let mut reset_password_key = [0u8; 24];
+        thread_rng().fill_bytes(&mut reset_password_key[0..]);
+        let reset_password_str = reset_password_key.to_base64(URL_SAFE);
+        itry!(user.start_reset_password(&reset_password_str,
+                                        CONFIG.get_reset_password_ttl()));
+        let event = audit_event(req,
+                                token.get_app_id(),
+                                Some(user.get_id()),
+                                AuditAction::PasswordResetRequested);
+        record_audit_event(db, &event);
+        let email = EmailStruct {
+            email: String::from(user.get_email()),
+            email_key: reset_password_str,
+            email_type: EmailType::Password,
+        };
+        EMAILS.lock().unwrap().push(email);
     }
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("if an account with that email \
+                                                         exists, a password reset email \
+                                                         has been sent"))
+            .unwrap())
+        .set_mut(status::Ok);
     Ok(res)
 }
 
@@ -240,11 +538,13 @@ pub fn start_reset_password(req: &mut Request) -> IronResult<Response> {
 /// - Method: `POST`
 /// - URL: `/reset_password/:pass_key`
 /// - Scopes: `Public`
-/// - Returns: A succesfful response, or failed response or unauthorized.
+/// - Returns: A succesfful response, an `invalid_key` error if the key is not valid, or a
+///   `PasswordPolicyErrorDTO` if the new password does not meet the password policy.
 ///
-/// It requires a `Public` scoped token
+/// It requires a `Public` scoped token. The reset key can only be used once, and after the reset
+/// all the existing tokens of the user are revoked and a notification email is sent.
 pub fn reset_password(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_public(req));
 
     let mut new_pass_str = String::new();
     let _ = req.body.read_to_string(&mut new_pass_str);
@@ -254,19 +554,36 @@ pub fn reset_password(req: &mut Request) -> IronResult<Response> {
     let mut res = Response::new();
     let pass_key = param!(req, "pass_key");
 
-    if token.is_public() {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-        let _ = itry!(db.confirm_password_reset(pass_key, new_password.new_password));
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("successfully reset your password"))
-                    .unwrap())
-                .set_mut(status::Ok);
-    } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
-                    .unwrap())
-                .set_mut(status::Forbidden);
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(user) = itry!(db.get_password_reset_user(&pass_key)) {
+        let failed_rules = CONFIG.get_password_policy()
+            .check(&new_password.new_password, user.get_username(), user.get_email());
+        if !failed_rules.is_empty() {
+            return Ok(Response::with(PasswordPolicyErrorDTO::new(&failed_rules)));
+        }
+    }
+    match db.confirm_password_reset(pass_key,
+                                    new_password.new_password,
+                                    &CONFIG.get_password_scheme()) {
+        Ok(user) => {
+            let event = audit_event(req,
+                                    token.get_app_id(),
+                                    Some(user.get_id()),
+                                    AuditAction::PasswordReset);
+            record_audit_event(db, &event);
+            let email = EmailStruct {
+                email: String::from(user.get_email()),
+                email_key: String::new(),
+                email_type: EmailType::PasswordChanged,
+            };
+            EMAILS.lock().unwrap().push(email);
+            let _ =
+                res.set_mut(json::encode(&ResponseDTO::new("successfully reset your \
+                                                             password"))
+                        .unwrap())
+                    .set_mut(status::Ok);
+        }
+        Err(e) => return Err(e.into()),
     }
 
     Ok(res)
@@ -282,22 +599,153 @@ pub fn reset_password(req: &mut Request) -> IronResult<Response> {
 ///
 /// It requires a `Public` scoped token
 pub fn confirm_email(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_public(req));
+    let mut res = Response::new();
+    let email_key = param!(req, "email_key");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let _ = itry!(db.try_confirm_email(email_key));
+    let _ =
+        res.set_mut(json::encode(&ResponseDTO::new("successfully confirmed email")).unwrap())
+            .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Confirms the new email address of a pending email change, and applies the change.
+///
+/// - Method: `POST`
+/// - URL: `/confirm_email_change/:email_key`
+/// - Scopes: `Public`
+/// - Returns: A succesfful response, an `invalid_key` error if the key is not valid, or an
+///   `email_exists` error if the new email has been taken by another user in the meantime.
+///
+/// It requires a `Public` scoped token
+pub fn confirm_email_change(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_public(req));
     let mut res = Response::new();
     let email_key = param!(req, "email_key");
 
-    if token.is_public() {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-        let _ = itry!(db.try_confirm_email(email_key));
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("successfully confirmed email")).unwrap())
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match db.confirm_email_change(email_key) {
+        Ok((user, old_email)) => {
+            let mut event = audit_event(req,
+                                        token.get_app_id(),
+                                        Some(user.get_id()),
+                                        AuditAction::EmailChange);
+            event.before = Some(old_email);
+            event.after = Some(String::from(user.get_email()));
+            record_audit_event(db, &event);
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully changed email"))
+                    .unwrap())
                 .set_mut(status::Ok);
-    } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(res)
+}
+
+/// Cancels a pending email change, with the key sent to the old address.
+///
+/// - Method: `POST`
+/// - URL: `/cancel_email_change/:email_key`
+/// - Scopes: `Public`
+/// - Returns: A succesfful response, or an `invalid_key` error if the key is not valid.
+///
+/// It requires a `Public` scoped token
+pub fn cancel_email_change(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_public(req));
+    let mut res = Response::new();
+    let email_key = param!(req, "email_key");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match db.cancel_email_change(email_key) {
+        Ok(user_id) => {
+            let event = audit_event(req,
+                                    token.get_app_id(),
+                                    Some(user_id),
+                                    AuditAction::EmailChangeCancelled);
+            record_audit_event(db, &event);
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully cancelled \
+                                                                 email change"))
                     .unwrap())
-                .set_mut(status::Forbidden);
+                .set_mut(status::Ok);
+        }
+        Err(e) => return Err(e.into()),
     }
     Ok(res)
 }
+
+/// Cancels the deletion of an account, with the key sent to the user, and enables it again if
+/// the deletion disabled it.
+///
+/// - Method: `POST`
+/// - URL: `/cancel_account_deletion/:deletion_key`
+/// - Scopes: `Public`
+/// - Returns: A successful response, or an `invalid_key` error if the key is not valid.
+///
+/// It requires a `Public` scoped token
+pub fn cancel_account_deletion(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_public(req));
+    let mut res = Response::new();
+    let deletion_key = param!(req, "deletion_key");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match db.cancel_account_deletion(deletion_key) {
+        Ok(user_id) => {
+            let event = audit_event(req,
+                                    token.get_app_id(),
+                                    Some(user_id),
+                                    AuditAction::AccountDeletionCancelled);
+            record_audit_event(db, &event);
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully cancelled \
+                                                                 account deletion"))
+                    .unwrap())
+                .set_mut(status::Ok);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+    use rand::{thread_rng, Rng};
+
+    use database::Database;
+    use password::PasswordScheme;
+    use super::start_mfa_login;
+
+    /// Logging in with the correct password must not reset the failed attempts of a user with two
+    /// factor authentication, or the second factor could be guessed without ever being locked.
+    ///
+    /// It needs a Redis server on the local host, so it only runs with `cargo test -- --ignored`.
+    #[test]
+    #[ignore]
+    fn correct_password_keeps_failed_attempts() {
+        let db = Database::new("redis://127.0.0.1/").unwrap();
+        let scheme = PasswordScheme::new(4, 1, 1);
+        let name = thread_rng().gen_ascii_chars().take(12).collect::<String>();
+        let email = format!("{}@example.com", name);
+        let user_id = db.create_user_simple(name.as_str(),
+                                  "correct horse battery",
+                                  email.as_str(),
+                                  name.as_str(),
+                                  &scheme)
+            .unwrap();
+        let mut user = db.get_user_by_id(user_id).unwrap().unwrap();
+        user.set_two_factor_enabled(true).unwrap();
+
+        for _ in 0..3 {
+            assert!(!user.is_locked());
+            assert!(user.check_password(&scheme, "correct horse battery").unwrap());
+            let _ = start_mfa_login(&db, "app", &user, false).unwrap();
+            assert!(!user.use_recovery_code("wrong code").unwrap());
+            let _ = user.register_failed_attempt(3, Duration::minutes(5)).unwrap();
+        }
+
+        let user = db.get_user_by_id(user_id).unwrap().unwrap();
+        assert!(user.is_locked());
+        user.delete().unwrap();
+    }
+}
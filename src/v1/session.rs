@@ -0,0 +1,112 @@
+//! Session module. It contains the methods for users to see where they are logged in and their
+//! login history, and to revoke their login sessions.
+use iron::prelude::*;
+use iron::status;
+
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+use dto::ResponseDTO;
+
+use DATABASES;
+use database::{AuditAction, Permission};
+use error::Error;
+use super::{audit_event, record_audit_event};
+use super::auth::{require_token, require_user, require_permission};
+use super::types::{SessionDTO, SessionsDTO, LoginAttemptDTO, LoginHistoryDTO};
+
+/// Gets the active login sessions of the user.
+///
+/// - Method: `GET`
+/// - URL: `/sessions`
+/// - Scopes: `User`
+/// - Returns: a `SessionsDTO` with the sessions of the user, the most recently used first. The
+///   session of the token used in the request is marked as `current`.
+pub fn get_sessions(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let sessions = itry!(db.get_user_sessions(user_id));
+    let dto = SessionsDTO {
+        sessions: sessions.iter()
+            .map(|s| SessionDTO::new(s, token.get_session_id() == Some(s.id.as_str())))
+            .collect(),
+    };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Revokes a login session of the user.
+///
+/// - Method: `DELETE`
+/// - URL: `/session/:session_id`
+/// - Scopes: `User`
+/// - Returns: a successful response if the session was revoked, or a `NotFound` status code if the
+///   user has no active session with that ID.
+///
+/// The token of the session stops working right away. The session of the token used in the
+/// request can be revoked too, to log out.
+pub fn revoke_session(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+    let session_id = param!(req, "session_id");
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match itry!(db.get_session(&session_id)) {
+        Some(ref session) if session.user_id == user_id => {
+            itry!(db.revoke_session(user_id, &session_id));
+            let mut event =
+                audit_event(req, token.get_app_id(), Some(user_id), AuditAction::SessionRevoked);
+            event.before = Some(session_id);
+            record_audit_event(db, &event);
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("session revoked")).unwrap())
+                .set_mut(status::Ok);
+        }
+        _ => return Err(Error::NotFound.into()),
+    }
+    Ok(res)
+}
+
+/// Revokes all the login sessions of the user but the one of the token used in the request.
+///
+/// - Method: `POST`
+/// - URL: `/revoke_other_sessions`
+/// - Scopes: `User`
+/// - Returns: a successful response once the other sessions are revoked.
+///
+/// The user tokens issued without a login session stop working too, including the one used in
+/// the request if it has no session.
+pub fn revoke_other_sessions(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    itry!(db.revoke_all_sessions(user_id, token.get_session_id()));
+    let event =
+        audit_event(req, token.get_app_id(), Some(user_id), AuditAction::OtherSessionsRevoked);
+    record_audit_event(db, &event);
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("other sessions revoked")).unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Gets the login history of the given user.
+///
+/// - Method: `GET`
+/// - URL: `/login_history/:user_id`
+/// - Scopes: `User`, or the `users:read` permission for other users
+/// - Returns: a `LoginHistoryDTO` with the last login attempts of the user, newest first.
+pub fn get_login_history(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
+    if !token.is_user(user_id) {
+        try!(require_permission(&token, Permission::UsersRead));
+    }
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let history = itry!(db.get_login_history(user_id));
+    let dto = LoginHistoryDTO { logins: history.iter().map(LoginAttemptDTO::new).collect() };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
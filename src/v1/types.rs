@@ -0,0 +1,576 @@
+//! Data transfer objects used by this server that are not part of `rest-api-data-types`.
+
+use rustc_serialize::{json, Encodable, Encoder};
+use rustc_serialize::json::{Json, ToJson};
+use dto::UserDTO;
+
+use password::PasswordRule;
+use validation::FieldError;
+use database::{AuditEvent, LoginAttempt, ProfileVisibility, Session, Verification};
+use super::errors::{get_message, Language};
+
+/// Error response, with a stable code to tell the errors apart.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ErrorDTO {
+    /// The code of the error, such as `user_not_found`, that does not change between versions.
+    pub code: String,
+    /// The description of the error, in the language of the request if it is supported.
+    pub message: String,
+    /// The ID of the request, also sent in the `X-Request-Id` header, to find it in the logs.
+    pub request_id: Option<String>,
+}
+
+/// Response to a login of a user with two factor authentication enabled.
+///
+/// The `mfa_token` has to be posted with a valid authenticator code to `/login/mfa/:mfa_token`
+/// to get the `User` scoped token.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct MfaPendingDTO {
+    /// The key of the pending login.
+    pub mfa_token: String,
+    /// Seconds until the pending login expires.
+    pub expiration: i64,
+}
+
+/// A recovery code posted in place of an authenticator code.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct RecoveryCodeDTO {
+    /// The recovery code.
+    pub recovery_code: String,
+}
+
+/// Newly generated recovery codes, that will not be shown again.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct RecoveryCodesDTO {
+    /// The recovery codes.
+    pub recovery_codes: Vec<String>,
+}
+
+/// Number of unused recovery codes of the user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct RecoveryCodesLeftDTO {
+    /// The number of recovery codes left.
+    pub recovery_codes_left: u64,
+}
+
+/// A user with the number of its unused recovery codes.
+///
+/// It is encoded as a single object with the fields of the `UserDTO` and the
+/// `recovery_codes_left` field, so that clients reading a `UserDTO` keep working.
+#[derive(Debug, Clone)]
+pub struct UserDetailsDTO {
+    /// The user.
+    pub user: UserDTO,
+    /// The number of recovery codes left.
+    pub recovery_codes_left: u64,
+}
+
+impl Encodable for UserDetailsDTO {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        let mut object = match Json::from_str(&json::encode(&self.user).unwrap()) {
+            Ok(Json::Object(object)) => object,
+            _ => unreachable!(),
+        };
+        let _ = object.insert(String::from("recovery_codes_left"),
+                              self.recovery_codes_left.to_json());
+        Json::Object(object).encode(s)
+    }
+}
+
+/// Authenticator enrollment data for a new TOTP secret.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AuthenticatorCodeDTO {
+    /// The `otpauth://` URI, for manual entry in the authenticator app.
+    pub otpauth_uri: String,
+    /// The format of the QR code: `png`, `svg` or `ascii`.
+    pub qr_format: String,
+    /// The QR code of the URI, as a data URI for images or as plain text for `ascii`.
+    pub qr_code: String,
+}
+
+/// TOTP settings of a client, overriding the server defaults for its users.
+///
+/// `None` fields keep their current value. An empty issuer goes back to the default one.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ClientTotpSettingsDTO {
+    /// The issuer shown in the authenticator apps.
+    pub issuer: Option<String>,
+    /// The HMAC algorithm: `SHA1`, `SHA256` or `SHA512`.
+    pub algorithm: Option<String>,
+    /// The number of digits of the codes, from 6 to 8.
+    pub digits: Option<u32>,
+    /// The seconds each code is valid for.
+    pub period: Option<u64>,
+}
+
+/// Roles of a user or an OAuth client.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct RolesDTO {
+    /// The roles: `support`, `moderator`, `billing` or `admin`.
+    pub roles: Vec<String>,
+}
+
+/// Request to start the password reset of the account with the given email.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ResetPasswordRequestDTO {
+    /// The email address of the account.
+    pub email: String,
+}
+
+/// Request to send a passwordless login link to the account with the given email.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct MagicLinkRequestDTO {
+    /// The email address of the account.
+    pub email: String,
+    /// Whether the user wants to be remembered once logged in.
+    pub remember_me: bool,
+}
+
+/// Request to delete the account of the user, confirmed with its password.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AccountDeletionDTO {
+    /// The current password of the user.
+    pub password: String,
+}
+
+/// A verification code sent by SMS to the phone of the user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PhoneCodeDTO {
+    /// The verification code.
+    pub code: String,
+}
+
+/// A password policy rule that the password did not meet.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PasswordRuleDTO {
+    /// The machine readable code of the rule, such as `min_length`.
+    pub code: String,
+    /// The description of the rule.
+    pub message: String,
+}
+
+/// Response for a password that does not meet the password policy.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PasswordPolicyErrorDTO {
+    /// The code of the error, always `weak_password`.
+    pub code: String,
+    /// The error message.
+    pub message: String,
+    /// Every rule the password did not meet.
+    pub failed_rules: Vec<PasswordRuleDTO>,
+}
+
+impl PasswordPolicyErrorDTO {
+    /// Creates the response for the given failed rules.
+    pub fn new(failed_rules: &[PasswordRule]) -> PasswordPolicyErrorDTO {
+        PasswordPolicyErrorDTO {
+            code: String::from("weak_password"),
+            message: String::from(get_message("weak_password", Language::English)),
+            failed_rules: failed_rules.iter()
+                .map(|rule| {
+                    PasswordRuleDTO {
+                        code: String::from(rule.get_code()),
+                        message: format!("{}", rule),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A user input field that did not pass validation.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct FieldErrorDTO {
+    /// The name of the field, such as `username` or `address.zip`.
+    pub field: String,
+    /// The description of the error.
+    pub message: String,
+}
+
+/// Response for user input that did not pass validation.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ValidationErrorDTO {
+    /// The code of the error, always `validation_error`.
+    pub code: String,
+    /// The error message.
+    pub message: String,
+    /// The errors of each invalid field.
+    pub errors: Vec<FieldErrorDTO>,
+}
+
+impl ValidationErrorDTO {
+    /// Creates the response for the given field errors.
+    pub fn new(errors: &[FieldError]) -> ValidationErrorDTO {
+        ValidationErrorDTO {
+            code: String::from("validation_error"),
+            message: String::from(get_message("validation_error", Language::English)),
+            errors: errors.iter()
+                .map(|e| {
+                    FieldErrorDTO {
+                        field: String::from(e.field),
+                        message: e.message.clone(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// An email change waiting for the new address to be confirmed.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PendingEmailChangeDTO {
+    /// The ID of the user.
+    pub user_id: u64,
+    /// The current email of the user.
+    pub email: String,
+    /// The new email, not confirmed yet.
+    pub new_email: String,
+    /// UNIX timestamp of when the change was requested.
+    pub requested: i64,
+}
+
+/// The pending email changes of all users.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct PendingEmailChangesDTO {
+    /// The pending email changes.
+    pub pending_email_changes: Vec<PendingEmailChangeDTO>,
+}
+
+/// A document uploaded with a verification request.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AttachmentUploadDTO {
+    /// The file name.
+    pub name: String,
+    /// The MIME type: `image/png`, `image/jpeg` or `application/pdf`.
+    pub content_type: String,
+    /// The contents of the file, in base 64.
+    pub data: String,
+}
+
+/// Request to verify some personal fields of the user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VerificationRequestDTO {
+    /// The fields to verify: `first_name`, `last_name`, `birthday` or `address`.
+    pub fields: Vec<String>,
+    /// The supporting documents, such as an ID card or a utility bill.
+    pub attachments: Vec<AttachmentUploadDTO>,
+}
+
+/// Review of a verification request by an admin.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VerificationReviewDTO {
+    /// Whether the request is approved.
+    pub approve: bool,
+    /// The reason, required to reject the request.
+    pub reason: Option<String>,
+}
+
+/// A document attached to a verification request.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AttachmentDTO {
+    /// The index of the attachment, to download it.
+    pub index: usize,
+    /// The file name.
+    pub name: String,
+    /// The MIME type.
+    pub content_type: String,
+}
+
+/// A state transition of a verification request.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VerificationEventDTO {
+    /// UNIX timestamp of the transition.
+    pub time: i64,
+    /// The new status.
+    pub status: String,
+    /// Who made the transition.
+    pub actor: String,
+    /// The reason given for the transition, if any.
+    pub reason: Option<String>,
+}
+
+/// An identity verification request.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VerificationDTO {
+    /// The ID of the request.
+    pub id: u64,
+    /// The ID of the user.
+    pub user_id: u64,
+    /// The fields to confirm.
+    pub fields: Vec<String>,
+    /// The status: `pending`, `approved` or `rejected`.
+    pub status: String,
+    /// UNIX timestamp of when the request was submitted.
+    pub created: i64,
+    /// UNIX timestamp of when the request was reviewed, if it was.
+    pub reviewed: Option<i64>,
+    /// The reason of the rejection, if it was rejected.
+    pub reason: Option<String>,
+    /// The attached documents.
+    pub attachments: Vec<AttachmentDTO>,
+    /// Every state transition of the request.
+    pub history: Vec<VerificationEventDTO>,
+}
+
+impl VerificationDTO {
+    /// Creates the DTO of the verification request.
+    pub fn new(verification: &Verification) -> VerificationDTO {
+        VerificationDTO {
+            id: verification.id,
+            user_id: verification.user_id,
+            fields: verification.fields.iter().map(|&(f, _)| format!("{}", f)).collect(),
+            status: format!("{}", verification.status),
+            created: verification.created.timestamp(),
+            reviewed: verification.reviewed.map(|r| r.timestamp()),
+            reason: verification.reason.clone(),
+            attachments: verification.attachments
+                .iter()
+                .enumerate()
+                .map(|(i, a)| {
+                    AttachmentDTO {
+                        index: i,
+                        name: a.name.clone(),
+                        content_type: a.content_type.clone(),
+                    }
+                })
+                .collect(),
+            history: verification.history
+                .iter()
+                .map(|e| {
+                    VerificationEventDTO {
+                        time: e.time,
+                        status: e.status.clone(),
+                        actor: e.actor.clone(),
+                        reason: e.reason.clone(),
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// A list of verification requests.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct VerificationsDTO {
+    /// The verification requests.
+    pub verifications: Vec<VerificationDTO>,
+}
+
+/// An administrative action on a user account.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AdminActionDTO {
+    /// The reason for the action, recorded in the audit log.
+    pub reason: String,
+    /// UNIX timestamp when a ban expires. Bans without it do not expire.
+    pub until: Option<i64>,
+}
+
+/// An event of the audit log.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AuditEventDTO {
+    /// The ID of the event.
+    pub id: String,
+    /// UNIX timestamp of the event.
+    pub time: i64,
+    /// The app ID of the token that performed the action.
+    pub app_id: String,
+    /// The IP address the request came from, if known.
+    pub ip: Option<String>,
+    /// The user the action was performed on, if any.
+    pub user_id: Option<u64>,
+    /// The action.
+    pub action: String,
+    /// The value before the action, if it changed one.
+    pub before: Option<String>,
+    /// The value after the action, if it changed one.
+    pub after: Option<String>,
+    /// The reason given for the action, if any.
+    pub reason: Option<String>,
+}
+
+impl AuditEventDTO {
+    /// Creates the DTO of the audit event.
+    pub fn new(event: &AuditEvent) -> AuditEventDTO {
+        AuditEventDTO {
+            id: event.id.clone(),
+            time: event.time.timestamp(),
+            app_id: event.app_id.clone(),
+            ip: event.ip.clone(),
+            user_id: event.user_id,
+            action: format!("{}", event.action),
+            before: event.before.clone(),
+            after: event.after.clone(),
+            reason: event.reason.clone(),
+        }
+    }
+}
+
+/// A page of the audit log, newest first.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AuditEventsDTO {
+    /// The events.
+    pub events: Vec<AuditEventDTO>,
+}
+
+/// A login session of the user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct SessionDTO {
+    /// The ID of the session.
+    pub id: String,
+    /// ID of the client application the user logged in with.
+    pub app_id: String,
+    /// The user agent of the device the user logged in from, if it was sent.
+    pub user_agent: Option<String>,
+    /// The IP address the user logged in from.
+    pub ip: Option<String>,
+    /// UNIX timestamp of the login.
+    pub created: i64,
+    /// UNIX timestamp of the last use of the session.
+    pub last_seen: i64,
+    /// Whether the user asked to be remembered.
+    pub remember_me: bool,
+    /// UNIX timestamp when the session expires.
+    pub expiration: i64,
+    /// Whether it is the session of the token used in the request.
+    pub current: bool,
+}
+
+impl SessionDTO {
+    /// Creates the DTO of the session.
+    pub fn new(session: &Session, current: bool) -> SessionDTO {
+        SessionDTO {
+            id: session.id.clone(),
+            app_id: session.app_id.clone(),
+            user_agent: session.user_agent.clone(),
+            ip: session.ip.clone(),
+            created: session.created.timestamp(),
+            last_seen: session.last_seen.timestamp(),
+            remember_me: session.remember_me,
+            expiration: session.expiration.timestamp(),
+            current: current,
+        }
+    }
+}
+
+/// The active login sessions of the user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct SessionsDTO {
+    /// The sessions, the most recently used first.
+    pub sessions: Vec<SessionDTO>,
+}
+
+/// A login attempt in the login history of a user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct LoginAttemptDTO {
+    /// UNIX timestamp of the attempt.
+    pub time: i64,
+    /// The IP address the attempt came from.
+    pub ip: Option<String>,
+    /// The user agent of the device, if it was sent.
+    pub user_agent: Option<String>,
+    /// ID of the client application used to log in.
+    pub app_id: String,
+    /// Whether the login succeeded.
+    pub success: bool,
+    /// Why the login failed, if it did.
+    pub reason: Option<String>,
+}
+
+impl LoginAttemptDTO {
+    /// Creates the DTO of the login attempt.
+    pub fn new(attempt: &LoginAttempt) -> LoginAttemptDTO {
+        LoginAttemptDTO {
+            time: attempt.time,
+            ip: attempt.ip.clone(),
+            user_agent: attempt.user_agent.clone(),
+            app_id: attempt.app_id.clone(),
+            success: attempt.success,
+            reason: attempt.reason.clone(),
+        }
+    }
+}
+
+/// The login history of a user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct LoginHistoryDTO {
+    /// The login attempts, newest first.
+    pub logins: Vec<LoginAttemptDTO>,
+}
+
+/// The profile image uploaded by the user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct AvatarDTO {
+    /// URL of the largest size of the image, set as the profile image of the user.
+    pub image_url: String,
+    /// The widths in pixels the image is available in, replacing the last segment of the URL.
+    pub sizes: Vec<u32>,
+}
+
+/// Which fields of the public profile of the user are shown.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct ProfileVisibilityDTO {
+    /// Whether the whole profile is hidden.
+    pub hidden: bool,
+    /// Whether the first name is shown.
+    pub first_name: bool,
+    /// Whether the last name is shown.
+    pub last_name: bool,
+    /// Whether the profile image is shown.
+    pub image: bool,
+    /// Whether the age is shown.
+    pub age: bool,
+    /// Whether the city, state and country are shown.
+    pub address: bool,
+}
+
+impl ProfileVisibilityDTO {
+    /// Creates the DTO of the visibility settings.
+    pub fn new(visibility: &ProfileVisibility) -> ProfileVisibilityDTO {
+        ProfileVisibilityDTO {
+            hidden: visibility.hidden,
+            first_name: visibility.first_name,
+            last_name: visibility.last_name,
+            image: visibility.image,
+            age: visibility.age,
+            address: visibility.address,
+        }
+    }
+}
+
+/// The two factor authentication state of a user, without its authenticator secret.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct TwoFactorStateDTO {
+    /// Whether two factor authentication is enabled.
+    pub enabled: bool,
+    /// HMAC algorithm of the authenticator codes.
+    pub algorithm: String,
+    /// Number of digits of the authenticator codes.
+    pub digits: u32,
+    /// Seconds each authenticator code is valid for.
+    pub period: u64,
+    /// Number of unused recovery codes.
+    pub recovery_codes_left: u64,
+}
+
+/// Export of all the personal data stored about a user.
+#[derive(Debug, Clone, RustcEncodable, RustcDecodable)]
+pub struct DataExportDTO {
+    /// UNIX timestamp of when the export was prepared.
+    pub generated: i64,
+    /// The user, with its address.
+    pub user: UserDTO,
+    /// Which fields of the public profile of the user are shown.
+    pub profile_visibility: ProfileVisibilityDTO,
+    /// The two factor authentication state of the user.
+    pub two_factor: TwoFactorStateDTO,
+    /// The pending email change of the user, if any.
+    pub pending_email_change: Option<PendingEmailChangeDTO>,
+    /// The active login sessions of the user.
+    pub sessions: Vec<SessionDTO>,
+    /// The login history of the user, newest first.
+    pub login_history: Vec<LoginAttemptDTO>,
+    /// The identity verification requests of the user, newest first.
+    pub verifications: Vec<VerificationDTO>,
+    /// The audit log events affecting the user, newest first.
+    pub audit_events: Vec<AuditEventDTO>,
+}
@@ -4,15 +4,33 @@ use std::io::Read;
 use iron::prelude::*;
 use iron::status;
 
+use chrono::UTC;
 use rand::{thread_rng, Rng};
 use rustc_serialize::json;
 use rustc_serialize::base64::{ToBase64, URL_SAFE};
-use dto::{AuthenticationCodeDTO, ResponseDTO, UpdateUserDTO, ScopeDTO as Scope, UserDTO};
-
-use {DATABASES, EMAILS};
-use super::{QRCODES_PATH, create_barcode};
+use qrcode::EcLevel;
+use dto::{AuthenticationCodeDTO, ResponseDTO, UpdateUserDTO, UserDTO};
+
+use {DATABASES, EMAILS, CONFIG, SMS, BLOBS};
+use database::{AuditAction, AuditEvent, Database, Permission, User};
+use error::{Error, Result};
+use super::{BarcodeFormat, get_authenticator_uri, create_barcode, parse_ec_level, audit_event,
+            record_audit_event, register_failed_attempt, locked_error, invalid_field};
+use super::auth::{AuthError, require_token, require_user, require_permission};
+use super::avatar::delete_avatar_blobs;
+use super::types::{RecoveryCodesDTO, RecoveryCodesLeftDTO, UserDetailsDTO, AuthenticatorCodeDTO,
+                   PasswordPolicyErrorDTO, ValidationErrorDTO, PendingEmailChangeDTO,
+                   PendingEmailChangesDTO, PhoneCodeDTO, AccountDeletionDTO};
 use utils::{EmailStruct, EmailType};
+use validation;
+use sms;
 
+/// Default minimum width of the authenticator QR codes, in pixels.
+const DEFAULT_BARCODE_SIZE: u32 = 200;
+/// Minimum width that can be requested for the authenticator QR codes, in pixels.
+const MIN_BARCODE_SIZE: u32 = 100;
+/// Maximum width that can be requested for the authenticator QR codes, in pixels.
+const MAX_BARCODE_SIZE: u32 = 1000;
 
 /// Gets resends the email confirmation.
 ///
@@ -23,45 +41,28 @@ use utils::{EmailStruct, EmailType};
 /// Requires `User` scoped token. It will take the id from the user scope and send an email to that
 /// user.
 pub fn resend_email_confirmation(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let (_, user_id) = try!(require_user(req));
     let mut res = Response::new();
 
-    let mut user_id = None;
-    for scope in token.get_scopes() {
-        match scope {
-            &Scope::User(id) => user_id = Some(id),
-            _ => {}
-        }
-    }
-
-    if let Some(user_id) = user_id {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-        let mut email_key = [0u8; 5];
-        thread_rng().fill_bytes(&mut email_key[0..]);
-        let email_str = email_key.to_base64(URL_SAFE);
-        let _ = itry!(db.start_confirm_email(user_id, &email_str));
-        if let Some(user) = itry!(db.get_user_by_id(user_id)) {
-            let email = EmailStruct {
-                email: String::from(user.get_email()),
-                email_key: email_str,
-                email_type: EmailType::Email,
-            };
-            EMAILS.lock().unwrap().push(email);
-            let _ =
-                res.set_mut(json::encode(&ResponseDTO::new("successfully resent confirmation \
-                                                             email"))
-                        .unwrap())
-                    .set_mut(status::Ok);
-        } else {
-            let _ = res.set_mut(json::encode(&ResponseDTO::new("the user was deleted")).unwrap())
-                .set_mut(status::Forbidden);
-        }
-    } else {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let mut email_key = [0u8; 5];
+    thread_rng().fill_bytes(&mut email_key[0..]);
+    let email_str = email_key.to_base64(URL_SAFE);
+    let _ = itry!(db.start_confirm_email(user_id, &email_str));
+    if let Some(user) = itry!(db.get_user_by_id(user_id)) {
+        let email = EmailStruct {
+            email: String::from(user.get_email()),
+            email_key: email_str,
+            email_type: EmailType::Email,
+        };
+        EMAILS.lock().unwrap().push(email);
         let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
+            res.set_mut(json::encode(&ResponseDTO::new("successfully resent confirmation \
+                                                         email"))
                     .unwrap())
-                .set_mut(status::Forbidden);
+                .set_mut(status::Ok);
+    } else {
+        return Err(Error::UserDoesNotExist.into());
     }
 
     Ok(res)
@@ -75,46 +76,33 @@ pub fn resend_email_confirmation(req: &mut Request) -> IronResult<Response> {
 /// - Returns: a successfully response. If the user posts the correct code within the given 30 sec
 ///   time frame using TOTP
 pub fn authenticate(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
-    let mut user_id = None;
-    for scope in token.get_scopes() {
-        match scope {
-            &Scope::User(id) => user_id = Some(id),
-            _ => {}
-        }
-    }
+    let (token, user_id) = try!(require_user(req));
     let mut res = Response::new();
-    if let Some(user_id) = user_id {
-        let mut authentication_str = String::new();
-        let _ = req.body.read_to_string(&mut authentication_str);
-        let code_dto = itry!(json::decode::<AuthenticationCodeDTO>(&authentication_str),
-                             status::BadRequest);
+    let mut authentication_str = String::new();
+    let _ = req.body.read_to_string(&mut authentication_str);
+    let code_dto = itry!(json::decode::<AuthenticationCodeDTO>(&authentication_str),
+                         status::BadRequest);
 
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-
-        if let Some(user) = itry!(db.get_user_by_id(user_id)) {
-            if user.check_authenticator_code(code_dto.code) {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
 
-                let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully authenticated"))
-                        .unwrap())
-                    .set_mut(status::Ok);
-            } else {
-                let _ =
-                    res.set_mut(json::encode(&ResponseDTO::new("Unsuccessful authentication \
-                                                                 code"))
-                            .unwrap())
-                        .set_mut(status::Accepted);
-            }
+    if let Some(mut user) = itry!(db.get_user_by_id(user_id)) {
+        if user.is_locked() {
+            return Err(locked_error(&user));
+        } else if itry!(user.check_authenticator_code(&CONFIG.get_totp(), code_dto.code)) {
+            itry!(user.clear_failed_attempts());
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully authenticated"))
+                    .unwrap())
+                .set_mut(status::Ok);
         } else {
-            let _ = res.set_mut(json::encode(&ResponseDTO::new("the user was deleted")).unwrap())
-                .set_mut(status::Forbidden);
+            itry!(register_failed_attempt(req,
+                                          db,
+                                          token.get_app_id(),
+                                          &mut user,
+                                          "incorrect authenticator code"));
+            return Err(Error::IncorrectCode.into());
         }
     } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
-                    .unwrap())
-                .set_mut(status::Forbidden);
+        return Err(Error::UserDoesNotExist.into());
     }
 
     Ok(res)
@@ -124,44 +112,218 @@ pub fn authenticate(req: &mut Request) -> IronResult<Response> {
 /// Gets the authenticator QR code.
 ///
 /// - Method: `GET`
-/// - URL: `/generate_authenticator_code
-/// - Returns: URL to the QR code.
+/// - URL: `/generate_authenticator_code?format=png&size=200&ec=M`
+/// - Returns: an `AuthenticatorCodeDTO` with the `otpauth://` URI and its QR code.
 ///
-/// Requires `User` scoped token. It will take the id from the user scope and send an email to that
-/// user.
+/// Requires `User` scoped token. It will take the id from the user scope and generate a new
+/// authenticator secret for that user, with the TOTP settings of the client the token belongs to.
+///
+/// The optional `format` can be `png`, `svg` or `ascii`, `size` is the minimum width in pixels of
+/// the images, and `ec` the error correction level, one of `L`, `M`, `Q` or `H`.
 pub fn generate_authenticator_code(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
-    let mut user_id = None;
-    for scope in token.get_scopes() {
-        match scope {
-            &Scope::User(id) => user_id = Some(id),
-            _ => {}
+    let (token, user_id) = try!(require_user(req));
+    let format_str = query_param!(req, "format").unwrap_or(String::from("png"));
+    let format = match format_str.parse::<BarcodeFormat>() {
+        Ok(format) => format,
+        Err(_) => return invalid_field("format", "the format must be png, svg or ascii"),
+    };
+    let size = match query_param!(req, "size") {
+        Some(size) => itry!(size.parse::<u32>(), status::BadRequest),
+        None => DEFAULT_BARCODE_SIZE,
+    };
+    if size < MIN_BARCODE_SIZE || size > MAX_BARCODE_SIZE {
+        return invalid_field("size",
+                             format!("the size must be between {} and {} pixels",
+                                     MIN_BARCODE_SIZE,
+                                     MAX_BARCODE_SIZE));
+    }
+    let ec_level = match query_param!(req, "ec") {
+        Some(ec) => {
+            match parse_ec_level(ec) {
+                Some(ec_level) => ec_level,
+                None => {
+                    return invalid_field("ec", "the error correction level must be L, M, Q or H")
+                }
+            }
+        }
+        None => EcLevel::M,
+    };
+    let mut res = Response::new();
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(mut user) = itry!(db.get_user_by_id(user_id)) {
+        if user.is_two_factor_enabled() {
+            return Err(Error::TwoFactorEnabled.into());
         }
+        let (issuer, totp) = match itry!(db.get_client(token.get_app_id())) {
+            Some(client) => {
+                (String::from(client.get_totp_issuer().unwrap_or(CONFIG.get_totp_issuer())),
+                 client.get_totp(&CONFIG.get_totp()))
+            }
+            None => (String::from(CONFIG.get_totp_issuer()), CONFIG.get_totp()),
+        };
+        let secret = itry!(user.get_authenticator_secret(&totp));
+        let uri = get_authenticator_uri(&secret, issuer, user.get_email(), &totp);
+        let qr_code = itry!(create_barcode(&uri, format, size, ec_level));
+        let code_dto = AuthenticatorCodeDTO {
+            otpauth_uri: uri,
+            qr_format: format.to_string(),
+            qr_code: qr_code,
+        };
+        let _ = res.set_mut(json::encode(&code_dto).unwrap()).set_mut(status::Ok);
+    } else {
+        return Err(Error::UserDoesNotExist.into());
     }
+    Ok(res)
+}
+
+/// Enables two factor authentication for the user.
+///
+/// - Method: `POST`
+/// - URL: `/enable_two_factor`
+/// - Scopes: `User`
+/// - Returns: a `RecoveryCodesDTO` with the new recovery codes if the posted code is correct for
+///   the secret generated in `/generate_authenticator_code`, or a `Forbidden` status code with
+///   the `invalid_code` error if it was not.
+///
+/// Confirming a first code makes sure the user enrolled the secret correctly before requiring it
+/// to log in. The recovery codes are only shown once.
+pub fn enable_two_factor(req: &mut Request) -> IronResult<Response> {
+    set_two_factor(req, true)
+}
+
+/// Disables two factor authentication for the user.
+///
+/// - Method: `POST`
+/// - URL: `/disable_two_factor`
+/// - Scopes: `User`
+/// - Returns: a successful response if the posted code is correct, or a `Forbidden` status code
+///   with the `invalid_code` error if it was not.
+pub fn disable_two_factor(req: &mut Request) -> IronResult<Response> {
+    set_two_factor(req, false)
+}
+
+/// Enables or disables two factor authentication after checking the posted authenticator code.
+fn set_two_factor(req: &mut Request, enabled: bool) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
     let mut res = Response::new();
-    if let Some(user_id) = user_id {
-        let db = thread_rng().choose(&DATABASES[..]).unwrap();
-        if let Some(mut user) = itry!(db.get_user_by_id(user_id)) {
-            let secret = itry!(user.get_authenticator_secret());
-            let _ = itry!(create_barcode(&secret,
-                                         user.get_email(),
-                                         format!("{}{}.png", QRCODES_PATH, &secret[..10])));
-            let _ = res.set_mut(json::encode(&ResponseDTO::new(format!("http://www.mydomain.\
-                                                                 com/qrcodes/{}.png",
-                                                                &secret[..10])))
-                    .unwrap())
-                .set_mut(status::Ok);
+    let mut authentication_str = String::new();
+    let _ = req.body.read_to_string(&mut authentication_str);
+    let code_dto = itry!(json::decode::<AuthenticationCodeDTO>(&authentication_str),
+                         status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(mut user) = itry!(db.get_user_by_id(user_id)) {
+        if user.is_two_factor_enabled() == enabled {
+            return Err(if enabled {
+                    Error::TwoFactorEnabled
+                } else {
+                    Error::TwoFactorNotEnabled
+                }
+                .into());
+        } else if user.is_locked() {
+            return Err(locked_error(&user));
+        } else if itry!(user.check_authenticator_code(&CONFIG.get_totp(), code_dto.code)) {
+            itry!(user.clear_failed_attempts());
+            itry!(user.set_two_factor_enabled(enabled));
+            let event = audit_event(req,
+                                    token.get_app_id(),
+                                    Some(user_id),
+                                    if enabled {
+                                        AuditAction::TwoFactorEnabled
+                                    } else {
+                                        AuditAction::TwoFactorDisabled
+                                    });
+            record_audit_event(db, &event);
+            if enabled {
+                let codes_dto = RecoveryCodesDTO {
+                    recovery_codes: itry!(user.generate_recovery_codes()),
+                };
+                let _ = res.set_mut(json::encode(&codes_dto).unwrap()).set_mut(status::Ok);
+            } else {
+                itry!(user.delete_recovery_codes());
+                let _ =
+                    res.set_mut(json::encode(&ResponseDTO::new("two factor authentication \
+                                                                 disabled"))
+                            .unwrap())
+                        .set_mut(status::Ok);
+            }
         } else {
-            let _ = res.set_mut(json::encode(&ResponseDTO::new("the user was deleted")).unwrap())
-                .set_mut(status::Forbidden);
+            itry!(register_failed_attempt(req,
+                                          db,
+                                          token.get_app_id(),
+                                          &mut user,
+                                          "incorrect authenticator code"));
+            return Err(Error::IncorrectCode.into());
         }
     } else {
-        let _ =
-            res.set_mut(json::encode(&ResponseDTO::new("token does not have correct \
-                                                         permissions"))
-                    .unwrap())
-                .set_mut(status::Forbidden);
+        return Err(Error::UserDoesNotExist.into());
     }
+
+    Ok(res)
+}
+
+/// Generates a new set of recovery codes, invalidating the previous ones.
+///
+/// - Method: `POST`
+/// - URL: `/regenerate_recovery_codes`
+/// - Scopes: `User`
+/// - Returns: a `RecoveryCodesDTO` with the new recovery codes if the posted authenticator code is
+///   correct, a `Forbidden` status code if it was not, or a `Conflict` status code if two factor
+///   authentication is disabled.
+pub fn regenerate_recovery_codes(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+    let mut authentication_str = String::new();
+    let _ = req.body.read_to_string(&mut authentication_str);
+    let code_dto = itry!(json::decode::<AuthenticationCodeDTO>(&authentication_str),
+                         status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(mut user) = itry!(db.get_user_by_id(user_id)) {
+        if !user.is_two_factor_enabled() {
+            return Err(Error::TwoFactorNotEnabled.into());
+        } else if user.is_locked() {
+            return Err(locked_error(&user));
+        } else if itry!(user.check_authenticator_code(&CONFIG.get_totp(), code_dto.code)) {
+            itry!(user.clear_failed_attempts());
+            let codes_dto = RecoveryCodesDTO {
+                recovery_codes: itry!(user.generate_recovery_codes()),
+            };
+            let _ = res.set_mut(json::encode(&codes_dto).unwrap()).set_mut(status::Ok);
+        } else {
+            itry!(register_failed_attempt(req,
+                                          db,
+                                          token.get_app_id(),
+                                          &mut user,
+                                          "incorrect authenticator code"));
+            return Err(Error::IncorrectCode.into());
+        }
+    } else {
+        return Err(Error::UserDoesNotExist.into());
+    }
+
+    Ok(res)
+}
+
+/// Gets the number of unused recovery codes of the user.
+///
+/// - Method: `GET`
+/// - URL: `/recovery_codes_left`
+/// - Scopes: `User`
+/// - Returns: a `RecoveryCodesLeftDTO` with the number of unused recovery codes.
+pub fn recovery_codes_left(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    if let Some(user) = itry!(db.get_user_by_id(user_id)) {
+        let left_dto = RecoveryCodesLeftDTO {
+            recovery_codes_left: itry!(user.get_recovery_codes_left()),
+        };
+        let _ = res.set_mut(json::encode(&left_dto).unwrap()).set_mut(status::Ok);
+    } else {
+        return Err(Error::UserDoesNotExist.into());
+    }
+
     Ok(res)
 }
 
@@ -169,33 +331,32 @@ pub fn generate_authenticator_code(req: &mut Request) -> IronResult<Response> {
 ///
 /// - Method: `GET`
 /// - URL: `/user/:user_id`
-/// - Scopes: `Admin`, `User`
-/// - Returns: the `UserDTO` object with all the information about the user if successful, or an
-///   `Accepted` status code with the error response if the user does not exist.
+/// - Scopes: `User`, or the `users:read` permission for other users
+/// - Returns: the `UserDTO` object with all the information about the user and a
+///   `recovery_codes_left` field with the number of unused recovery codes if successful, or a
+///   `NotFound` status code if the user does not exist.
 ///
 /// In the case of using a `User` token, it will need to have the same ID as the user being
 /// requested.
 pub fn get_user(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_token(req));
     let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
 
-    let mut res = Response::new();
-    if !token.is_admin() && !token.is_user(user_id) {
-        let _ = res.set_mut(json::encode(&ResponseDTO::new("unauthorized scope")).unwrap())
-            .set_mut(status::Forbidden);
-        return Ok(res);
+    if !token.is_user(user_id) {
+        try!(require_permission(&token, Permission::UsersRead));
     }
+    let mut res = Response::new();
 
     let db = thread_rng().choose(&DATABASES[..]).unwrap();
     match db.get_user_by_id(user_id) {
         Ok(Some(user)) => {
-            let _ = res.set_mut(json::encode::<UserDTO>(&user.into()).unwrap()).set_mut(status::Ok);
-        }
-        Ok(None) => {
-            let _ = res.set_mut(json::encode(&ResponseDTO::new("deleted user")).unwrap())
-                .set_mut(status::Accepted);
-            return Ok(res);
+            let dto = UserDetailsDTO {
+                recovery_codes_left: itry!(user.get_recovery_codes_left()),
+                user: user.into(),
+            };
+            let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
         }
+        Ok(None) => return Err(Error::UserDoesNotExist.into()),
         Err(e) => {
             println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
             itry!(Err(e));
@@ -209,17 +370,13 @@ pub fn get_user(req: &mut Request) -> IronResult<Response> {
 ///
 /// - Method: `GET`
 /// - URL: `/all_users`
-/// - Scopes: `Admin`
+/// - Scopes: the `users:read` permission
 /// - Returns: a list of `UserDTO` objects with all the information about the users if successful.
 pub fn get_all_users(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_token(req));
 
     let mut res = Response::new();
-    if !token.is_admin() {
-        let _ = res.set_mut(json::encode(&ResponseDTO::new("unauthorized scope")).unwrap())
-            .set_mut(status::Forbidden);
-        return Ok(res);
-    }
+    try!(require_permission(&token, Permission::UsersRead));
 
     let db = thread_rng().choose(&DATABASES[..]).unwrap();
     let users = match db.get_all_user_ids() {
@@ -250,25 +407,25 @@ pub fn get_all_users(req: &mut Request) -> IronResult<Response> {
 ///
 /// - Method: `DELETE`
 /// - URL: `/user/:user_id`
-/// - Scopes: `Admin`
+/// - Scopes: the `users:delete` permission
 /// - Returns: an `OK` status code if the removal is successful, or a `NotFound` status code if the
 ///   a user with the given ID was not found.
 pub fn delete_user(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_token(req));
     let mut res = Response::new();
-    if !token.is_admin() {
-        let _ = res.set_mut(json::encode(&ResponseDTO::new("unauthorized scope")).unwrap())
-            .set_mut(status::Forbidden);
-        return Ok(res);
-    }
+    try!(require_permission(&token, Permission::UsersDelete));
 
     let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
 
     let db = thread_rng().choose(&DATABASES[..]).unwrap();
     match db.get_user_by_id(user_id) {
         Ok(Some(user)) => {
-            match user.delete() {
+            let mut event =
+                audit_event(req, token.get_app_id(), Some(user_id), AuditAction::DeleteUser);
+            event.before = Some(String::from(user.get_username()));
+            match purge_user(db, user) {
                 Ok(_) => {
+                    record_audit_event(db, &event);
                     let _ = res.set_mut(json::encode(&ResponseDTO::new("user deleted")).unwrap())
                         .set_mut(status::Ok);
                 }
@@ -278,10 +435,7 @@ pub fn delete_user(req: &mut Request) -> IronResult<Response> {
                 }
             }
         }
-        Ok(None) => {
-            let _ = res.set_mut(json::encode(&ResponseDTO::new("user not found")).unwrap())
-                .set_mut(status::NotFound);
-        }
+        Ok(None) => return Err(Error::UserDoesNotExist.into()),
         Err(e) => {
             println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
             itry!(Err(e));
@@ -291,125 +445,444 @@ pub fn delete_user(req: &mut Request) -> IronResult<Response> {
     Ok(res)
 }
 
+/// Requests the deletion of the account of the user.
+///
+/// - Method: `POST`
+/// - URL: `/delete_account`
+/// - Scopes: `User`
+/// - Returns: a successful response if the deletion was scheduled, or a `Forbidden` status code
+///   if the password was not correct.
+///
+/// The `AccountDeletionDTO` must be in the body. The account is disabled and all of its tokens are
+/// revoked right away, and it is purged once the grace period ends, unless the deletion is
+/// cancelled with the link sent by email.
+pub fn delete_account(req: &mut Request) -> IronResult<Response> {
+    let (token, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+    let mut body = String::new();
+    let _ = itry!(req.body.read_to_string(&mut body));
+    let dto = itry!(json::decode::<AccountDeletionDTO>(&body), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let mut user = match itry!(db.get_user_by_id(user_id)) {
+        Some(user) => user,
+        None => return Err(Error::UserDoesNotExist.into()),
+    };
+    if user.is_locked() {
+        return Err(locked_error(&user));
+    }
+    if !itry!(user.check_password(&CONFIG.get_password_scheme(), dto.password)) {
+        itry!(register_failed_attempt(req,
+                                      db,
+                                      token.get_app_id(),
+                                      &mut user,
+                                      "incorrect password"));
+        return Err(Error::InvalidCredentials.into());
+    }
+
+    itry!(user.clear_failed_attempts());
+    let mut cancel_key = [0u8; 24];
+    thread_rng().fill_bytes(&mut cancel_key[0..]);
+    let cancel_key = cancel_key.to_base64(URL_SAFE);
+    let purge_at = itry!(user.start_account_deletion(&cancel_key,
+                                                     CONFIG.get_account_deletion_grace()));
+    itry!(user.revoke_sessions());
+
+    let mut event =
+        audit_event(req, token.get_app_id(), Some(user_id), AuditAction::AccountDeletionRequested);
+    event.after = Some(purge_at.to_rfc3339());
+    record_audit_event(db, &event);
+    let email = EmailStruct {
+        email: String::from(user.get_email()),
+        email_key: cancel_key,
+        email_type: EmailType::AccountDeletion(purge_at),
+    };
+    EMAILS.lock().unwrap().push(email);
+
+    let _ = res.set_mut(json::encode(&ResponseDTO::new(format!("the account will be deleted on \
+                                                                 {}",
+                                                                purge_at)))
+            .unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Purges the accounts whose deletion grace period has ended, recording each deletion in the
+/// audit log.
+///
+/// The scheduled deletions of users that no longer exist are dropped. An account that cannot be
+/// purged is logged and tried again in the next run, without stopping the purge of the others.
+pub fn purge_deleted_accounts() -> Result<()> {
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    for user_id in try!(db.get_due_account_deletions(UTC::now())) {
+        if let Err(e) = purge_deleted_account(db, user_id) {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+        }
+    }
+    Ok(())
+}
+
+/// Purges the given account whose deletion grace period has ended.
+fn purge_deleted_account(db: &Database, user_id: u64) -> Result<()> {
+    match try!(db.get_user_by_id(user_id)) {
+        Some(user) => {
+            let mut event = AuditEvent::new("system", None, Some(user_id), AuditAction::DeleteUser);
+            event.before = Some(String::from(user.get_username()));
+            event.reason = Some(String::from("account deletion grace period ended"));
+            try!(purge_user(db, user));
+            record_audit_event(db, &event);
+            Ok(())
+        }
+        None => db.delete_account_deletion(user_id),
+    }
+}
+
+/// Deletes the user along with its avatar and the documents attached to its verification
+/// requests.
+fn purge_user(db: &Database, user: User) -> Result<()> {
+    for verification in try!(db.get_user_verifications(user.get_id())) {
+        for attachment in &verification.attachments {
+            try!(BLOBS.delete(&attachment.key));
+        }
+    }
+    if let Some(version) = try!(db.get_user_avatar(user.get_id())) {
+        try!(delete_avatar_blobs(user.get_id(), &version));
+    }
+    user.delete()
+}
+
 /// Updates the given user with the provided information
 ///
 /// - Method: `POST`
 /// - URL: `/update_user/:user_id`
-/// - Scopes: `Admin`, `User`
+/// - Scopes: `User`, or the `users:write` permission for other users
 /// - Returns: an `OK` status code if the removal is successful.
+///
+/// The new username, email, phone and address are validated before any change is made; if any of
+/// them is not valid, an `UnprocessableEntity` status code is returned with a `ValidationErrorDTO`
+/// listing the errors of each field.
+///
+/// A new password can only be set by the user, and `old_password` must be the current password:
+/// otherwise a `Forbidden` status code is returned and the failed attempt counts towards the
+/// lockout of the user. The password of other users is reset with
+/// `/force_password_reset/:user_id`.
+///
+/// A new email is not applied right away: a confirmation is sent to the new address, and a notice
+/// with a link to cancel the change is sent to the current one. The email is only changed once
+/// the new address is confirmed in `/confirm_email_change/:email_key`.
+///
+/// The `new_image` field is ignored, the profile image can only be changed by uploading it to
+/// `/avatar`.
 pub fn update_user(req: &mut Request) -> IronResult<Response> {
-    let token = get_token!(req);
+    let token = try!(require_token(req));
     let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
-    let mut res = Response::new();
-    if !token.is_admin() && !token.is_user(user_id) {
-        let _ = res.set_mut(json::encode(&ResponseDTO::new("unauthorized scope")).unwrap())
-            .set_mut(status::Forbidden);
-        return Ok(res);
+    let can_write = itry!(token.has_permission(Permission::UsersWrite));
+    if !can_write && !token.is_user(user_id) {
+        return Err(AuthError::InsufficientScope.into());
     }
+    let mut res = Response::new();
 
     let mut body = String::new();
     let _ = itry!(req.body.read_to_string(&mut body));
 
     let dto = itry!(json::decode::<UpdateUserDTO>(&body), status::BadRequest);
+
+    let mut errors = Vec::new();
+    let new_username = match dto.new_username {
+        Some(ref username) => {
+            match validation::validate_username(username) {
+                Ok(username) => Some(username),
+                Err(e) => {
+                    errors.push(e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let new_email = match dto.new_email {
+        Some(ref email) => {
+            match validation::normalize_email(email) {
+                Ok(email) => Some(email),
+                Err(e) => {
+                    errors.push(e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let new_phone = match dto.new_phone {
+        Some(ref phone) => {
+            match validation::normalize_phone(phone) {
+                Ok(phone) => Some(phone),
+                Err(e) => {
+                    errors.push(e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    let new_address = match dto.new_address {
+        Some(ref address) => {
+            match validation::normalize_address(address) {
+                Ok(address) => Some(address),
+                Err(e) => {
+                    errors.extend(e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
+    if !errors.is_empty() {
+        return Ok(Response::with(ValidationErrorDTO::new(&errors)));
+    }
+
     let db = thread_rng().choose(&DATABASES[..]).unwrap();
     match db.get_user_by_id(user_id) {
         Ok(Some(mut user)) => {
+            // Every check that can reject the update runs before anything is written, so that a
+            // rejected update does not change the user.
+            if let Some(ref new_username) = new_username {
+                if itry!(db.check_username_exists(new_username)) &&
+                   user.get_username().to_lowercase() != new_username.to_lowercase() {
+                    return Err(Error::UsernameExists.into());
+                }
+            }
+            if let Some(ref new_email) = new_email {
+                if itry!(db.check_email_exists(new_email)) {
+                    return Err(Error::EmailExists.into());
+                }
+            }
+            if let Some(ref new_password) = dto.new_password {
+                if !token.is_user(user_id) {
+                    return invalid_field("new_password",
+                                         "the password of other users can only be reset with \
+                                          /force_password_reset");
+                }
+                if user.is_locked() {
+                    return Err(locked_error(&user));
+                }
+                let correct = match dto.old_password {
+                    Some(ref old_password) => {
+                        itry!(user.check_password(&CONFIG.get_password_scheme(), old_password))
+                    }
+                    None => false,
+                };
+                if !correct {
+                    itry!(register_failed_attempt(req,
+                                                  db,
+                                                  token.get_app_id(),
+                                                  &mut user,
+                                                  "incorrect password"));
+                    return Err(Error::InvalidCredentials.into());
+                }
+                let username = new_username.as_ref()
+                    .map(|u| u.as_str())
+                    .unwrap_or(user.get_username());
+                let failed_rules = CONFIG.get_password_policy()
+                    .check(new_password, username, user.get_email());
+                if !failed_rules.is_empty() {
+                    return Ok(Response::with(PasswordPolicyErrorDTO::new(&failed_rules)));
+                }
+            }
+            let confirmed_field = if dto.new_first.is_some() && user.is_first_name_confirmed() {
+                Some("first_name")
+            } else if dto.new_last.is_some() && user.is_last_name_confirmed() {
+                Some("last_name")
+            } else if new_address.is_some() && user.is_address_confirmed() {
+                Some("address")
+            } else if dto.new_birthday.is_some() && user.is_birthday_confirmed() {
+                Some("birthday")
+            } else {
+                None
+            };
+            if let Some(field) = confirmed_field {
+                if !can_write {
+                    return invalid_field(field,
+                                         "the field is already confirmed, please contact \
+                                          support if the change is needed");
+                }
+            }
+
             let _ =
                 res.set_mut(json::encode(&ResponseDTO::new("succesffuly updated user")).unwrap())
                     .set_mut(status::Ok);
-            if let Some(new_username) = dto.new_username {
-                if itry!(db.check_username_exists(&new_username)) {
-                    if user.get_username().to_lowercase() != new_username.to_lowercase() {
-                        let _ = res.set_mut(json::encode(&ResponseDTO::new(" a user with that \
-                                                                     username already exists"))
-                                .unwrap())
-                            .set_mut(status::Accepted);
-                    } else {
-                        itry!(user.set_username(new_username));
-                    }
-                } else {
-                    itry!(user.set_username(new_username));
+            if let Some(new_username) = new_username {
+                if user.get_username() != new_username {
+                    let mut event = audit_event(req,
+                                                token.get_app_id(),
+                                                Some(user_id),
+                                                AuditAction::UsernameChange);
+                    event.before = Some(String::from(user.get_username()));
+                    itry!(user.set_username(&new_username));
+                    event.after = Some(new_username);
+                    record_audit_event(db, &event);
                 }
-
             }
             if let Some(new_password) = dto.new_password {
-                if !token.is_admin() && dto.old_password.is_some() &&
-                   itry!(user.check_password(dto.old_password.unwrap())) {
-                    itry!(user.set_password(new_password));
-                }
+                itry!(user.set_password(&CONFIG.get_password_scheme(), new_password));
+                let event = audit_event(req,
+                                        token.get_app_id(),
+                                        Some(user_id),
+                                        AuditAction::PasswordChange);
+                record_audit_event(db, &event);
             }
             if let Some(new_first) = dto.new_first {
-                if !user.is_first_name_confirmed() || token.is_admin() {
-                    itry!(user.set_first_name(new_first));
-                } else {
-                    let _ = res.set_mut(json::encode(&ResponseDTO::new("users cannot change an \
-                                                                 already confirmed first name, \
-                                                                 please contact support if the \
-                                                                 change is needed"))
-                            .unwrap())
-                        .set_mut(status::Accepted);
-                    return Ok(res);
-                }
+                itry!(user.set_first_name(new_first));
             }
             if let Some(new_last) = dto.new_last {
-                if !user.is_last_name_confirmed() || token.is_admin() {
-                    itry!(user.set_last_name(new_last));
-                } else {
-                    let _ = res.set_mut(json::encode(&ResponseDTO::new("users cannot change an \
-                                                                 already confirmed last name, \
-                                                                 please contact support if the \
-                                                                 change is needed"))
-                            .unwrap())
-                        .set_mut(status::Accepted);
-                    return Ok(res);
-                }
+                itry!(user.set_last_name(new_last));
             }
-            if let Some(new_address) = dto.new_address {
+            if let Some(new_address) = new_address {
                 itry!(user.set_address(Some(new_address)));
             }
             if let Some(new_birthday) = dto.new_birthday {
-                if !user.is_birthday_confirmed() || token.is_admin() {
-                    itry!(user.set_birthday(Some(new_birthday)));
-                } else {
-                    let _ = res.set_mut(json::encode(&ResponseDTO::new("users cannot change an \
-                                                                 already confirmed birthday, \
-                                                                 please contact support if the \
-                                                                 change is needed"))
-                            .unwrap())
-                        .set_mut(status::Accepted);
-                    return Ok(res);
-                }
+                itry!(user.set_birthday(Some(new_birthday)));
             }
-            if let Some(new_phone) = dto.new_phone {
+            if let Some(new_phone) = new_phone {
                 itry!(user.set_phone(Some(new_phone)));
             }
-            if let Some(new_email) = dto.new_email {
-                if itry!(db.check_email_exists(new_email.to_lowercase())) {
-                    let _ =
-                        res.set_mut(json::encode(&ResponseDTO::new("user with that email \
-                                                                     already exists"))
-                                .unwrap())
-                            .set_mut(status::Accepted);
-                } else {
-                    itry!(user.set_email(&new_email));
-                    let db = thread_rng().choose(&DATABASES[..]).unwrap();
-                    let mut email_key = [0u8; 5];
-                    thread_rng().fill_bytes(&mut email_key[0..]);
-                    let email_str = email_key.to_base64(URL_SAFE);
-                    let _ = itry!(db.start_confirm_email(user_id, &email_str));
-                    let email = EmailStruct {
-                        email: new_email,
-                        email_key: email_str,
-                        email_type: EmailType::Email,
-                    };
-                    EMAILS.lock().unwrap().push(email);
-                }
+            if let Some(new_email) = new_email {
+                let mut confirm_key = [0u8; 24];
+                thread_rng().fill_bytes(&mut confirm_key[0..]);
+                let confirm_str = confirm_key.to_base64(URL_SAFE);
+                let mut cancel_key = [0u8; 24];
+                thread_rng().fill_bytes(&mut cancel_key[0..]);
+                let cancel_str = cancel_key.to_base64(URL_SAFE);
+                itry!(user.start_email_change(&new_email,
+                                              &confirm_str,
+                                              &cancel_str,
+                                              CONFIG.get_email_change_ttl()));
+                let mut event = audit_event(req,
+                                            token.get_app_id(),
+                                            Some(user_id),
+                                            AuditAction::EmailChangeRequested);
+                event.before = Some(String::from(user.get_email()));
+                event.after = Some(new_email.clone());
+                record_audit_event(db, &event);
+                let mut emails = EMAILS.lock().unwrap();
+                emails.push(EmailStruct {
+                    email: String::from(user.get_email()),
+                    email_key: cancel_str,
+                    email_type: EmailType::EmailChangeNotice(new_email.clone()),
+                });
+                emails.push(EmailStruct {
+                    email: new_email,
+                    email_key: confirm_str,
+                    email_type: EmailType::EmailChange,
+                });
             }
-            if let Some(new_image) = dto.new_image {
-                itry!(user.set_image(Some(new_image)));
+        }
+        Ok(None) => return Err(Error::UserDoesNotExist.into()),
+        Err(e) => {
+            println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
+            itry!(Err(e));
+        }
+    }
+
+    Ok(res)
+}
+
+/// Sends a verification code by SMS to the phone of the user.
+///
+/// - Method: `POST`
+/// - URL: `/send_phone_verification`
+/// - Scopes: `User`
+/// - Returns: a successful response if the code was sent, a `Conflict` status code if the user
+///   has no phone or it is already confirmed, or a `TooManyRequests` status code if too many codes
+///   were sent to the number recently.
+pub fn send_phone_verification(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let user = match itry!(db.get_user_by_id(user_id)) {
+        Some(user) => user,
+        None => return Err(Error::UserDoesNotExist.into()),
+    };
+    let phone = match user.get_phone() {
+        Some(&(ref phone, false)) => phone.clone(),
+        Some(&(_, true)) => return Err(Error::PhoneConfirmed.into()),
+        None => return Err(Error::NoPhone.into()),
+    };
+
+    if itry!(db.increment_sms_sends(&phone, CONFIG.get_sms_send_window())) >
+       CONFIG.get_sms_max_sends() {
+        return Err(Error::SmsLimitReached.into());
+    }
+
+    let code = sms::generate_code(CONFIG.get_sms_code_digits());
+    itry!(user.start_phone_verification(&code, CONFIG.get_sms_code_ttl()));
+    itry!(SMS.send(&phone,
+                   &format!("Your verification code is {}. It expires in {} minutes.",
+                            code,
+                            CONFIG.get_sms_code_ttl().num_minutes())));
+    let _ = res.set_mut(json::encode(&ResponseDTO::new("verification code sent")).unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Verifies the phone of the user with the code sent by SMS.
+///
+/// - Method: `POST`
+/// - URL: `/verify_phone`
+/// - Scopes: `User`
+/// - Returns: a successful response if the phone was confirmed, or a `Forbidden` status code if
+///   the code is not correct or has expired.
+///
+/// The `PhoneCodeDTO` with the code must be in the body.
+pub fn verify_phone(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let mut body = String::new();
+    let _ = req.body.read_to_string(&mut body);
+    let dto = itry!(json::decode::<PhoneCodeDTO>(&body), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match itry!(db.get_user_by_id(user_id)) {
+        Some(mut user) => {
+            if itry!(user.verify_phone(dto.code)) {
+                let _ = res.set_mut(json::encode(&ResponseDTO::new("successfully confirmed \
+                                                                     phone"))
+                        .unwrap())
+                    .set_mut(status::Ok);
+            } else {
+                return Err(Error::IncorrectCode.into());
             }
         }
-        Ok(None) => {}
+        None => return Err(Error::UserDoesNotExist.into()),
+    }
+    Ok(res)
+}
+
+/// Unlocks a user that was locked out after too many failed attempts.
+///
+/// - Method: `POST`
+/// - URL: `/unlock_user/:user_id`
+/// - Scopes: the `users:write` permission
+/// - Returns: an `OK` status code if the user was unlocked, or a `NotFound` status code if a user
+///   with the given ID was not found.
+pub fn unlock_user(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    let mut res = Response::new();
+    try!(require_permission(&token, Permission::UsersWrite));
+
+    let user_id = itry!(param!(req, "user_id").parse::<u64>(), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match db.get_user_by_id(user_id) {
+        Ok(Some(mut user)) => {
+            itry!(user.unlock());
+            let event = audit_event(req, token.get_app_id(), Some(user_id), AuditAction::Unlock);
+            record_audit_event(db, &event);
+            let _ = res.set_mut(json::encode(&ResponseDTO::new("user unlocked")).unwrap())
+                .set_mut(status::Ok);
+        }
+        Ok(None) => return Err(Error::UserDoesNotExist.into()),
         Err(e) => {
             println!("Error: {:?}, file: {}, line: {}", e, file!(), line!());
             itry!(Err(e));
@@ -418,3 +891,32 @@ pub fn update_user(req: &mut Request) -> IronResult<Response> {
 
     Ok(res)
 }
+
+/// Gets the email changes waiting for the new address to be confirmed.
+///
+/// - Method: `GET`
+/// - URL: `/pending_email_changes`
+/// - Scopes: the `users:read` permission
+/// - Returns: a `PendingEmailChangesDTO` with the pending changes of all users.
+pub fn pending_email_changes(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    let mut res = Response::new();
+    try!(require_permission(&token, Permission::UsersRead));
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let mut changes = Vec::new();
+    for pending in itry!(db.get_pending_email_changes()) {
+        if let Some(user) = itry!(db.get_user_by_id(pending.user_id)) {
+            changes.push(PendingEmailChangeDTO {
+                user_id: pending.user_id,
+                email: String::from(user.get_email()),
+                new_email: pending.new_email,
+                requested: pending.requested.timestamp(),
+            });
+        }
+    }
+
+    let dto = PendingEmailChangesDTO { pending_email_changes: changes };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
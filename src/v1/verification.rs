@@ -0,0 +1,309 @@
+//! Identity verification module. It contains the methods for users to submit their documents to
+//! confirm their name, birthday and address, and for admins to review them.
+use std::io::Read;
+
+use iron::prelude::*;
+use iron::status;
+use iron::mime::Mime;
+
+use rand::{thread_rng, Rng};
+use rustc_serialize::json;
+use rustc_serialize::base64::FromBase64;
+
+use {DATABASES, BLOBS};
+use database::{Attachment, Permission, VerificationField};
+use error::Error;
+use validation::FieldError;
+use super::invalid_field;
+use super::auth::{require_token, require_user, require_permission};
+use super::types::{VerificationRequestDTO, VerificationReviewDTO, VerificationDTO,
+                   VerificationsDTO, ValidationErrorDTO};
+
+/// Maximum number of documents attached to a verification request.
+const MAX_ATTACHMENTS: usize = 5;
+/// Maximum size of each attached document, in bytes.
+const MAX_ATTACHMENT_SIZE: usize = 5 * 1024 * 1024;
+/// Maximum length of each attached document encoded in base 64, that takes 4 characters for every
+/// 3 bytes.
+const MAX_ENCODED_ATTACHMENT_LEN: usize = (MAX_ATTACHMENT_SIZE + 2) / 3 * 4;
+/// Maximum size of the body of a verification request, in bytes: enough for the biggest documents
+/// and the rest of the request.
+const MAX_REQUEST_SIZE: usize = MAX_ATTACHMENTS * MAX_ENCODED_ATTACHMENT_LEN + 64 * 1024;
+/// Maximum length of the file name of the attached documents.
+const MAX_ATTACHMENT_NAME_LEN: usize = 255;
+/// MIME types accepted for the attached documents.
+const ATTACHMENT_TYPES: [&'static str; 3] = ["image/png", "image/jpeg", "application/pdf"];
+
+/// Submits an identity verification request.
+///
+/// - Method: `POST`
+/// - URL: `/verification`
+/// - Scopes: `User`
+/// - Returns: the `VerificationDTO` of the new request. If the user already has a pending request
+///   a `Conflict` status code will be returned, and if the fields or documents are not valid, a
+///   `ValidationErrorDTO` will be returned, with an `UnprocessableEntity` status code.
+///
+/// The `VerificationRequestDTO` must be in the body. The request verifies the values the fields
+/// have when it is submitted. Bodies bigger than the maximum number and size of documents allow
+/// are rejected without reading the rest of them.
+pub fn submit_verification(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let mut body = String::new();
+    let _ = itry!((&mut req.body).take(MAX_REQUEST_SIZE as u64 + 1).read_to_string(&mut body),
+                  status::BadRequest);
+    if body.len() > MAX_REQUEST_SIZE {
+        return Err(Error::PayloadTooLarge.into());
+    }
+    let dto = itry!(json::decode::<VerificationRequestDTO>(&body), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let user = match itry!(db.get_user_by_id(user_id)) {
+        Some(user) => user,
+        None => return Err(Error::UserDoesNotExist.into()),
+    };
+    if itry!(db.get_user_pending_verification(user_id)).is_some() {
+        return Err(Error::VerificationPending.into());
+    }
+
+    let mut errors = Vec::new();
+    let mut fields = Vec::new();
+    for name in &dto.fields {
+        match name.parse::<VerificationField>() {
+            Ok(field) => {
+                let (set, confirmed) = match field {
+                    VerificationField::FirstName => {
+                        (user.get_first_name().is_some(), user.is_first_name_confirmed())
+                    }
+                    VerificationField::LastName => {
+                        (user.get_last_name().is_some(), user.is_last_name_confirmed())
+                    }
+                    VerificationField::Birthday => {
+                        (user.get_birthday().is_some(), user.is_birthday_confirmed())
+                    }
+                    VerificationField::Address => {
+                        (user.get_address().is_some(), user.is_address_confirmed())
+                    }
+                };
+                if !set {
+                    errors.push(FieldError::new("fields", format!("the {} is not set", field)));
+                } else if confirmed {
+                    errors.push(FieldError::new("fields",
+                                                format!("the {} is already confirmed", field)));
+                } else if !fields.contains(&field) {
+                    fields.push(field);
+                }
+            }
+            Err(()) => {
+                errors.push(FieldError::new("fields", format!("unknown field '{}'", name)));
+            }
+        }
+    }
+    if fields.is_empty() && errors.is_empty() {
+        errors.push(FieldError::new("fields", "at least one field must be verified"));
+    }
+
+    if dto.attachments.is_empty() || dto.attachments.len() > MAX_ATTACHMENTS {
+        errors.push(FieldError::new("attachments",
+                                    format!("between 1 and {} documents must be attached",
+                                            MAX_ATTACHMENTS)));
+    }
+    let mut documents = Vec::with_capacity(dto.attachments.len());
+    for attachment in &dto.attachments {
+        if attachment.name.is_empty() || attachment.name.len() > MAX_ATTACHMENT_NAME_LEN {
+            errors.push(FieldError::new("attachments.name",
+                                        format!("the file name must be between 1 and {} \
+                                                 characters long",
+                                                MAX_ATTACHMENT_NAME_LEN)));
+        }
+        if !ATTACHMENT_TYPES.contains(&attachment.content_type.as_str()) {
+            errors.push(FieldError::new("attachments.content_type",
+                                        format!("the document type must be one of {}",
+                                                ATTACHMENT_TYPES.join(", "))));
+        }
+        let size_error = FieldError::new("attachments.data",
+                                         format!("the documents cannot be bigger than {} MiB",
+                                                 MAX_ATTACHMENT_SIZE / 1024 / 1024));
+        // Documents too big to fit in the maximum size once decoded are not decoded at all.
+        if attachment.data.trim().len() > MAX_ENCODED_ATTACHMENT_LEN {
+            errors.push(size_error);
+            continue;
+        }
+        match attachment.data.from_base64() {
+            Ok(ref data) if data.len() > MAX_ATTACHMENT_SIZE => errors.push(size_error),
+            Ok(data) => documents.push(data),
+            Err(_) => {
+                errors.push(FieldError::new("attachments.data",
+                                            "the document must be encoded in base 64"));
+            }
+        }
+    }
+
+    if !errors.is_empty() {
+        return Ok(Response::with(ValidationErrorDTO::new(&errors)));
+    }
+
+    let id = itry!(db.increment_verification_id());
+    let mut attachments = Vec::with_capacity(documents.len());
+    for (i, (attachment, data)) in dto.attachments.iter().zip(documents).enumerate() {
+        let key = format!("verifications/{}/{}", id, i);
+        itry!(BLOBS.put(&key, &data));
+        attachments.push(Attachment {
+            name: attachment.name.clone(),
+            content_type: attachment.content_type.clone(),
+            key: key,
+        });
+    }
+    itry!(db.create_verification(id,
+                                 &user,
+                                 &fields,
+                                 &attachments,
+                                 format!("user:{}", user_id)));
+
+    let verification = itry!(db.get_verification(id)).unwrap();
+    let _ = res.set_mut(json::encode(&VerificationDTO::new(&verification)).unwrap())
+        .set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Gets the verification requests of the user.
+///
+/// - Method: `GET`
+/// - URL: `/verifications`
+/// - Scopes: `User`
+/// - Returns: a `VerificationsDTO` with the requests of the user, newest first.
+pub fn get_verifications(req: &mut Request) -> IronResult<Response> {
+    let (_, user_id) = try!(require_user(req));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let dto = VerificationsDTO {
+        verifications: itry!(db.get_user_verifications(user_id))
+            .iter()
+            .map(VerificationDTO::new)
+            .collect(),
+    };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Gets a verification request.
+///
+/// - Method: `GET`
+/// - URL: `/verification/:verification_id`
+/// - Scopes: `User`, or the `verifications:read` permission for other users
+/// - Returns: the `VerificationDTO` of the request, or a `NotFound` status code if it does not
+///   exist or belongs to another user.
+pub fn get_verification(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    let id = itry!(param!(req, "verification_id").parse::<u64>(), status::BadRequest);
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match itry!(db.get_verification(id)) {
+        Some(ref verification) if token.is_user(verification.user_id) ||
+                                  itry!(token.has_permission(Permission::VerificationsRead)) => {
+            let _ = res.set_mut(json::encode(&VerificationDTO::new(verification)).unwrap())
+                .set_mut(status::Ok);
+        }
+        _ => return Err(Error::NotFound.into()),
+    }
+    Ok(res)
+}
+
+/// Gets the verification requests waiting for review.
+///
+/// - Method: `GET`
+/// - URL: `/pending_verifications`
+/// - Scopes: the `verifications:read` permission
+/// - Returns: a `VerificationsDTO` with the pending requests, oldest first.
+pub fn pending_verifications(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::VerificationsRead));
+    let mut res = Response::new();
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let dto = VerificationsDTO {
+        verifications: itry!(db.get_pending_verifications())
+            .iter()
+            .map(VerificationDTO::new)
+            .collect(),
+    };
+    let _ = res.set_mut(json::encode(&dto).unwrap()).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Downloads a document attached to a verification request.
+///
+/// - Method: `GET`
+/// - URL: `/verification/:verification_id/attachments/:index`
+/// - Scopes: the `verifications:read` permission
+/// - Returns: the document, with its content type, or a `NotFound` status code if it does not
+///   exist.
+pub fn get_verification_attachment(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::VerificationsRead));
+    let mut res = Response::new();
+    let id = itry!(param!(req, "verification_id").parse::<u64>(), status::BadRequest);
+    let index = itry!(param!(req, "index").parse::<usize>(), status::BadRequest);
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    let attachment = match itry!(db.get_verification(id)) {
+        Some(mut verification) if index < verification.attachments.len() => {
+            verification.attachments.swap_remove(index)
+        }
+        _ => return Err(Error::NotFound.into()),
+    };
+
+    let data = itry!(BLOBS.get(&attachment.key));
+    let mime = match attachment.content_type.parse::<Mime>() {
+        Ok(mime) => mime,
+        Err(_) => "application/octet-stream".parse::<Mime>().unwrap(),
+    };
+    let _ = res.set_mut(data).set_mut(mime).set_mut(status::Ok);
+    Ok(res)
+}
+
+/// Approves or rejects a verification request.
+///
+/// - Method: `POST`
+/// - URL: `/review_verification/:verification_id`
+/// - Scopes: the `verifications:review` permission
+/// - Returns: the updated `VerificationDTO`. An `UnprocessableEntity` status code will be returned
+///   if the request is rejected without a reason. A `verification_not_pending` error is returned
+///   if the request was already reviewed, and a `verification_outdated` error if the user changed
+///   the verified fields after submitting it.
+///
+/// The `VerificationReviewDTO` must be in the body. Approving the request confirms the verified
+/// fields of the user.
+pub fn review_verification(req: &mut Request) -> IronResult<Response> {
+    let token = try!(require_token(req));
+    try!(require_permission(&token, Permission::VerificationsReview));
+    let mut res = Response::new();
+    let id = itry!(param!(req, "verification_id").parse::<u64>(), status::BadRequest);
+
+    let mut body = String::new();
+    let _ = req.body.read_to_string(&mut body);
+    let dto = itry!(json::decode::<VerificationReviewDTO>(&body), status::BadRequest);
+    let reason = match dto.reason {
+        Some(ref r) if !r.trim().is_empty() => Some(String::from(r.trim())),
+        _ => None,
+    };
+    if !dto.approve && reason.is_none() {
+        return invalid_field("reason", "a reason is required to reject the request");
+    }
+
+    let db = thread_rng().choose(&DATABASES[..]).unwrap();
+    match db.review_verification(id,
+                                 dto.approve,
+                                 format!("client:{}", token.get_app_id()),
+                                 reason) {
+        Ok(verification) => {
+            let _ = res.set_mut(json::encode(&VerificationDTO::new(&verification)).unwrap())
+                .set_mut(status::Ok);
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(res)
+}
@@ -0,0 +1,514 @@
+//! This module contains the validation and normalization of the user input that gets stored with
+//! the user: usernames, email addresses, phone numbers and postal addresses.
+//!
+//! Every check reports the field it failed for, so that the clients can show each error next to
+//! the right input.
+
+use std::fmt;
+
+use idna;
+use public_utils::Address;
+
+/// Minimum length of usernames.
+const USERNAME_MIN_LEN: usize = 3;
+/// Maximum length of usernames.
+const USERNAME_MAX_LEN: usize = 32;
+/// Maximum length of the local part of an email address (RFC 5321).
+const EMAIL_LOCAL_MAX_LEN: usize = 64;
+/// Maximum length of a domain name (RFC 1035).
+const DOMAIN_MAX_LEN: usize = 253;
+/// Maximum length of a domain label (RFC 1035).
+const DOMAIN_LABEL_MAX_LEN: usize = 63;
+/// Minimum number of digits of a phone number, including the country code.
+const PHONE_MIN_DIGITS: usize = 7;
+/// Maximum number of digits of a phone number, including the country code (E.164).
+const PHONE_MAX_DIGITS: usize = 15;
+/// Maximum length of the street lines of an address.
+const ADDRESS_LINE_MAX_LEN: usize = 100;
+/// Maximum length of the city and state of an address.
+const ADDRESS_FIELD_MAX_LEN: usize = 64;
+/// Maximum length of postal codes of countries without a known format.
+const ZIP_MAX_LEN: usize = 10;
+
+/// Usernames that cannot be registered, since they could be used to impersonate the service.
+const RESERVED_USERNAMES: &'static [&'static str] =
+    &["admin", "administrator", "root", "system", "support", "help", "info", "security",
+      "staff", "moderator", "mod", "official", "owner", "webmaster", "postmaster", "hostmaster",
+      "abuse", "noreply", "no-reply", "api", "oauth", "login", "register", "me", "null",
+      "undefined", "anonymous", "guest", "user", "users"];
+
+/// ISO 3166-1 alpha-2 country codes.
+const COUNTRY_CODES: &'static [&'static str] =
+    &["AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX",
+      "AZ", "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ",
+      "BR", "BS", "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK",
+      "CL", "CM", "CN", "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM",
+      "DO", "DZ", "EC", "EE", "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR",
+      "GA", "GB", "GD", "GE", "GF", "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS",
+      "GT", "GU", "GW", "GY", "HK", "HM", "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN",
+      "IO", "IQ", "IR", "IS", "IT", "JE", "JM", "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN",
+      "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC", "LI", "LK", "LR", "LS", "LT", "LU", "LV",
+      "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK", "ML", "MM", "MN", "MO", "MP", "MQ",
+      "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA", "NC", "NE", "NF", "NG", "NI",
+      "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG", "PH", "PK", "PL", "PM",
+      "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW", "SA", "SB", "SC",
+      "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS", "ST", "SV",
+      "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO", "TR",
+      "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+      "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW"];
+
+/// Countries where the state or province is a required part of the address.
+const STATE_REQUIRED: &'static [&'static str] =
+    &["AR", "AU", "BR", "CA", "CN", "ES", "IN", "IT", "JP", "MX", "MY", "US"];
+
+/// Countries that do not use postal codes.
+const NO_POSTAL_CODE: &'static [&'static str] =
+    &["AE", "AG", "AO", "AW", "BF", "BI", "BJ", "BO", "BS", "BW", "BZ", "CD", "CF", "CG", "CI",
+      "CK", "CM", "DJ", "DM", "ER", "FJ", "GA", "GD", "GH", "GM", "GQ", "GY", "HK", "KI", "KM",
+      "KN", "KP", "LC", "ML", "MO", "MR", "MW", "NR", "NU", "QA", "RW", "SB", "SC", "SL", "SR",
+      "ST", "SY", "TD", "TG", "TK", "TL", "TO", "TV", "UG", "VU", "YE", "ZW"];
+
+/// Postal code formats of the countries with a well known format.
+///
+/// In the formats, `9` is a digit, `A` is a letter and any other character must appear as is.
+const POSTAL_CODE_FORMATS: &'static [(&'static str, &'static [&'static str])] =
+    &[("AR", &["A9999AAA", "9999"]),
+      ("AT", &["9999"]),
+      ("AU", &["9999"]),
+      ("BE", &["9999"]),
+      ("BR", &["99999-999", "99999999"]),
+      ("CA", &["A9A 9A9", "A9A9A9"]),
+      ("CH", &["9999"]),
+      ("CN", &["999999"]),
+      ("CZ", &["999 99", "99999"]),
+      ("DE", &["99999"]),
+      ("DK", &["9999"]),
+      ("ES", &["99999"]),
+      ("FI", &["99999"]),
+      ("FR", &["99999"]),
+      ("GB", &["A9 9AA", "A99 9AA", "AA9 9AA", "AA99 9AA", "A9A 9AA", "AA9A 9AA"]),
+      ("IE", &["A99 AAAA", "A99 A9AA", "A99 AA9A", "A99 AAA9", "A99 A99A", "A99 A9A9",
+               "A99 AA99", "A99 A999"]),
+      ("IN", &["999999", "999 999"]),
+      ("IT", &["99999"]),
+      ("JP", &["999-9999", "9999999"]),
+      ("MX", &["99999"]),
+      ("NL", &["9999 AA", "9999AA"]),
+      ("NO", &["9999"]),
+      ("NZ", &["9999"]),
+      ("PL", &["99-999"]),
+      ("PT", &["9999-999"]),
+      ("RU", &["999999"]),
+      ("SE", &["999 99", "99999"]),
+      ("US", &["99999", "99999-9999"])];
+
+/// A validation error of a user input field.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldError {
+    /// The name of the field, such as `username` or `address.zip`.
+    pub field: &'static str,
+    /// The description of the error.
+    pub message: String,
+}
+
+impl FieldError {
+    /// Creates a new field error.
+    pub fn new<S: Into<String>>(field: &'static str, message: S) -> FieldError {
+        FieldError {
+            field: field,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Validates the username, and returns it without surrounding whitespace.
+///
+/// Usernames must be between 3 and 32 characters long, contain only ASCII letters, digits, `.`,
+/// `_` and `-`, start with a letter or digit, and not be a reserved name.
+pub fn validate_username<S: AsRef<str>>(username: S) -> Result<String, FieldError> {
+    let username = username.as_ref().trim();
+    let len = username.chars().count();
+    if len < USERNAME_MIN_LEN || len > USERNAME_MAX_LEN {
+        return Err(FieldError::new("username",
+                                   format!("the username must be between {} and {} characters \
+                                            long",
+                                           USERNAME_MIN_LEN,
+                                           USERNAME_MAX_LEN)));
+    }
+    if !username.chars().all(|c| is_ascii_alphanumeric(c) || c == '.' || c == '_' || c == '-') {
+        return Err(FieldError::new("username",
+                                   "the username can only contain letters, digits, '.', '_' \
+                                    and '-'"));
+    }
+    if !username.chars().next().map_or(false, is_ascii_alphanumeric) {
+        return Err(FieldError::new("username",
+                                   "the username must start with a letter or a digit"));
+    }
+    let lowercase = username.to_lowercase();
+    if RESERVED_USERNAMES.contains(&lowercase.as_str()) {
+        return Err(FieldError::new("username", "the username is reserved"));
+    }
+    Ok(String::from(username))
+}
+
+/// Validates the email address, and returns it normalized.
+///
+/// The local part must be an RFC 5322 dot-atom, and the domain a valid host name. International
+/// domain names are converted to their ASCII (punycode) form, and the address is lowercased, so
+/// that the same address is always stored the same way.
+pub fn normalize_email<S: AsRef<str>>(email: S) -> Result<String, FieldError> {
+    let email = email.as_ref().trim();
+    let at = match email.rfind('@') {
+        Some(i) => i,
+        None => return Err(FieldError::new("email", "the email address must contain an '@'")),
+    };
+    let (local, domain) = (&email[..at], &email[at + 1..]);
+
+    if local.is_empty() || local.len() > EMAIL_LOCAL_MAX_LEN {
+        return Err(FieldError::new("email",
+                                   format!("the part before the '@' must be between 1 and {} \
+                                            characters long",
+                                           EMAIL_LOCAL_MAX_LEN)));
+    }
+    if !local.split('.').all(|atom| !atom.is_empty() && atom.chars().all(is_atext)) {
+        return Err(FieldError::new("email", "the part before the '@' is not valid"));
+    }
+
+    let domain = match idna::domain_to_ascii(domain) {
+        Ok(d) => d.to_lowercase(),
+        Err(_) => return Err(FieldError::new("email", "the domain is not valid")),
+    };
+    if let Err(message) = check_domain(&domain) {
+        return Err(FieldError::new("email", message));
+    }
+
+    Ok(format!("{}@{}", local.to_lowercase(), domain))
+}
+
+/// Returns the forms in which the given email address may be stored, starting with the
+/// normalized one.
+///
+/// Addresses stored before emails were normalized kept their international domain names in
+/// Unicode, so their Unicode form is returned too. Lookups must try every form, so that those
+/// users can still be found and their addresses cannot be registered again.
+pub fn email_variants<S: AsRef<str>>(email: S) -> Vec<String> {
+    let mut variants = Vec::new();
+    if let Ok(normalized) = normalize_email(&email) {
+        let at = normalized.rfind('@').unwrap();
+        let (unicode, _) = idna::domain_to_unicode(&normalized[at + 1..]);
+        let legacy = format!("{}@{}", &normalized[..at], unicode.to_lowercase());
+        variants.push(normalized);
+        if !variants.contains(&legacy) {
+            variants.push(legacy);
+        }
+    }
+    let raw = email.as_ref().trim().to_lowercase();
+    if !variants.contains(&raw) {
+        variants.push(raw);
+    }
+    variants
+}
+
+/// Validates the phone number, and returns it in E.164 format, such as `+14155550123`.
+///
+/// Spaces, dashes, dots and parentheses are ignored, and a leading `00` is accepted in place of
+/// the `+`. The number must include the country calling code.
+pub fn normalize_phone<S: AsRef<str>>(phone: S) -> Result<String, FieldError> {
+    let phone = phone.as_ref().trim();
+    let digits = if phone.starts_with('+') {
+        &phone[1..]
+    } else if phone.starts_with("00") {
+        &phone[2..]
+    } else {
+        return Err(FieldError::new("phone",
+                                   "the phone number must start with '+' and the country \
+                                    calling code"));
+    };
+
+    let mut normalized = String::from("+");
+    for c in digits.chars() {
+        match c {
+            '0'...'9' => normalized.push(c),
+            ' ' | '-' | '.' | '(' | ')' => {}
+            _ => {
+                return Err(FieldError::new("phone",
+                                           "the phone number can only contain digits, spaces, \
+                                            '-', '.', '(' and ')'"))
+            }
+        }
+    }
+
+    let count = normalized.len() - 1;
+    if count < PHONE_MIN_DIGITS || count > PHONE_MAX_DIGITS {
+        return Err(FieldError::new("phone",
+                                   format!("the phone number must have between {} and {} digits",
+                                           PHONE_MIN_DIGITS,
+                                           PHONE_MAX_DIGITS)));
+    }
+    if normalized[1..].starts_with('0') {
+        return Err(FieldError::new("phone", "the country calling code cannot start with 0"));
+    }
+    Ok(normalized)
+}
+
+/// Validates the address, and returns it normalized.
+///
+/// The country must be an ISO 3166-1 alpha-2 code. The state is required in the countries that
+/// use it in their addresses, and the postal code is checked against the format of the country
+/// when it is known. All the errors found are returned.
+pub fn normalize_address(address: &Address) -> Result<Address, Vec<FieldError>> {
+    let mut errors = Vec::new();
+
+    let address1 = address.get_address1().trim();
+    let address2 = address.get_address2().map(|a| a.trim()).unwrap_or("");
+    let city = address.get_city().trim();
+    let state = address.get_state().trim();
+    let zip = address.get_zip().trim().to_uppercase();
+    let country = address.get_country().trim().to_uppercase();
+
+    if address1.is_empty() || address1.chars().count() > ADDRESS_LINE_MAX_LEN {
+        errors.push(FieldError::new("address.address1",
+                                    format!("the address must be between 1 and {} characters \
+                                             long",
+                                            ADDRESS_LINE_MAX_LEN)));
+    }
+    if address2.chars().count() > ADDRESS_LINE_MAX_LEN {
+        errors.push(FieldError::new("address.address2",
+                                    format!("the second address line cannot be longer than {} \
+                                             characters",
+                                            ADDRESS_LINE_MAX_LEN)));
+    }
+    if city.is_empty() || city.chars().count() > ADDRESS_FIELD_MAX_LEN {
+        errors.push(FieldError::new("address.city",
+                                    format!("the city must be between 1 and {} characters long",
+                                            ADDRESS_FIELD_MAX_LEN)));
+    }
+
+    if !COUNTRY_CODES.contains(&country.as_str()) {
+        errors.push(FieldError::new("address.country",
+                                    "the country must be an ISO 3166-1 alpha-2 code, such as \
+                                     'US'"));
+    } else {
+        if STATE_REQUIRED.contains(&country.as_str()) && state.is_empty() {
+            errors.push(FieldError::new("address.state",
+                                        format!("the state is required for addresses in {}",
+                                                country)));
+        }
+        if let Err(message) = check_postal_code(&zip, &country) {
+            errors.push(FieldError::new("address.zip", message));
+        }
+    }
+    if state.chars().count() > ADDRESS_FIELD_MAX_LEN {
+        errors.push(FieldError::new("address.state",
+                                    format!("the state cannot be longer than {} characters",
+                                            ADDRESS_FIELD_MAX_LEN)));
+    }
+
+    if errors.is_empty() {
+        Ok(Address::new(String::from(address1),
+                        if address2.is_empty() {
+                            None
+                        } else {
+                            Some(String::from(address2))
+                        },
+                        String::from(city),
+                        String::from(state),
+                        zip,
+                        country))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Checks that the ASCII domain is a valid host name with at least two labels.
+fn check_domain(domain: &str) -> Result<(), &'static str> {
+    if domain.is_empty() || domain.len() > DOMAIN_MAX_LEN {
+        return Err("the domain is too long");
+    }
+    let labels = domain.split('.').collect::<Vec<_>>();
+    if labels.len() < 2 {
+        return Err("the domain must contain at least one '.'");
+    }
+    for label in &labels {
+        if label.is_empty() || label.len() > DOMAIN_LABEL_MAX_LEN ||
+           label.starts_with('-') || label.ends_with('-') ||
+           !label.chars().all(|c| is_ascii_alphanumeric(c) || c == '-') {
+            return Err("the domain is not valid");
+        }
+    }
+    if labels[labels.len() - 1].chars().all(|c| c >= '0' && c <= '9') {
+        return Err("the domain is not valid");
+    }
+    Ok(())
+}
+
+/// Checks the postal code against the format of the country.
+fn check_postal_code(zip: &str, country: &str) -> Result<(), String> {
+    if NO_POSTAL_CODE.contains(&country) {
+        return if zip.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("addresses in {} do not have a postal code", country))
+        };
+    }
+    if zip.is_empty() {
+        return Err(String::from("the postal code is required"));
+    }
+    match POSTAL_CODE_FORMATS.iter().find(|&&(c, _)| c == country) {
+        Some(&(_, formats)) => {
+            if formats.iter().any(|f| matches_format(zip, f)) {
+                Ok(())
+            } else {
+                Err(format!("the postal code must have the format {}", formats.join(" or ")))
+            }
+        }
+        None => {
+            if zip.len() <= ZIP_MAX_LEN &&
+               zip.chars().all(|c| is_ascii_alphanumeric(c) || c == ' ' || c == '-') {
+                Ok(())
+            } else {
+                Err(String::from("the postal code is not valid"))
+            }
+        }
+    }
+}
+
+/// Checks if the value matches the postal code format.
+fn matches_format(value: &str, format: &str) -> bool {
+    value.len() == format.len() &&
+    value.chars().zip(format.chars()).all(|(v, f)| {
+        match f {
+            '9' => v >= '0' && v <= '9',
+            'A' => v >= 'A' && v <= 'Z',
+            _ => v == f,
+        }
+    })
+}
+
+/// Checks if the character is an ASCII letter or digit.
+fn is_ascii_alphanumeric(c: char) -> bool {
+    (c >= 'a' && c <= 'z') || (c >= 'A' && c <= 'Z') || (c >= '0' && c <= '9')
+}
+
+/// Checks if the character is allowed in an RFC 5322 atom.
+fn is_atext(c: char) -> bool {
+    is_ascii_alphanumeric(c) || "!#$%&'*+/=?^_`{|}~-".contains(c)
+}
+
+#[cfg(test)]
+mod tests {
+    use public_utils::Address;
+    use super::{validate_username, normalize_email, email_variants, normalize_phone,
+                normalize_address};
+
+    #[test]
+    fn usernames() {
+        assert_eq!(validate_username("  alice_01 "), Ok(String::from("alice_01")));
+        assert!(validate_username("al").is_err());
+        assert!(validate_username((0..33).map(|_| "a").collect::<String>()).is_err());
+        assert!(validate_username("alice smith").is_err());
+        assert!(validate_username("álice").is_err());
+        assert!(validate_username("_alice").is_err());
+        assert!(validate_username("Admin").is_err());
+    }
+
+    #[test]
+    fn emails() {
+        assert_eq!(normalize_email(" Alice@Example.COM "),
+                   Ok(String::from("alice@example.com")));
+        assert_eq!(normalize_email("alice@bücher.de"),
+                   Ok(String::from("alice@xn--bcher-kva.de")));
+        assert_eq!(normalize_email("first.o'neil+tag@example.com"),
+                   Ok(String::from("first.o'neil+tag@example.com")));
+        assert!(normalize_email("alice.example.com").is_err());
+        assert!(normalize_email("@example.com").is_err());
+        assert!(normalize_email("alice..smith@example.com").is_err());
+        assert!(normalize_email("alice@localhost").is_err());
+        assert!(normalize_email("alice@-example.com").is_err());
+        assert!(normalize_email("alice@example.123").is_err());
+        let long_local = (0..65).map(|_| "a").collect::<String>();
+        assert!(normalize_email(format!("{}@example.com", long_local)).is_err());
+    }
+
+    #[test]
+    fn email_lookup_forms() {
+        assert_eq!(email_variants("Alice@Example.com"),
+                   vec![String::from("alice@example.com")]);
+        assert_eq!(email_variants("alice@BÜCHER.de"),
+                   vec![String::from("alice@xn--bcher-kva.de"),
+                        String::from("alice@bücher.de")]);
+        assert_eq!(email_variants("alice@xn--bcher-kva.de"),
+                   vec![String::from("alice@xn--bcher-kva.de"),
+                        String::from("alice@bücher.de")]);
+        assert_eq!(email_variants("not an email"), vec![String::from("not an email")]);
+    }
+
+    #[test]
+    fn phones() {
+        assert_eq!(normalize_phone("+1 (415) 555-0123"), Ok(String::from("+14155550123")));
+        assert_eq!(normalize_phone("0034 612.345.678"), Ok(String::from("+34612345678")));
+        assert!(normalize_phone("415 555 0123").is_err());
+        assert!(normalize_phone("+1 415 CALL NOW").is_err());
+        assert!(normalize_phone("+12345").is_err());
+        assert!(normalize_phone("+1234567890123456").is_err());
+        assert!(normalize_phone("+0123456789").is_err());
+    }
+
+    #[test]
+    fn addresses() {
+        let address = Address::new(String::from(" 1 Infinite Loop "),
+                                   None,
+                                   String::from("Cupertino"),
+                                   String::from("CA"),
+                                   String::from("95014"),
+                                   String::from("us"));
+        let normalized = normalize_address(&address).unwrap();
+        assert_eq!(normalized.get_address1(), "1 Infinite Loop");
+        assert_eq!(normalized.get_country(), "US");
+
+        let address = Address::new(String::from("10 Downing Street"),
+                                   Some(String::new()),
+                                   String::from("London"),
+                                   String::new(),
+                                   String::from("sw1a 2aa"),
+                                   String::from("GB"));
+        let normalized = normalize_address(&address).unwrap();
+        assert_eq!(normalized.get_zip(), "SW1A 2AA");
+        assert_eq!(normalized.get_address2(), None);
+    }
+
+    #[test]
+    fn invalid_addresses() {
+        let address = Address::new(String::new(),
+                                   None,
+                                   String::from("Austin"),
+                                   String::new(),
+                                   String::from("7870"),
+                                   String::from("US"));
+        let fields = normalize_address(&address)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.field)
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["address.address1", "address.state", "address.zip"]);
+
+        let address = Address::new(String::from("Main Street 1"),
+                                   None,
+                                   String::from("Nowhere"),
+                                   String::new(),
+                                   String::new(),
+                                   String::from("XX"));
+        let fields = normalize_address(&address)
+            .unwrap_err()
+            .iter()
+            .map(|e| e.field)
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["address.country"]);
+    }
+}